@@ -0,0 +1,59 @@
+//! Capture to handler hand-off on a synthetic pcap, through the file reader, the decoder and a
+//! worker queue: a fresh allocation per packet, as the pipeline used to do, against buffers from the pool
+
+use std::fs;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use perso::{bench::{self, BufferPool}, config::Config};
+
+const PACKETS: usize = 10_000;
+
+/// pcap file of Ethernet/IPv4/UDP frames from 64 to 1514 bytes
+fn synthetic_pcap() -> Vec<u8> {
+    let mut file = Vec::new();
+    // Magic, version 2.4, zone, sigfigs, snaplen, Ethernet
+    file.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    file.extend_from_slice(&2u16.to_le_bytes());
+    file.extend_from_slice(&4u16.to_le_bytes());
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&65535u32.to_le_bytes());
+    file.extend_from_slice(&1u32.to_le_bytes());
+    for i in 0..PACKETS {
+        let len = 64 + (i * 97) % 1451;
+        let mut frame = vec![0u8; len];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        let ip = &mut frame[14..];
+        ip[0] = 0x45;
+        ip[2..4].copy_from_slice(&((len - 14) as u16).to_be_bytes());
+        ip[8] = 64;
+        ip[9] = 17;
+        ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ip[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        file.extend_from_slice(&(i as u32).to_le_bytes());
+        file.extend_from_slice(&0u32.to_le_bytes());
+        file.extend_from_slice(&(len as u32).to_le_bytes());
+        file.extend_from_slice(&(len as u32).to_le_bytes());
+        file.extend_from_slice(&frame);
+    }
+    file
+}
+
+fn pipeline(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("pipeline-{}.pcap", std::process::id()));
+    fs::write(&path, synthetic_pcap()).unwrap();
+    let file = path.to_str().unwrap();
+    let config = Config::default();
+    // Without buffers to recycle every packet gets a fresh allocation, as before the pool
+    let unpooled = BufferPool::new(0, config.queue.buffer_size);
+    let pooled = BufferPool::new(config.pool_buffers(), config.queue.buffer_size);
+
+    let mut group = c.benchmark_group("pipeline");
+    group.throughput(Throughput::Elements(PACKETS as u64));
+    group.bench_function("vec", |b| b.iter(|| bench::decode(&config, &unpooled, file)));
+    group.bench_function("pool", |b| b.iter(|| bench::decode(&config, &pooled, file)));
+    group.finish();
+    fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
@@ -1,4 +1,6 @@
 #!/bin/sh
-cargo build
-sudo setcap cap_net_raw,cap_net_admin=eip /stuff/perso/target/debug/perso
-cargo run
\ No newline at end of file
+# Capturing needs raw socket capabilities, the arguments go to perso (see perso --help)
+cd "$(dirname "$0")" || exit 1
+cargo build || exit 1
+sudo setcap cap_net_raw,cap_net_admin=eip target/debug/perso
+./target/debug/perso --config config.toml "$@"
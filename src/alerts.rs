@@ -0,0 +1,27 @@
+use std::{net::Ipv4Addr, sync::{Arc, atomic::Ordering}};
+
+use crate::{events::Event, stats::Stats};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertType {
+    TUNNEL,
+    DGA,
+    NXDOMAIN,
+    SPOOFING,
+}
+
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub ts: u128,
+    pub alert_type: AlertType,
+    pub client: Ipv4Addr,
+    pub subject: String,
+    pub score: f64,
+    pub evidence: Vec<String>,
+}
+
+/// Counts the alert and hands it to the outputs
+pub fn raise(alert: Alert, events: &mut Vec<Event>, stats: &Arc<Stats>) {
+    stats.alerts.fetch_add(1, Ordering::Relaxed);
+    events.push(Event::Alert(alert));
+}
@@ -0,0 +1,307 @@
+use std::{collections::HashMap, fmt, net::Ipv4Addr, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread};
+
+use crate::{
+    config::{Config, ConfigError, Mode},
+    dissector::{Dissector, Registry},
+    events::{EventSink, Events},
+    export,
+    hosts::{self, HostEntry},
+    interface,
+    inventory::{self, ArpEntry},
+    outputs,
+    packet_handler,
+    pool::BufferPool,
+    queue::PacketQueue,
+    reload::{self, LiveConfig, Reloader},
+    shutdown::Shutdown,
+    stats::{self, Stats},
+    utils::DnsRecord,
+};
+
+/// Where the packets come from, overrides the mode and sources of the config
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Interface(String),
+    /// Capture file, directory or `*`/`?` pattern
+    File(String),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config, with the sources applied, doesn't pass `Config::validate`
+    Config(ConfigError),
+    /// Interfaces and files can't be mixed in one analyzer
+    Sources,
+    /// An output of the config can't be opened
+    Output(String, String),
+    /// An interface or capture file can't be opened, or the capture filter doesn't compile
+    Capture(String),
+    /// A capture or handler thread panicked
+    Panicked,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(error) => write!(f, "{}", error),
+            Error::Sources => write!(f, "Can't capture from interfaces and files at once"),
+            Error::Output(output, error) => write!(f, "Couldn't open output {}: {}", output, error),
+            Error::Capture(error) => write!(f, "{}", error),
+            Error::Panicked => write!(f, "A capture or handler thread panicked"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Default)]
+pub struct AnalyzerBuilder {
+    config: Option<Config>,
+    sources: Vec<Source>,
+    sinks: Vec<Box<dyn EventSink>>,
+    dissectors: Registry,
+    reloader: Option<Reloader>,
+    print_stats: bool,
+}
+
+/// Captures, decodes and analyzes traffic until the input ends or a shutdown is requested
+pub struct Analyzer {
+    config: Config,
+    events: Arc<Events>,
+    stats: Arc<Stats>,
+    dissectors: Registry,
+    reloader: Option<Reloader>,
+    print_stats: bool,
+    shutdown: Shutdown,
+}
+
+impl AnalyzerBuilder {
+    /// Defaults to `Config::default()`
+    pub fn config(mut self, config: Config) -> AnalyzerBuilder {
+        self.config = Some(config);
+        self
+    }
+
+    /// Can be called several times, to capture from several interfaces or read several files
+    pub fn source(mut self, source: Source) -> AnalyzerBuilder {
+        self.sources.push(source);
+        self
+    }
+
+    /// Every sink receives every event, along with the `outputs` of the config
+    pub fn sink<S: EventSink + 'static>(mut self, sink: S) -> AnalyzerBuilder {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Adds a parser for the TCP (6) or UDP (17) flows, probed after the built-in ones
+    pub fn dissector<D: Dissector + 'static>(mut self, protocol: u8, dissector: D) -> AnalyzerBuilder {
+        self.dissectors.register(protocol, Arc::new(dissector));
+        self
+    }
+
+    /// Called on SIGHUP or `reload::request` to read the config again. The rules and outputs
+    /// are replaced, the other sections need a restart. No reload without it
+    pub fn reload<F: Fn() -> Result<Config, ConfigError> + Send + 'static>(mut self, reloader: F) -> AnalyzerBuilder {
+        self.reloader = Some(Box::new(reloader));
+        self
+    }
+
+    /// Logs the counters every second and a summary when the run ends, off by default
+    pub fn print_stats(mut self, print_stats: bool) -> AnalyzerBuilder {
+        self.print_stats = print_stats;
+        self
+    }
+
+    pub fn build(self) -> Result<Analyzer, Error> {
+        let mut config = self.config.unwrap_or_default();
+        let interfaces: Vec<String> = self
+            .sources
+            .iter()
+            .filter_map(|source| match source {
+                Source::Interface(name) => Some(name.clone()),
+                Source::File(_) => None,
+            })
+            .collect();
+        let files: Vec<String> = self
+            .sources
+            .iter()
+            .filter_map(|source| match source {
+                Source::File(path) => Some(path.clone()),
+                Source::Interface(_) => None,
+            })
+            .collect();
+        if !interfaces.is_empty() && !files.is_empty() {
+            return Err(Error::Sources);
+        }
+        if !interfaces.is_empty() {
+            config.general.mode = Mode::INTERFACE;
+            config.general.interfaces = interfaces;
+        } else if !files.is_empty() {
+            config.general.mode = Mode::FILE;
+            config.general.files = files;
+        }
+        config.validate().map_err(Error::Config)?;
+        let outputs = match outputs::open(&config.outputs) {
+            Ok(outputs) => outputs,
+            Err((output, e)) => return Err(Error::Output(output, e.to_string())),
+        };
+        Ok(Analyzer {
+            stats: Stats::new(&interface::sources(&config)),
+            config,
+            events: Arc::new(Events::new(outputs, self.sinks)),
+            dissectors: self.dissectors,
+            reloader: self.reloader,
+            print_stats: self.print_stats,
+            shutdown: Shutdown::default(),
+        })
+    }
+}
+
+impl Analyzer {
+    pub fn builder() -> AnalyzerBuilder {
+        AnalyzerBuilder::default()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The counters of the run, they keep growing until it ends
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Stops the capture from another thread, the packets already read are still handled
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Blocks until every source is read, or until the `shutdown` handle or a signal stops the capture.
+    /// No thread of the run outlives it
+    pub fn run(self) -> Result<(), Error> {
+        let config = self.config;
+        let events = self.events;
+        let stats = self.stats;
+        let live = LiveConfig::new(config.clone());
+
+        // Init the probe struct
+        let dns_records: Arc<Mutex<HashMap<DnsRecord, String>>> = Arc::new(Mutex::new(HashMap::new()));
+        let hosts: Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>> = Arc::new(Mutex::new(HashMap::new()));
+        let arp_table: Arc<Mutex<HashMap<Ipv4Addr, ArpEntry>>> = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut producers = Vec::new();
+        let mut queues = Vec::new();
+        for _ in 0..config.general.workers.max(1) {
+            let (tx, receiver) = PacketQueue::new(config.queue.capacity, config.queue.policy, &stats);
+            producers.push(tx);
+            queues.push(receiver);
+        }
+        let pool = BufferPool::new(config.pool_buffers(), config.queue.buffer_size);
+
+        /*
+        For each core we will need 2 main threads
+        - Thread 1: reads from interface
+        - Thread 2: reads from the queue
+        */
+
+        let interface_threads = interface::run(&config, &producers, &pool, &arp_table, &events, &stats, &self.shutdown)
+            .map_err(Error::Capture)?;
+        // The capture threads hold the only senders, the queues close when the input ends
+        drop(producers);
+
+        // Cleared at the end of the run, which stops the periodic threads
+        let running = Arc::new(AtomicBool::new(true));
+        let mut periodic_threads = Vec::new();
+
+        // Initializing the stats thread
+        if self.print_stats {
+            periodic_threads.push(stats::run(&stats, &running));
+        }
+
+        // Initializing the host table export thread
+        periodic_threads.push(export::run(
+            "host table",
+            &config.names.file,
+            config.names.export_interval,
+            &hosts,
+            hosts::export,
+            &running,
+        ));
+
+        // Initializing the ARP table export thread
+        periodic_threads.push(export::run(
+            "ARP table",
+            &config.arp.file,
+            config.arp.export_interval,
+            &arp_table,
+            inventory::export,
+            &running,
+        ));
+
+        // Initializing the reload thread
+        if let Some(reloader) = self.reloader {
+            periodic_threads.push(reload::run(reloader, &live, &events, &running));
+        }
+
+        let mut dissectors = packet_handler::builtin(&live, &dns_records, &hosts, &stats);
+        dissectors.extend(self.dissectors);
+        // Core 0 is left to the capture threads
+        let cores = if config.general.cpu_affinity { core_affinity::get_core_ids().unwrap_or_default() } else { Vec::new() };
+        let handler_threads: Vec<_> = queues
+            .into_iter()
+            .enumerate()
+            .map(|(index, queue)| {
+                let core = cores.get(index + 1).copied();
+                let live = live.clone();
+                let dns_records = dns_records.clone();
+                let hosts = hosts.clone();
+                let dissectors = dissectors.clone();
+                let events = events.clone();
+                let stats = stats.clone();
+                thread::spawn(move || {
+                    if let Some(core) = core {
+                        core_affinity::set_for_current(core);
+                    }
+                    packet_handler::run(&live, queue, &dns_records, &hosts, dissectors, &events, &stats);
+                })
+            })
+            .collect();
+
+        // Wait for the input to end or for a shutdown request, a panicked thread fails the run
+        let mut panicked = false;
+        for interface_thread in interface_threads {
+            if interface_thread.join().is_err() {
+                panicked = true;
+            }
+        }
+        for handler_thread in handler_threads {
+            if handler_thread.join().is_err() {
+                panicked = true;
+            }
+        }
+        running.store(false, Ordering::Relaxed);
+        for periodic_thread in periodic_threads {
+            if periodic_thread.join().is_err() {
+                panicked = true;
+            }
+        }
+
+        // Final exports
+        if !config.names.file.as_os_str().is_empty() {
+            export::write("host table", &config.names.file, &hosts, hosts::export);
+        }
+        if !config.arp.file.as_os_str().is_empty() {
+            export::write("ARP table", &config.arp.file, &arp_table, inventory::export);
+        }
+        events.flush();
+        if self.print_stats {
+            log::info!("Shutting down, {} hosts named, {} ARP entries", hosts.lock().unwrap().len(), arp_table.lock().unwrap().len());
+            stats::print(&stats, &stats::Snapshot::take(&stats));
+        }
+        if panicked {
+            return Err(Error::Panicked);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+//! Entry points for benches/pipeline.rs, not part of the API
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, thread};
+
+use crate::{config::{Config, Mode}, events::Events, interface, queue::PacketQueue, shutdown::Shutdown, stats::Stats};
+
+pub use crate::pool::BufferPool;
+
+/// Reads the capture file through the file reader into a worker queue drained by another
+/// thread, as the capture and handler threads do, and returns the packets that went through
+pub fn decode(config: &Config, pool: &Arc<BufferPool>, file: &str) -> usize {
+    let mut config = config.clone();
+    config.general.mode = Mode::FILE;
+    config.general.file = file.to_string();
+    config.general.files.clear();
+    let stats = Stats::new(&interface::sources(&config));
+    let events = Arc::new(Events::default());
+    let (producer, queue) = PacketQueue::new(config.queue.capacity, config.queue.policy, &stats);
+    let handler = thread::spawn(move || {
+        let mut packets = 0;
+        while queue.pop().is_some() {
+            packets += 1;
+        }
+        packets
+    });
+    let arp_table = Arc::new(Mutex::new(HashMap::new()));
+    let readers = interface::run(&config, &[producer], pool, &arp_table, &events, &stats, &Shutdown::default()).unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    handler.join().unwrap()
+}
@@ -0,0 +1,106 @@
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+
+use perso::config::{Config, Mode, Output, OutputType};
+
+/// Passive network traffic analyzer, the flags override the config file
+#[derive(Debug, Parser)]
+#[command(name = "perso", version)]
+pub struct Cli {
+    /// TOML config file, ./config.toml or the user config directory when missing
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Captures from this interface, can be repeated
+    #[arg(short = 'i', long = "interface", global = true, value_name = "IFACE")]
+    pub interfaces: Vec<String>,
+    /// Reads this capture file, directory or pattern, can be repeated
+    #[arg(short = 'r', long = "read", global = true, value_name = "PCAP")]
+    pub files: Vec<String>,
+    /// BPF capture filter
+    #[arg(long, global = true, value_name = "BPF")]
+    pub filter: Option<String>,
+    /// stdout, jsonl:PATH, csv:PATH or unix:PATH, can be repeated and replaces the [[outputs]]
+    #[arg(long = "output", global = true, value_name = "TYPE[:PATH]")]
+    pub outputs: Vec<String>,
+    /// Packet handler threads
+    #[arg(long, global = true, value_name = "N")]
+    pub workers: Option<usize>,
+    /// Loads and validates the config with the flags applied, then exits
+    #[arg(long)]
+    pub check_config: bool,
+    /// Prints a config.toml with every default value, then exits
+    #[arg(long, conflicts_with = "check_config")]
+    pub dump_default_config: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Subcommand)]
+pub enum Command {
+    /// Captures from the interfaces of -i or of the config
+    Live,
+    /// Reads the capture files of -r or of the config
+    Read,
+    /// Only prints the counters, no event is written
+    Stats,
+}
+
+impl Cli {
+    /// Applies the flags to the config loaded from the file, exits on conflicting flags
+    pub fn apply(&self, config: &mut Config) {
+        if !self.interfaces.is_empty() && !self.files.is_empty() {
+            fail(ErrorKind::ArgumentConflict, "-i and -r can't be used together");
+        }
+        match self.command {
+            Some(Command::Live) if !self.files.is_empty() => fail(ErrorKind::ArgumentConflict, "live doesn't read capture files, use read -r"),
+            Some(Command::Read) if !self.interfaces.is_empty() => fail(ErrorKind::ArgumentConflict, "read doesn't capture from interfaces, use live -i"),
+            Some(Command::Stats) if !self.outputs.is_empty() => fail(ErrorKind::ArgumentConflict, "stats doesn't write events, drop --output"),
+            Some(Command::Live) => config.general.mode = Mode::INTERFACE,
+            Some(Command::Read) => config.general.mode = Mode::FILE,
+            Some(Command::Stats) => config.outputs.clear(),
+            None => (),
+        }
+        if !self.interfaces.is_empty() {
+            config.general.mode = Mode::INTERFACE;
+            config.general.interfaces = self.interfaces.clone();
+        }
+        if !self.files.is_empty() {
+            config.general.mode = Mode::FILE;
+            config.general.files = self.files.clone();
+        }
+        if let Some(filter) = &self.filter {
+            config.general.filter = filter.clone();
+        }
+        if let Some(workers) = self.workers {
+            if workers == 0 {
+                fail(ErrorKind::InvalidValue, "--workers must be at least 1");
+            }
+            config.general.workers = workers;
+        }
+        if !self.outputs.is_empty() {
+            config.outputs = self.outputs.iter().map(|output| parse_output(output)).collect();
+        }
+    }
+}
+
+/// TYPE or TYPE:PATH
+fn parse_output(value: &str) -> Output {
+    let (output_type, path) = match value.split_once(':') {
+        Some((output_type, path)) => (output_type, path),
+        None => (value, ""),
+    };
+    let output_type = match output_type {
+        "stdout" => OutputType::STDOUT,
+        "jsonl" => OutputType::JSONL,
+        "csv" => OutputType::CSV,
+        "unix" => OutputType::UNIX,
+        _ => fail(ErrorKind::InvalidValue, &format!("Unknown output type {}, expected stdout|jsonl|csv|unix", output_type)),
+    };
+    if output_type.has_path() && path.is_empty() {
+        fail(ErrorKind::InvalidValue, &format!("--output {} needs a path, e.g. {}:events", value, output_type));
+    }
+    Output { output_type, path: path.into(), events: Vec::new() }
+}
+
+fn fail(kind: ErrorKind, message: &str) -> ! {
+    Cli::command().error(kind, message).exit()
+}
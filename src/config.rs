@@ -1,41 +1,856 @@
+use std::{collections::BTreeMap, convert::TryFrom, fmt, net::Ipv4Addr, path::{Path, PathBuf}, time::Duration};
+
+use directories::ProjectDirs;
+use serde::{de, Deserializer, Serializer};
 use serde_derive::{Serialize, Deserialize};
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+use crate::{events::Event, utils::AppType};
+
+/// The template written by `--dump-default-config`, every value in it is the default
+pub const TEMPLATE: &str = include_str!("../config.toml");
+
+/// Where the packets come from
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    INTERFACE,
+    FILE,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct General {
-    pub mode: String,
+    pub mode: Mode,
+    /// Empty for the default device of libpcap
     pub interface: String,
+    /// Captures from all of these at once, `interface` is used when empty
+    pub interfaces: Vec<String>,
     pub file: String,
+    /// Capture files, directories or `*`/`?` patterns read as one capture, `file` is used when empty
+    pub files: Vec<String>,
+    /// BPF expression, empty to capture everything
+    pub filter: String,
+    /// Bytes kept per packet, 0 for the libpcap default
+    pub snaplen: i32,
+    /// Kernel buffer, 0 keeps the libpcap default
+    #[serde(with = "size")]
+    pub buffer_size: usize,
+    pub promiscuous: bool,
+    /// Read timeout, plain numbers are milliseconds
+    #[serde(with = "millis")]
+    pub timeout: Duration,
+    /// Packet handler threads, the flows are spread over them by address pair
+    pub workers: usize,
+    /// Pins worker N to core N+1, core 0 is left to the capture threads
+    pub cpu_affinity: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct Whatsapp {
-    pub debug: bool,
-    pub file: String,
+impl ::std::default::Default for General {
+    fn default() -> Self {
+        Self {
+            mode: Mode::INTERFACE,
+            interface: "".to_string(),
+            interfaces: Vec::new(),
+            file: "".to_string(),
+            files: Vec::new(),
+            filter: "".to_string(),
+            snaplen: 65535,
+            buffer_size: 0,
+            promiscuous: true,
+            timeout: Duration::from_millis(1000),
+            workers: 1,
+            cpu_affinity: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Names {
+    pub enabled: bool,
+    /// CSV host table, empty to disable the export
+    pub file: PathBuf,
+    /// Plain numbers are seconds
+    #[serde(with = "secs")]
+    pub export_interval: Duration,
+}
+
+impl ::std::default::Default for Names {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: PathBuf::new(),
+            export_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The application signatures, replaced by a reload
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Apps {
+    /// Names that identify an application, matched against the DNS answers, Host headers and SNIs
+    pub domains: BTreeMap<String, AppType>,
+}
+
+impl ::std::default::Default for Apps {
+    fn default() -> Self {
+        Self {
+            domains: BTreeMap::from([("g.whatsapp.net".to_string(), AppType::WHATSAPP)]),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct EncryptedDns {
+    pub doh_servers: Vec<String>,
+    pub doh_ips: Vec<Ipv4Addr>,
+}
+
+impl ::std::default::Default for EncryptedDns {
+    fn default() -> Self {
+        Self {
+            doh_servers: vec![
+                "dns.google".to_string(),
+                "cloudflare-dns.com".to_string(),
+                "dns.quad9.net".to_string(),
+                "doh.opendns.com".to_string(),
+                "dns.nextdns.io".to_string(),
+                "dns.adguard.com".to_string(),
+                "doh.cleanbrowsing.org".to_string(),
+            ],
+            doh_ips: vec![
+                Ipv4Addr::new(8, 8, 8, 8),
+                Ipv4Addr::new(8, 8, 4, 4),
+                Ipv4Addr::new(1, 1, 1, 1),
+                Ipv4Addr::new(1, 0, 0, 1),
+                Ipv4Addr::new(9, 9, 9, 9),
+                Ipv4Addr::new(149, 112, 112, 112),
+                Ipv4Addr::new(208, 67, 222, 222),
+                Ipv4Addr::new(208, 67, 220, 220),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DnsAnalytics {
+    pub enabled: bool,
+    /// Period over which per-domain and per-client counters are accumulated, plain numbers are seconds
+    #[serde(with = "secs")]
+    pub window: Duration,
+    pub max_label_len: usize,
+    pub entropy_threshold: f64,
+    pub entropy_min_len: usize,
+    pub subdomain_threshold: usize,
+    pub txt_ratio_threshold: f64,
+    pub txt_min_queries: usize,
+    /// Number of tunnelling heuristics that must fire to raise an alert
+    pub tunnel_min_score: usize,
+    pub dga_threshold: f64,
+    pub dga_min_len: usize,
+    pub nxdomain_threshold: usize,
+}
+
+impl ::std::default::Default for DnsAnalytics {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: Duration::from_secs(60),
+            max_label_len: 40,
+            entropy_threshold: 3.8,
+            entropy_min_len: 16,
+            subdomain_threshold: 200,
+            txt_ratio_threshold: 0.5,
+            txt_min_queries: 20,
+            tunnel_min_score: 2,
+            dga_threshold: 0.75,
+            dga_min_len: 8,
+            nxdomain_threshold: 20,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Extract {
+    pub enabled: bool,
+    pub directory: PathBuf,
+    /// Content-type prefixes of the HTTP response bodies to carve
+    pub content_types: Vec<String>,
+    #[serde(with = "size")]
+    pub min_size: usize,
+    /// After decoding
+    #[serde(with = "size")]
+    pub max_size: usize,
+}
+
+impl ::std::default::Default for Extract {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("extracted"),
+            content_types: vec![
+                "application/octet-stream".to_string(),
+                "application/x-msdownload".to_string(),
+                "application/zip".to_string(),
+                "application/pdf".to_string(),
+                "application/javascript".to_string(),
+            ],
+            min_size: 1,
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Arp {
+    pub enabled: bool,
+    /// CSV IP/MAC inventory, empty to disable the export
+    pub file: PathBuf,
+    /// Plain numbers are seconds
+    #[serde(with = "secs")]
+    pub export_interval: Duration,
+}
+
+impl ::std::default::Default for Arp {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: PathBuf::new(),
+            export_interval: Duration::from_secs(60),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Capture {
+    /// Keeps flows from different VLANs apart when the address spaces overlap
+    pub vlan_flow_key: bool,
+    /// Keeps flows from different tunnels apart when the inner address spaces overlap. GTP-U
+    /// uses one TEID per direction, so it only suits taps that see one of them
+    pub tunnel_flow_key: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Fragments {
+    pub enabled: bool,
+    /// How long an incomplete datagram is kept, plain numbers are seconds
+    #[serde(with = "secs")]
+    pub timeout: Duration,
+    /// Buffered for incomplete datagrams, the oldest are dropped past it
+    #[serde(with = "size")]
+    pub max_memory: usize,
+    pub overlap_policy: OverlapPolicy,
+}
+
+impl ::std::default::Default for Fragments {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: Duration::from_secs(30),
+            max_memory: 16 * 1024 * 1024,
+            overlap_policy: OverlapPolicy::FIRST,
+        }
+    }
+}
+
+/// What to do when a fragment overlaps data already received
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    /// Keep the bytes that arrived first (Windows, BSD-right)
+    FIRST,
+    /// Let the newer fragment overwrite (Linux, Cisco)
+    LAST,
+    /// Overlaps are never legitimate, drop the whole datagram
+    DROP,
+}
+
+/// What a capture thread does when the queue of a worker is full
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    /// Wait for the workers, pcap drops in the kernel instead
+    #[serde(rename = "block")]
+    BLOCK,
+    /// Throw away the packet being pushed
+    #[serde(rename = "drop_newest")]
+    NEWEST,
+    /// Throw away the packet that waited the longest
+    #[serde(rename = "drop_oldest")]
+    OLDEST,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Queue {
+    /// Packets waiting between the capture threads and a worker
+    pub capacity: usize,
+    pub policy: QueuePolicy,
+    /// Preallocated packet buffers, reused once the handlers are done with a packet.
+    /// 0 sizes the pool with `Config::required_buffers`
+    pub buffers: usize,
+    /// Initial size of a buffer, larger packets grow it
+    #[serde(with = "size")]
+    pub buffer_size: usize,
+}
+
+impl ::std::default::Default for Queue {
+    fn default() -> Self {
+        Self {
+            capacity: 8192,
+            policy: QueuePolicy::BLOCK,
+            buffers: 0,
+            buffer_size: 2048,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Output {
+    #[serde(rename = "type")]
+    pub output_type: OutputType,
+    /// File for jsonl and csv, appended to, or socket for unix
+    pub path: PathBuf,
+    /// flow_started|flow_updated|flow_ended|dns|app|alert, every event when empty
+    pub events: Vec<String>,
+}
+
+impl ::std::default::Default for Output {
+    fn default() -> Self {
+        Self {
+            output_type: OutputType::STDOUT,
+            path: PathBuf::new(),
+            events: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputType {
+    /// One human readable line per event
+    STDOUT,
+    /// One JSON object per line
+    JSONL,
+    CSV,
+    /// JSON Lines to a Unix stream socket
+    UNIX,
+}
+
+impl OutputType {
+    /// Whether the output writes to `path`
+    pub fn has_path(&self) -> bool {
+        *self != OutputType::STDOUT
+    }
+}
+
+impl fmt::Display for OutputType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+fn default_outputs() -> Vec<Output> {
+    vec![Output::default()]
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Config {
+    #[serde(default)]
     pub general: General,
-    pub whatsapp: Whatsapp
+    #[serde(default)]
+    pub names: Names,
+    #[serde(default)]
+    pub apps: Apps,
+    #[serde(default)]
+    pub encrypted_dns: EncryptedDns,
+    #[serde(default)]
+    pub dns_analytics: DnsAnalytics,
+    #[serde(default)]
+    pub extract: Extract,
+    #[serde(default)]
+    pub arp: Arp,
+    #[serde(default)]
+    pub capture: Capture,
+    #[serde(default)]
+    pub fragments: Fragments,
+    #[serde(default)]
+    pub queue: Queue,
+    /// Every output receives the events it doesn't filter out
+    #[serde(default = "default_outputs")]
+    pub outputs: Vec<Output>,
 }
 
 impl ::std::default::Default for Config {
     fn default() -> Self {
         Self {
-            general: General {
-                mode: "interface".to_string(),
-                interface: "enx58ef68b4b1a5".to_string(),
-                file: "".to_string(),
-            },
-            whatsapp: Whatsapp {
-                debug: true,
-                file: "whatsapp".to_string()
+            general: General::default(),
+            names: Names::default(),
+            apps: Apps::default(),
+            encrypted_dns: EncryptedDns::default(),
+            dns_analytics: DnsAnalytics::default(),
+            extract: Extract::default(),
+            arp: Arp::default(),
+            capture: Capture::default(),
+            fragments: Fragments::default(),
+            queue: Queue::default(),
+            outputs: default_outputs(),
+        }
+    }
+}
+
+/// A value of the config that can't work, `key` is its path like `outputs[1].path`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file is missing or isn't TOML of the expected shape, with the file and the reason
+    Load(String, String),
+    /// Every problem found in the values
+    Invalid(Vec<Problem>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Load(path, error) => write!(f, "Couldn't load config file {}: {}", path, error),
+            ConfigError::Invalid(problems) => {
+                write!(f, "Invalid config, {} problem(s):", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
             }
         }
     }
 }
 
-pub fn load_config() -> Config {
-    let cfg: Config = confy::load("/stuff/perso/config").unwrap();
-    cfg
+impl std::error::Error for ConfigError {}
+
+/// Where `load_config` reads from: ./config.toml when there is one, else perso.toml in the
+/// user config directory, e.g. ~/.config/perso/perso.toml on Linux
+pub fn default_path() -> Result<PathBuf, ConfigError> {
+    let local = PathBuf::from("config.toml");
+    if local.is_file() {
+        return Ok(local);
+    }
+    ProjectDirs::from("rs", "", "perso")
+        .map(|project| project.config_dir().join("perso.toml"))
+        .ok_or_else(|| ConfigError::Load("perso.toml".to_string(), "no home directory to look in".to_string()))
+}
+
+/// Reads the config from `default_path`, which is created with the defaults if missing
+pub fn load_config() -> Result<Config, ConfigError> {
+    let path = default_path()?;
+    confy::load_path(&path).map_err(|e| ConfigError::Load(path.display().to_string(), e.to_string()))
+}
+
+/// Reads a TOML config file, the missing sections and fields keep their defaults.
+/// The values are checked by `Config::validate`, once the command line is applied
+pub fn load(path: &str) -> Result<Config, ConfigError> {
+    // confy would create a missing file, a typo in the path should fail instead
+    if !Path::new(path).is_file() {
+        return Err(ConfigError::Load(path.to_string(), "no such file".to_string()));
+    }
+    confy::load_path(path).map_err(|e| ConfigError::Load(path.to_string(), e.to_string()))
+}
+
+impl Config {
+    /// Checks the values that parse but can't work, and reports all of them
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut problem = |key: &str, message: String| problems.push(Problem { key: key.to_string(), message });
+
+        let general = &self.general;
+        match general.mode {
+            Mode::FILE if general.file.is_empty() && general.files.is_empty() => {
+                problem("general.file", "no capture file to read, set general.file or general.files, or use -r".to_string())
+            }
+            Mode::FILE if general.files.is_empty() => {
+                if !Path::new(&general.file).exists() {
+                    problem("general.file", format!("{} doesn't exist", general.file));
+                }
+            }
+            Mode::FILE => {
+                for (index, file) in general.files.iter().enumerate() {
+                    // Patterns are expanded when reading, they may match nothing yet
+                    if !file.contains('*') && !file.contains('?') && !Path::new(file).exists() {
+                        problem(&format!("general.files[{}]", index), format!("{} doesn't exist", file));
+                    }
+                }
+            }
+            Mode::INTERFACE => {
+                if general.timeout.as_millis() == 0 {
+                    problem("general.timeout", "must be above 0, the capture threads check for a shutdown between reads".to_string());
+                }
+                if general.interface.is_empty() && general.interfaces.is_empty() {
+                    if let Err(e) = crate::interface::default_device() {
+                        problem("general.interface", format!("no interface set and no default device: {}", e));
+                    }
+                }
+            }
+        }
+        if general.snaplen < 0 {
+            problem("general.snaplen", format!("{} is negative, use 0 for the libpcap default", general.snaplen));
+        }
+        if general.buffer_size > i32::MAX as usize {
+            problem("general.buffer_size", "must be below 2GiB".to_string());
+        }
+        if general.workers == 0 {
+            problem("general.workers", "must be at least 1".to_string());
+        }
+        if let Err(e) = crate::interface::check_filter(self) {
+            problem("general.filter", format!("\"{}\" doesn't compile: {}", general.filter, e));
+        }
+
+        if self.names.enabled && !self.names.file.as_os_str().is_empty() {
+            if let Some(message) = export_problem(&self.names.file, self.names.export_interval) {
+                problem(if self.names.export_interval.as_secs() == 0 { "names.export_interval" } else { "names.file" }, message);
+            }
+        }
+        if self.arp.enabled && !self.arp.file.as_os_str().is_empty() {
+            if let Some(message) = export_problem(&self.arp.file, self.arp.export_interval) {
+                problem(if self.arp.export_interval.as_secs() == 0 { "arp.export_interval" } else { "arp.file" }, message);
+            }
+        }
+
+        let analytics = &self.dns_analytics;
+        if analytics.enabled {
+            if analytics.window.as_secs() == 0 {
+                problem("dns_analytics.window", "must be at least 1s".to_string());
+            }
+            if !(0.0..=1.0).contains(&analytics.txt_ratio_threshold) {
+                problem("dns_analytics.txt_ratio_threshold", format!("{} isn't a ratio between 0 and 1", analytics.txt_ratio_threshold));
+            }
+            if !(0.0..=1.0).contains(&analytics.dga_threshold) {
+                problem("dns_analytics.dga_threshold", format!("{} isn't a score between 0 and 1", analytics.dga_threshold));
+            }
+            if !(1..=4).contains(&analytics.tunnel_min_score) {
+                problem("dns_analytics.tunnel_min_score", "must be between 1 and 4, the number of tunnelling heuristics".to_string());
+            }
+        }
+
+        let extract = &self.extract;
+        if extract.enabled {
+            if extract.directory.as_os_str().is_empty() {
+                problem("extract.directory", "must be set when extract.enabled is true".to_string());
+            }
+            if extract.max_size == 0 {
+                problem("extract.max_size", "must be above 0".to_string());
+            } else if extract.min_size > extract.max_size {
+                problem("extract.min_size", format!("{} is above extract.max_size ({})", extract.min_size, extract.max_size));
+            }
+        }
+
+        if self.fragments.enabled {
+            if self.fragments.timeout.as_secs() == 0 {
+                problem("fragments.timeout", "must be at least 1s".to_string());
+            }
+            if self.fragments.max_memory == 0 {
+                problem("fragments.max_memory", "must be above 0".to_string());
+            }
+        }
+
+        if self.queue.capacity == 0 {
+            problem("queue.capacity", "must be at least 1".to_string());
+        }
+        if self.queue.buffer_size == 0 {
+            problem("queue.buffer_size", "must be above 0".to_string());
+        }
+        let required = self.required_buffers();
+        if self.queue.buffers != 0 && self.queue.buffers < required {
+            problem(
+                "queue.buffers",
+                format!(
+                    "{} is below queue.capacity x general.workers plus the packets in flight ({}), full queues would allocate per packet, use 0 to size it",
+                    self.queue.buffers, required
+                ),
+            );
+        }
+
+        for (index, output) in self.outputs.iter().enumerate() {
+            if output.output_type.has_path() && output.path.as_os_str().is_empty() {
+                problem(&format!("outputs[{}].path", index), format!("required by {} outputs", output.output_type));
+            } else if output.output_type.has_path() && !parent_exists(&output.path) {
+                problem(&format!("outputs[{}].path", index), format!("the directory of {} doesn't exist", output.path.display()));
+            }
+            for name in output.events.iter() {
+                if !Event::NAMES.contains(&name.as_str()) {
+                    problem(
+                        &format!("outputs[{}].events", index),
+                        format!("unknown event \"{}\", expected one of {}", name, Event::NAMES.join("|")),
+                    );
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Buffers the pool needs so that full queues never allocate: every slot of every worker queue,
+    /// plus the packet each worker is handling and the one each capture thread is decoding
+    pub fn required_buffers(&self) -> usize {
+        let workers = self.general.workers.max(1);
+        let sources = crate::interface::sources(self).len();
+        self.queue.capacity.saturating_mul(workers) + workers + sources
+    }
+
+    /// `queue.buffers`, or what the queues need when it is 0
+    pub fn pool_buffers(&self) -> usize {
+        if self.queue.buffers == 0 {
+            self.required_buffers()
+        } else {
+            self.queue.buffers
+        }
+    }
+}
+
+/// What is wrong with a periodic CSV export, if anything
+fn export_problem(file: &Path, interval: Duration) -> Option<String> {
+    if interval.as_secs() == 0 {
+        Some("must be at least 1s".to_string())
+    } else if !parent_exists(file) {
+        Some(format!("the directory of {} doesn't exist", file.display()))
+    } else {
+        None
+    }
+}
+
+fn parent_exists(path: &Path) -> bool {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.is_dir(),
+        _ => true,
+    }
+}
+
+/// Durations are a plain number in the unit of the field, or a string like "500ms", "30s", "5m" or "1h"
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = split_unit(value)?;
+    let millis = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" | "min" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return Err(format!("unknown duration unit in \"{}\", expected ms, s, m or h", value)),
+    };
+    match number.checked_mul(millis) {
+        Some(millis) => Ok(Duration::from_millis(millis)),
+        None => Err(format!("\"{}\" is too long", value)),
+    }
+}
+
+/// Sizes are a plain number of bytes, or a string like "2048", "64KiB", "16MiB" or "1GB"
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, unit) = split_unit(value)?;
+    let scale: u64 = match unit {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "KB" => 1000,
+        "MB" => 1000 * 1000,
+        "GB" => 1000 * 1000 * 1000,
+        _ => return Err(format!("unknown size unit in \"{}\", expected B, KiB, MiB, GiB, KB, MB or GB", value)),
+    };
+    match number.checked_mul(scale).and_then(|bytes| usize::try_from(bytes).ok()) {
+        Some(bytes) => Ok(bytes),
+        None => Err(format!("\"{}\" is too large", value)),
+    }
+}
+
+fn split_unit(value: &str) -> Result<(u64, &str), String> {
+    let value = value.trim();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    if digits == 0 {
+        return Err(format!("\"{}\" doesn't start with a number", value));
+    }
+    match value[..digits].parse::<u64>() {
+        Ok(number) => Ok((number, value[digits..].trim())),
+        Err(_) => Err(format!("\"{}\" is too large", value)),
+    }
+}
+
+/// Accepts a number in `unit`, or a string that `parse` understands
+struct UnitVisitor<T> {
+    expecting: &'static str,
+    unit: fn(u64) -> T,
+    parse: fn(&str) -> Result<T, String>,
+}
+
+impl<'de, T> de::Visitor<'de> for UnitVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        Ok((self.unit)(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+        if value < 0 {
+            return Err(E::custom(format!("{} is negative", value)));
+        }
+        Ok((self.unit)(value as u64))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+        // A quoted plain number is in the unit of the field, as it would be unquoted
+        match split_unit(value) {
+            Ok((number, "")) => Ok((self.unit)(number)),
+            _ => (self.parse)(value).map_err(E::custom),
+        }
+    }
+}
+
+/// Plain numbers are seconds
+mod secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(value.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserializer.deserialize_any(UnitVisitor {
+            expecting: "seconds or a duration like \"30s\"",
+            unit: Duration::from_secs,
+            parse: parse_duration,
+        })
+    }
+}
+
+/// Plain numbers are milliseconds
+mod millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(value.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserializer.deserialize_any(UnitVisitor {
+            expecting: "milliseconds or a duration like \"500ms\"",
+            unit: Duration::from_millis,
+            parse: parse_duration,
+        })
+    }
+}
+
+/// Plain numbers are bytes
+mod size {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &usize, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(*value as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_any(UnitVisitor {
+            expecting: "bytes or a size like \"16MiB\"",
+            unit: |bytes| bytes as usize,
+            parse: parse_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 5 m "), Ok(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("2min"), Ok(Duration::from_secs(2 * 60)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(60 * 60)));
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("1.5s").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("2048"), Ok(2048));
+        assert_eq!(parse_size("2048B"), Ok(2048));
+        assert_eq!(parse_size("64KiB"), Ok(64 * 1024));
+        assert_eq!(parse_size("16M"), Ok(16 * 1024 * 1024));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert_eq!(parse_size("64KB"), Ok(64 * 1000));
+        assert_eq!(parse_size("1GB"), Ok(1000 * 1000 * 1000));
+        assert!(parse_size("1TB").is_err());
+        assert!(parse_size("MiB").is_err());
+    }
+
+    #[test]
+    fn rejects_overflows() {
+        assert!(parse_duration("99999999999999999999ms").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+        assert!(parse_size("18446744073709551615GiB").is_err());
+    }
+
+    #[test]
+    fn plain_numbers_are_in_the_unit_of_the_field() {
+        for timeout in ["timeout=1000", "timeout=\"1000\"", "timeout=\"1s\""] {
+            let general: General = toml::from_str(timeout).unwrap();
+            assert_eq!(general.timeout, Duration::from_millis(1000), "{}", timeout);
+        }
+        for interval in ["export_interval=60", "export_interval=\"60\"", "export_interval=\"1m\""] {
+            let names: Names = toml::from_str(interval).unwrap();
+            assert_eq!(names.export_interval, Duration::from_secs(60), "{}", interval);
+        }
+        for max_memory in ["max_memory=2048", "max_memory=\"2048\"", "max_memory=\"2KiB\""] {
+            let fragments: Fragments = toml::from_str(max_memory).unwrap();
+            assert_eq!(fragments.max_memory, 2048, "{}", max_memory);
+        }
+        assert!(toml::from_str::<General>("timeout=-1").is_err());
+        assert!(toml::from_str::<General>("timeout=\"1 week\"").is_err());
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let mut config = Config::default();
+        config.general.interface = "eth0".to_string();
+        assert!(config.validate().is_ok());
+
+        config.general.workers = 0;
+        config.dns_analytics.dga_threshold = 2.0;
+        config.queue.capacity = 0;
+        config.outputs.push(Output { output_type: OutputType::JSONL, path: PathBuf::new(), events: Vec::new() });
+        let problems = match config.validate() {
+            Err(ConfigError::Invalid(problems)) => problems,
+            other => panic!("expected problems, got {:?}", other),
+        };
+        let keys: Vec<&str> = problems.iter().map(|problem| problem.key.as_str()).collect();
+        assert_eq!(keys, ["general.workers", "dns_analytics.dga_threshold", "queue.capacity", "outputs[1].path"]);
+    }
+
+    #[test]
+    fn template_is_the_default() {
+        assert_eq!(toml::from_str::<Config>(TEMPLATE).unwrap(), Config::default());
+    }
 }
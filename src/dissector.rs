@@ -0,0 +1,81 @@
+use std::{fmt, net::Ipv4Addr, sync::Arc};
+
+use crate::{events::Event, utils::AppType};
+
+/// What the dissectors of a flow know and share about it
+#[derive(Debug, Clone)]
+pub struct Flow {
+    /// IP protocol number, 6 or 17
+    pub protocol: u8,
+    pub client: (Ipv4Addr, u16),
+    pub server: (Ipv4Addr, u16),
+    /// Set by the dissector that recognized the application
+    pub app_type: AppType,
+    /// Set by the TLS and QUIC dissectors
+    pub sni: Option<String>,
+    /// Capture time in milliseconds of the packet being parsed, of the last one when the flow closes
+    pub ts: u128,
+}
+
+/// Application protocol parser, decides which flows it follows and starts a
+/// `FlowDissector` for each of them
+pub trait Dissector: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Looks at the first payload of a flow, the first in-order bytes for TCP,
+    /// and returns true to follow it. Flows are only probed once
+    fn probe(&self, flow: &Flow, from_client: bool, payload: &[u8]) -> bool;
+
+    /// Per-flow state of a flow `probe` accepted
+    fn start(&self) -> Box<dyn FlowDissector>;
+}
+
+/// Parser of one flow
+pub trait FlowDissector: Send {
+    /// Receives the in-order stream data of a TCP flow or the datagrams of a UDP flow,
+    /// starting with the payload that was probed
+    fn parse(&mut self, flow: &mut Flow, from_client: bool, data: &[u8], events: &mut Vec<Event>);
+
+    /// The flow ended, timed out or the input ended
+    fn close(&mut self, _flow: &mut Flow, _events: &mut Vec<Event>) {}
+}
+
+impl fmt::Debug for dyn FlowDissector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FlowDissector")
+    }
+}
+
+/// Dissectors by transport, probed in registration order
+#[derive(Default, Clone)]
+pub struct Registry {
+    tcp: Vec<Arc<dyn Dissector>>,
+    udp: Vec<Arc<dyn Dissector>>,
+}
+
+impl Registry {
+    /// `protocol` is 6 for TCP or 17 for UDP, anything else is ignored
+    pub fn register(&mut self, protocol: u8, dissector: Arc<dyn Dissector>) {
+        match protocol {
+            6 => self.tcp.push(dissector),
+            17 => self.udp.push(dissector),
+            _ => log::warn!("Dissector {} registered for protocol {}, only TCP and UDP are dissected", dissector.name(), protocol),
+        }
+    }
+
+    /// Adds the dissectors of `other` after ours
+    pub fn extend(&mut self, other: Registry) {
+        self.tcp.extend(other.tcp);
+        self.udp.extend(other.udp);
+    }
+
+    /// Starts every dissector that wants the flow
+    pub(crate) fn start(&self, flow: &Flow, from_client: bool, payload: &[u8]) -> Vec<Box<dyn FlowDissector>> {
+        let dissectors = if flow.protocol == 6 { &self.tcp } else { &self.udp };
+        dissectors
+            .iter()
+            .filter(|dissector| dissector.probe(flow, from_client, payload))
+            .map(|dissector| dissector.start())
+            .collect()
+    }
+}
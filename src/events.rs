@@ -0,0 +1,186 @@
+use std::{net::Ipv4Addr, sync::Mutex};
+
+use crate::{
+    alerts::Alert,
+    handlers::{http::HttpTransaction, http2::Http2Stream, icmp::IcmpError},
+    utils::{AppType, Tunnel},
+};
+
+/// What the analyzer hands to the sinks, timestamps are milliseconds since the epoch
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// First packet of a UDP flow or SYN ACK of a TCP connection
+    FlowStarted(FlowRecord),
+    /// The dissectors recognized the application or learned the SNI
+    FlowUpdated(FlowRecord),
+    /// The flow ended, timed out or was still open when the input ended
+    FlowEnded(FlowRecord),
+    Dns(DnsTransaction),
+    App(AppEvent),
+    Alert(Alert),
+}
+
+impl Event {
+    /// Every event name, in the order of the variants
+    pub const NAMES: [&'static str; 6] = ["flow_started", "flow_updated", "flow_ended", "dns", "app", "alert"];
+
+    /// Name used by the outputs, and by their `events` filter
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::FlowStarted(_) => "flow_started",
+            Event::FlowUpdated(_) => "flow_updated",
+            Event::FlowEnded(_) => "flow_ended",
+            Event::Dns(_) => "dns",
+            Event::App(_) => "app",
+            Event::Alert(_) => "alert",
+        }
+    }
+}
+
+/// A TCP or UDP flow, as it was when the event was emitted. Like every event timestamp,
+/// `first_ts`, `last_ts` and the ICMP error `ts` are capture times in milliseconds since the epoch
+#[derive(Debug, Clone)]
+pub struct FlowRecord {
+    /// IP protocol number, 6 or 17
+    pub protocol: u8,
+    pub client: (Ipv4Addr, u16),
+    pub server: (Ipv4Addr, u16),
+    /// 0 unless VLANs are part of the flow key
+    pub vlan: u16,
+    pub first_ts: u128,
+    pub last_ts: u128,
+    pub packets: usize,
+    pub app_type: AppType,
+    pub sni: Option<String>,
+    /// Names harvested from mDNS, LLMNR or NBNS when the flow started
+    pub client_name: Option<String>,
+    pub server_name: Option<String>,
+    /// Last ICMP error a router or the server reported about the flow
+    pub icmp_error: Option<IcmpError>,
+    /// Capture sources the flow was seen on
+    pub interfaces: Vec<u16>,
+    /// Outer tunnels the flow was carried in, outermost first
+    pub tunnels: Vec<Tunnel>,
+}
+
+/// A DNS response and the question it answers
+#[derive(Debug, Clone)]
+pub struct DnsTransaction {
+    pub ts: u128,
+    pub client: Ipv4Addr,
+    pub server: Ipv4Addr,
+    pub id: u16,
+    pub questions: Vec<String>,
+    /// A and CNAME data, in answer order
+    pub answers: Vec<String>,
+    /// NOERROR, NXDOMAIN...
+    pub response_code: String,
+}
+
+/// Something an application parser found in a flow
+#[derive(Debug, Clone)]
+pub struct AppEvent {
+    pub ts: u128,
+    pub client: (Ipv4Addr, u16),
+    pub server: (Ipv4Addr, u16),
+    /// http, http2, tls or quic
+    pub protocol: &'static str,
+    /// Host header, :authority or SNI
+    pub host: Option<String>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub status: Option<u16>,
+}
+
+/// Receives the events of an analyzer, mostly from the packet handler thread
+pub trait EventSink: Send {
+    fn event(&mut self, event: &Event);
+
+    /// Called every second and once the input has ended, for the sinks that buffer
+    fn flush(&mut self) {}
+}
+
+impl<F: FnMut(&Event) + Send> EventSink for F {
+    fn event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// The sinks of an analyzer, shared by the handlers
+#[derive(Default)]
+pub(crate) struct Events {
+    sinks: Mutex<Sinks>,
+}
+
+#[derive(Default)]
+struct Sinks {
+    /// Opened from the `outputs` of the config, replaced by a reload
+    outputs: Vec<Box<dyn EventSink>>,
+    /// Added with `AnalyzerBuilder::sink`
+    added: Vec<Box<dyn EventSink>>,
+}
+
+impl Events {
+    pub fn new(outputs: Vec<Box<dyn EventSink>>, added: Vec<Box<dyn EventSink>>) -> Events {
+        Events { sinks: Mutex::new(Sinks { outputs, added }) }
+    }
+
+    pub fn emit(&self, event: Event) {
+        let sinks = &mut *self.sinks.lock().unwrap();
+        for sink in sinks.outputs.iter_mut().chain(sinks.added.iter_mut()) {
+            sink.event(&event);
+        }
+    }
+
+    pub fn flush(&self) {
+        let sinks = &mut *self.sinks.lock().unwrap();
+        for sink in sinks.outputs.iter_mut().chain(sinks.added.iter_mut()) {
+            sink.flush();
+        }
+    }
+
+    /// Swaps the outputs between two events, the old ones are flushed and closed
+    pub fn replace_outputs(&self, outputs: Vec<Box<dyn EventSink>>) {
+        let old = {
+            let mut sinks = self.sinks.lock().unwrap();
+            std::mem::replace(&mut sinks.outputs, outputs)
+        };
+        for mut sink in old {
+            sink.flush();
+        }
+    }
+}
+
+impl AppEvent {
+    pub(crate) fn http(ts: u128, client: (Ipv4Addr, u16), server: (Ipv4Addr, u16), transaction: &HttpTransaction) -> AppEvent {
+        let request = &transaction.request;
+        AppEvent {
+            ts,
+            client,
+            server,
+            protocol: "http",
+            host: request.host.clone(),
+            method: Some(request.method.clone()),
+            path: Some(request.uri.clone()),
+            status: transaction.response.as_ref().map(|response| response.status),
+        }
+    }
+
+    pub(crate) fn http2(ts: u128, client: (Ipv4Addr, u16), server: (Ipv4Addr, u16), stream: &Http2Stream) -> AppEvent {
+        AppEvent {
+            ts,
+            client,
+            server,
+            protocol: "http2",
+            host: stream.authority.clone(),
+            method: stream.method.clone(),
+            path: stream.path.clone(),
+            status: stream.status,
+        }
+    }
+
+    /// TLS or QUIC ClientHello
+    pub(crate) fn hello(ts: u128, protocol: &'static str, client: (Ipv4Addr, u16), server: (Ipv4Addr, u16), sni: &Option<String>) -> AppEvent {
+        AppEvent { ts, client, server, protocol, host: sni.clone(), method: None, path: None, status: None }
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::AtomicBool},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::shutdown;
+
+/// Writes a table as CSV, like `hosts::export` and `inventory::export`
+pub type Exporter<T> = fn(&T, &mut dyn Write) -> io::Result<()>;
+
+/// Periodically dumps `table` to `path` until `running` is cleared, nothing is written
+/// while `path` is empty. `name` is what the error messages call the table
+pub fn run<T: Clone + Send + 'static>(
+    name: &'static str,
+    path: &Path,
+    interval: Duration,
+    table: &Arc<Mutex<T>>,
+    exporter: Exporter<T>,
+    running: &Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    let table = table.clone();
+    let path: PathBuf = path.to_path_buf();
+    let interval = interval.max(Duration::from_secs(1));
+    let running = running.clone();
+
+    thread::spawn(move || {
+        while shutdown::sleep(&running, interval) {
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            write(name, &path, &table, exporter);
+        }
+    })
+}
+
+/// Writes a snapshot of `table` to `path`, the lock isn't held while writing
+pub fn write<T: Clone>(name: &str, path: &Path, table: &Arc<Mutex<T>>, exporter: Exporter<T>) {
+    let table = table.lock().unwrap().clone();
+    match File::create(path) {
+        Ok(mut file) => {
+            if let Err(e) = exporter(&table, &mut file) {
+                log::warn!("Couldn't export {}: {}", name, e);
+            }
+        }
+        Err(e) => log::warn!("Couldn't create {} file {}: {}", name, path.display(), e),
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    net::Ipv4Addr,
+    path::Path,
+    sync::{Arc, atomic::Ordering},
+};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, handlers::http::HttpTransaction, stats::Stats};
+
+#[derive(Debug, Serialize)]
+struct Sidecar<'a> {
+    ts: u128,
+    client: String,
+    server: String,
+    method: &'a str,
+    host: Option<&'a str>,
+    uri: &'a str,
+    status: u16,
+    content_type: Option<&'a str>,
+    content_encoding: Option<&'a str>,
+    wire_size: usize,
+    size: usize,
+    sha256: &'a str,
+}
+
+/// Writes the body of a carved HTTP response to `extract.directory` as `<sha256>`,
+/// next to a `<sha256>.json` sidecar describing where it came from
+pub fn write(
+    config: &Config,
+    ts: u128,
+    client: (Ipv4Addr, u16),
+    server: (Ipv4Addr, u16),
+    transaction: &HttpTransaction,
+    stats: &Arc<Stats>,
+) {
+    let response = match &transaction.response {
+        Some(response) => response,
+        None => return,
+    };
+    let body = match &response.body {
+        Some(body) if !body.is_empty() => body,
+        _ => return,
+    };
+    let content = match decode(body, response.content_encoding.as_deref(), config.extract.max_size) {
+        Some(content) => content,
+        None => {
+            log::warn!("[EXTRACT] Couldn't decode {:?} body of {}", response.content_encoding, transaction.request.uri);
+            return;
+        }
+    };
+    if content.len() < config.extract.min_size || content.len() > config.extract.max_size {
+        return;
+    }
+
+    let hash: String = Sha256::digest(&content).iter().map(|byte| format!("{:02x}", byte)).collect();
+    let directory = Path::new(&config.extract.directory);
+    if let Err(e) = fs::create_dir_all(directory) {
+        log::warn!("[EXTRACT] Couldn't create {}: {}", directory.display(), e);
+        return;
+    }
+
+    let sidecar = Sidecar {
+        ts,
+        client: format!("{}:{}", client.0, client.1),
+        server: format!("{}:{}", server.0, server.1),
+        method: &transaction.request.method,
+        host: transaction.request.host.as_deref(),
+        uri: &transaction.request.uri,
+        status: response.status,
+        content_type: response.content_type.as_deref(),
+        content_encoding: response.content_encoding.as_deref(),
+        wire_size: body.len(),
+        size: content.len(),
+        sha256: &hash,
+    };
+
+    let object_path = directory.join(&hash);
+    // The same object seen twice is only written once, its sidecar describes the last sighting
+    if !object_path.exists() {
+        if let Err(e) = File::create(&object_path).and_then(|mut file| file.write_all(&content)) {
+            log::warn!("[EXTRACT] Couldn't write {:?}: {}", object_path, e);
+            return;
+        }
+    }
+    let sidecar_path = directory.join(format!("{}.json", hash));
+    match serde_json::to_vec_pretty(&sidecar) {
+        Ok(json) => {
+            if let Err(e) = File::create(&sidecar_path).and_then(|mut file| file.write_all(&json)) {
+                log::warn!("[EXTRACT] Couldn't write {:?}: {}", sidecar_path, e);
+                return;
+            }
+        }
+        Err(e) => log::warn!("[EXTRACT] Couldn't serialize sidecar: {}", e),
+    }
+    stats.extracted.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Undoes the content encoding, giving up past `max_size` decoded bytes
+fn decode(body: &[u8], content_encoding: Option<&str>, max_size: usize) -> Option<Vec<u8>> {
+    let mut content = Vec::new();
+    let limit = max_size as u64 + 1;
+    let result = match content_encoding {
+        None | Some("identity") => return Some(body.to_vec()),
+        Some("gzip") | Some("x-gzip") => GzDecoder::new(body).take(limit).read_to_end(&mut content),
+        // "deflate" is supposed to be zlib wrapped but some servers send raw deflate
+        Some("deflate") => match ZlibDecoder::new(body).take(limit).read_to_end(&mut content) {
+            Ok(len) => Ok(len),
+            Err(_) => {
+                content.clear();
+                DeflateDecoder::new(body).take(limit).read_to_end(&mut content)
+            }
+        },
+        Some(_) => return None,
+    };
+    result.ok().map(|_| content)
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex, atomic::Ordering},
+};
+
+use crate::{
+    alerts::{self, Alert, AlertType},
+    events::Event,
+    inventory::ArpEntry,
+    stats::Stats,
+    utils::format_mac,
+};
+
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+/// Parses an Ethernet/IPv4 ARP packet and updates the IP/MAC table, a MAC change raises an alert,
+/// `ts` is the capture time in milliseconds
+pub fn handle(
+    table: &Arc<Mutex<HashMap<Ipv4Addr, ArpEntry>>>,
+    ts: u128,
+    payload: &[u8],
+    events: &mut Vec<Event>,
+    stats: &Arc<Stats>,
+) {
+    // htype(2) ptype(2) hlen(1) plen(1) oper(2) sha(6) spa(4) tha(6) tpa(4)
+    if payload.len() < 28
+        || payload[0..2] != [0, 1]
+        || payload[2..4] != [0x08, 0x00]
+        || payload[4] != 6
+        || payload[5] != 4
+    {
+        return;
+    }
+    stats.arp.fetch_add(1, Ordering::Relaxed);
+
+    let operation = u16::from_be_bytes([payload[6], payload[7]]);
+    let mut sender_mac = [0u8; 6];
+    sender_mac.copy_from_slice(&payload[8..14]);
+    let sender_ip = Ipv4Addr::new(payload[14], payload[15], payload[16], payload[17]);
+    let target_mac = &payload[18..24];
+    let target_ip = Ipv4Addr::new(payload[24], payload[25], payload[26], payload[27]);
+
+    // ARP probes (RFC 5227) don't claim any address yet
+    if sender_ip.is_unspecified() || (operation != ARP_REQUEST && operation != ARP_REPLY) {
+        return;
+    }
+    // Announcements repeat the sender address as target, unsolicited replies go to broadcast
+    let gratuitous = sender_ip == target_ip || (operation == ARP_REPLY && target_mac == [0xff; 6]);
+    if gratuitous {
+        log::info!("[ARP] Gratuitous {} is-at {}", sender_ip, format_mac(&sender_mac));
+    }
+
+    let mut table = table.lock().unwrap();
+    let entry = table
+        .entry(sender_ip)
+        .or_insert_with(|| ArpEntry::new(sender_ip, sender_mac, ts));
+    entry.last_ts = ts;
+    if gratuitous {
+        entry.gratuitous += 1;
+    }
+    if entry.mac == sender_mac {
+        return;
+    }
+
+    let previous = entry.mac;
+    let mut evidence = vec![format!("previous mac {} seen since {}", format_mac(&previous), entry.first_ts)];
+    if gratuitous {
+        evidence.push("announced by gratuitous ARP".to_string());
+    }
+    // A MAC coming back to an address it held before is a common poisoning flip-flop
+    if entry.previous_macs.contains(&sender_mac) {
+        evidence.push("mac flip-flop".to_string());
+    }
+    entry.previous_macs.retain(|mac| *mac != sender_mac);
+    entry.previous_macs.push(previous);
+    entry.mac = sender_mac;
+
+    let score = evidence.len() as f64;
+    drop(table);
+    alerts::raise(
+        Alert {
+            ts,
+            alert_type: AlertType::SPOOFING,
+            client: sender_ip,
+            subject: format_mac(&sender_mac),
+            score,
+            evidence,
+        },
+        events,
+        stats,
+    );
+}
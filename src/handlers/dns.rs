@@ -1,10 +1,5 @@
 use std::{collections::HashMap, net::Ipv4Addr, sync::{Arc, Mutex, atomic::Ordering}};
-use crate::{stats::Stats, utils::{DnsRecord, DnsRecordType, AppType}, };
-use phf::phf_map;
-
-static DNS_APPS: phf::Map<&'static str, AppType> = phf_map! {
-    "g.whatsapp.net" => AppType::WHATSAPP
-};
+use crate::{config::Config, dissector::{Dissector, Flow, FlowDissector}, events::{DnsTransaction, Event}, handlers::dns_analytics::{self, DnsAnalytics}, reload::LiveConfig, stats::Stats, utils::{DnsRecord, DnsRecordType, AppType}, };
 
 pub fn _get_ip(cname: String, dns_records: &HashMap<String, DnsRecord>) -> Ipv4Addr {
     if dns_records.contains_key(&cname) {
@@ -21,15 +16,38 @@ pub fn _get_ip(cname: String, dns_records: &HashMap<String, DnsRecord>) -> Ipv4A
     return Ipv4Addr::new(0, 0, 0, 0);
 }
 
-pub fn handle(payload: &[u8], dns_records: &Arc<Mutex<HashMap<DnsRecord, String>>>, stats: &Arc<Stats>) {
+/// Learns the A and CNAME records of a DNS packet, reports the transaction if it is a response
+fn handle(dissector: &DnsDissector, ts: u128, source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8], events: &mut Vec<Event>) {
+    // The analytics thresholds of the latest reload
+    let config = &*dissector.config.get();
+    let stats = &dissector.stats;
     match dns_parser::Packet::parse(payload) {
-        Err(e) => println!("{:?}", e),
+        Err(e) => {
+            log::debug!("{:?}", e);
+        }
         Ok(dns_packet) => {
             stats.dns.fetch_add(1, Ordering::Relaxed);
-            let mut dns_records = dns_records.lock().unwrap();
+            if config.dns_analytics.enabled {
+                let mut analytics = dissector.analytics.lock().unwrap();
+                analytics.roll_window(config, ts);
+                if dns_packet.header.query {
+                    for question in &dns_packet.questions {
+                        let txt_or_null = question.qtype == dns_parser::QueryType::TXT
+                            || question.qtype == dns_parser::QueryType::NULL;
+                        dns_analytics::on_query(&mut analytics, config, source, &question.qname.to_string(), txt_or_null, events, stats);
+                    }
+                } else if dns_packet.header.response_code == dns_parser::ResponseCode::NameError {
+                    for question in &dns_packet.questions {
+                        dns_analytics::on_nxdomain(&mut analytics, config, destination, &question.qname.to_string(), events, stats);
+                    }
+                }
+            }
+            let mut answers = Vec::new();
+            let mut dns_records = dissector.dns_records.lock().unwrap();
             for record in dns_packet.answers {
                 match record.data {
                     dns_parser::RData::A(data) => {
+                        answers.push(data.0.to_string());
                         dns_records.insert(
                             DnsRecord {
                                 data: data.0.to_string(),
@@ -40,6 +58,7 @@ pub fn handle(payload: &[u8], dns_records: &Arc<Mutex<HashMap<DnsRecord, String>
                     }
                     dns_parser::RData::AAAA(_) => (),
                     dns_parser::RData::CNAME(data) => {
+                        answers.push(data.0.to_string());
                         dns_records.insert(
                             DnsRecord {
                                 data: data.0.to_string(),
@@ -58,6 +77,18 @@ pub fn handle(payload: &[u8], dns_records: &Arc<Mutex<HashMap<DnsRecord, String>
                 }
             }
             //println!("[DNS] {:?}", getIp(String::from("www.youtube.com"), &dnsRecords));
+            if dns_packet.header.query {
+                return;
+            }
+            events.push(Event::Dns(DnsTransaction {
+                ts,
+                client: destination,
+                server: source,
+                id: dns_packet.header.id,
+                questions: dns_packet.questions.iter().map(|question| question.qname.to_string()).collect(),
+                answers,
+                response_code: format!("{:?}", dns_packet.header.response_code),
+            }));
         }
     }
 }
@@ -78,6 +109,37 @@ pub fn parse_dns_record(dns_record: DnsRecord, dns_records: &HashMap<DnsRecord,
     
 }
 
-pub fn dns_to_app(dns: &str) -> Option<AppType>{
-    return DNS_APPS.get(dns).cloned();
+/// The application `apps.domains` maps the name to
+pub fn dns_to_app(config: &Config, dns: &str) -> Option<AppType> {
+    config.apps.domains.get(dns).copied()
+}
+
+/// Learns the DNS records and reports the transactions of the flows to or from port 53
+#[derive(Clone)]
+pub struct DnsDissector {
+    pub config: Arc<LiveConfig>,
+    pub dns_records: Arc<Mutex<HashMap<DnsRecord, String>>>,
+    pub analytics: Arc<Mutex<DnsAnalytics>>,
+    pub stats: Arc<Stats>,
+}
+
+impl Dissector for DnsDissector {
+    fn name(&self) -> &'static str {
+        "dns"
+    }
+
+    fn probe(&self, flow: &Flow, _from_client: bool, _payload: &[u8]) -> bool {
+        flow.client.1 == 53 || flow.server.1 == 53
+    }
+
+    fn start(&self) -> Box<dyn FlowDissector> {
+        Box::new(self.clone())
+    }
+}
+
+impl FlowDissector for DnsDissector {
+    fn parse(&mut self, flow: &mut Flow, from_client: bool, data: &[u8], events: &mut Vec<Event>) {
+        let (source, destination) = if from_client { (flow.client.0, flow.server.0) } else { (flow.server.0, flow.client.0) };
+        handle(self, flow.ts, source, destination, data, events);
+    }
 }
@@ -0,0 +1,271 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    sync::Arc,
+};
+
+use phf::phf_set;
+
+use crate::{
+    alerts::{self, Alert, AlertType},
+    config::Config,
+    events::Event,
+    stats::Stats,
+};
+
+/// The most frequent letter pairs in English and in common hostnames,
+/// domains made mostly of other pairs are likely machine generated
+static COMMON_BIGRAMS: phf::Set<&'static str> = phf_set! {
+    "th", "he", "in", "er", "an", "re", "on", "at", "en", "nd", "ti", "es", "or", "te", "of",
+    "ed", "is", "it", "al", "ar", "st", "to", "nt", "ng", "se", "ha", "as", "ou", "io", "le",
+    "ve", "co", "me", "de", "hi", "ri", "ro", "ic", "ne", "ea", "ra", "ce", "li", "ch", "ll",
+    "be", "ma", "si", "om", "ur", "ca", "el", "ta", "la", "ns", "ge", "ly", "ei", "os", "no",
+    "pe", "do", "su", "pa", "ec", "ac", "ot", "di", "ol", "tr", "sh", "us", "ad", "lo", "il",
+    "wa", "ss", "ee", "oo", "ap", "am", "em", "et", "ie", "ow", "ay", "ub", "ut", "up",
+    "we", "bo", "fo", "go", "mo", "po", "so", "vi", "ws", "ww", "ob", "oc", "ud", "ag", "ck",
+};
+
+#[derive(Debug, Default)]
+struct DomainStats {
+    queries: usize,
+    txt_null: usize,
+    subdomains: HashSet<String>,
+}
+
+/// Per-window state of the DNS analytics, reset every `dns_analytics.window`
+#[derive(Debug, Default)]
+pub struct DnsAnalytics {
+    window_start: u128,
+    /// Capture time of the DNS packet being scored, in milliseconds
+    ts: u128,
+    domains: HashMap<String, DomainStats>,
+    nxdomains: HashMap<Ipv4Addr, Vec<String>>,
+    alerted: HashSet<String>,
+}
+
+impl DnsAnalytics {
+    pub fn new() -> DnsAnalytics {
+        DnsAnalytics::default()
+    }
+
+    /// Moves to the capture time of the next DNS packet, a new window starts once the current one is over
+    pub fn roll_window(&mut self, config: &Config, ts: u128) {
+        self.ts = ts;
+        // A clock stepping back doesn't end the window
+        if ts.saturating_sub(self.window_start) >= config.dns_analytics.window.as_millis() {
+            self.window_start = ts;
+            self.domains.clear();
+            self.nxdomains.clear();
+            self.alerted.clear();
+        }
+    }
+
+    /// Returns true the first time a given alert is raised in the current window
+    fn should_alert(&mut self, alert_type: AlertType, subject: &str) -> bool {
+        self.alerted.insert(format!("{:?}:{}", alert_type, subject))
+    }
+}
+
+/// Scores a query for tunnelling and DGA, called for every question of a DNS query once
+/// `roll_window` moved to its capture time
+pub fn on_query(
+    analytics: &mut DnsAnalytics,
+    config: &Config,
+    client: Ipv4Addr,
+    qname: &str,
+    txt_or_null: bool,
+    events: &mut Vec<Event>,
+    stats: &Arc<Stats>,
+) {
+    let cfg = &config.dns_analytics;
+    let ts = analytics.ts;
+
+    let qname = qname.trim_end_matches('.').to_lowercase();
+    let domain = registered_domain(&qname);
+    let subdomain = qname[..qname.len() - domain.len()].trim_end_matches('.');
+
+    let domain_stats = analytics.domains.entry(domain.to_string()).or_default();
+    domain_stats.queries += 1;
+    if txt_or_null {
+        domain_stats.txt_null += 1;
+    }
+    if !subdomain.is_empty() {
+        domain_stats.subdomains.insert(subdomain.to_string());
+    }
+
+    // Tunnelling: every heuristic that fires adds a point and its evidence
+    let mut evidence = Vec::new();
+    if let Some(label) = subdomain.split('.').find(|label| label.len() > cfg.max_label_len) {
+        evidence.push(format!("label length {} > {}", label.len(), cfg.max_label_len));
+    }
+    let payload: String = subdomain.split('.').collect();
+    if payload.len() >= cfg.entropy_min_len {
+        let entropy = entropy(&payload);
+        if entropy >= cfg.entropy_threshold {
+            evidence.push(format!("subdomain entropy {:.2} >= {:.2}", entropy, cfg.entropy_threshold));
+        }
+    }
+    if domain_stats.subdomains.len() > cfg.subdomain_threshold {
+        evidence.push(format!(
+            "{} distinct subdomains in {:?}",
+            domain_stats.subdomains.len(),
+            cfg.window
+        ));
+    }
+    if domain_stats.queries >= cfg.txt_min_queries {
+        let ratio = domain_stats.txt_null as f64 / domain_stats.queries as f64;
+        if ratio >= cfg.txt_ratio_threshold {
+            evidence.push(format!(
+                "TXT/NULL {:.0}% of {} queries",
+                ratio * 100.0,
+                domain_stats.queries
+            ));
+        }
+    }
+    if evidence.len() >= cfg.tunnel_min_score && analytics.should_alert(AlertType::TUNNEL, domain) {
+        alerts::raise(
+            Alert {
+                ts,
+                alert_type: AlertType::TUNNEL,
+                client,
+                subject: domain.to_string(),
+                score: evidence.len() as f64,
+                evidence,
+            },
+            events,
+            stats,
+        );
+    }
+
+    // DGA: only the label right under the public suffix is algorithmically generated
+    let label = domain.split('.').next().unwrap_or("");
+    if label.len() >= cfg.dga_min_len {
+        let (score, evidence) = dga_score(label);
+        if score >= cfg.dga_threshold && analytics.should_alert(AlertType::DGA, domain) {
+            alerts::raise(
+                Alert {
+                    ts,
+                    alert_type: AlertType::DGA,
+                    client,
+                    subject: domain.to_string(),
+                    score,
+                    evidence,
+                },
+                events,
+                stats,
+            );
+        }
+    }
+}
+
+/// Tracks NXDOMAIN answers per client, bursts of them are typical of DGA malware
+pub fn on_nxdomain(
+    analytics: &mut DnsAnalytics,
+    config: &Config,
+    client: Ipv4Addr,
+    qname: &str,
+    events: &mut Vec<Event>,
+    stats: &Arc<Stats>,
+) {
+    let ts = analytics.ts;
+
+    let names = analytics.nxdomains.entry(client).or_default();
+    names.push(qname.trim_end_matches('.').to_lowercase());
+    if names.len() > config.dns_analytics.nxdomain_threshold {
+        let count = names.len();
+        let mut evidence = vec![format!("{} NXDOMAIN in {:?}", count, config.dns_analytics.window)];
+        evidence.extend(names.iter().rev().take(5).cloned());
+        if analytics.should_alert(AlertType::NXDOMAIN, &client.to_string()) {
+            alerts::raise(
+                Alert {
+                    ts,
+                    alert_type: AlertType::NXDOMAIN,
+                    client,
+                    subject: client.to_string(),
+                    score: count as f64,
+                    evidence,
+                },
+                events,
+                stats,
+            );
+        }
+    }
+}
+
+/// Shannon entropy in bits per character
+pub fn entropy(value: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in value.chars() {
+        *counts.entry(c).or_insert(0) += 1;
+    }
+    let len = value.chars().count() as f64;
+    counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Scores a label between 0 and 1 from its entropy, its share of uncommon
+/// letter pairs and its share of digits
+pub fn dga_score(label: &str) -> (f64, Vec<String>) {
+    let entropy = entropy(label);
+    let bigrams: Vec<String> = label
+        .as_bytes()
+        .windows(2)
+        .map(|pair| String::from_utf8_lossy(pair).to_string())
+        .collect();
+    let rare = bigrams.iter().filter(|b| !COMMON_BIGRAMS.contains(b.as_str())).count();
+    let rare_ratio = if bigrams.is_empty() { 0.0 } else { rare as f64 / bigrams.len() as f64 };
+    let digits = label.chars().filter(|c| c.is_ascii_digit()).count();
+    let digit_ratio = digits as f64 / label.len() as f64;
+
+    let score = 0.4 * (entropy / 4.0).min(1.0) + 0.4 * rare_ratio + 0.2 * (digit_ratio * 2.0).min(1.0);
+    let evidence = vec![
+        format!("label {}", label),
+        format!("entropy {:.2}", entropy),
+        format!("uncommon bigrams {:.0}%", rare_ratio * 100.0),
+        format!("digits {:.0}%", digit_ratio * 100.0),
+    ];
+    (score, evidence)
+}
+
+/// Returns the registrable part of a name (`example.co.uk`, `example.com`),
+/// using the short second-level label heuristic instead of a public suffix list
+pub fn registered_domain(name: &str) -> &str {
+    let labels: Vec<&str> = name.split('.').collect();
+    let keep = if labels.len() >= 3 && labels[labels.len() - 1].len() == 2 && labels[labels.len() - 2].len() <= 3 {
+        3
+    } else {
+        2
+    };
+    if labels.len() <= keep {
+        return name;
+    }
+    let skip: usize = labels[..labels.len() - keep].iter().map(|label| label.len() + 1).sum();
+    &name[skip..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roll_window_survives_a_clock_step_back() {
+        let config = Config::default();
+        let window = config.dns_analytics.window.as_millis();
+        let mut analytics = DnsAnalytics::new();
+        analytics.roll_window(&config, 10 * window);
+        analytics.domains.insert("example.com".to_string(), DomainStats::default());
+
+        analytics.roll_window(&config, 5 * window);
+        assert_eq!(analytics.window_start, 10 * window);
+        assert_eq!(analytics.domains.len(), 1);
+
+        analytics.roll_window(&config, 11 * window);
+        assert_eq!(analytics.window_start, 11 * window);
+        assert!(analytics.domains.is_empty());
+    }
+}
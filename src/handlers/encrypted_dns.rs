@@ -0,0 +1,53 @@
+use std::{net::Ipv4Addr, sync::{Arc, atomic::Ordering}};
+
+use crate::{config::Config, stats::Stats, utils::EncryptedDnsType};
+
+const DOT_PORT: u16 = 853;
+const DOQ_PORT: u16 = 853;
+const HTTPS_PORT: u16 = 443;
+
+/// Flags a TCP flow as DoT (port 853) or DoH (known resolver address on 443)
+pub fn detect_tcp(config: &Config, server: (Ipv4Addr, u16), client: (Ipv4Addr, u16)) -> Option<EncryptedDnsType> {
+    if server.1 == DOT_PORT || client.1 == DOT_PORT {
+        return Some(EncryptedDnsType::DOT);
+    }
+    detect_doh_ip(config, server, client)
+}
+
+/// Flags a UDP flow as DoQ (port 853) or DoH over HTTP/3 (known resolver address on 443)
+pub fn detect_udp(config: &Config, src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16)) -> Option<EncryptedDnsType> {
+    if src.1 == DOQ_PORT || dst.1 == DOQ_PORT {
+        return Some(EncryptedDnsType::DOQ);
+    }
+    detect_doh_ip(config, src, dst)
+}
+
+fn detect_doh_ip(config: &Config, a: (Ipv4Addr, u16), b: (Ipv4Addr, u16)) -> Option<EncryptedDnsType> {
+    for (ip, port) in [a, b].iter() {
+        if *port == HTTPS_PORT && config.encrypted_dns.doh_ips.iter().any(|doh| doh == ip) {
+            return Some(EncryptedDnsType::DOH);
+        }
+    }
+    None
+}
+
+/// Flags a flow as DoH when its TLS/QUIC SNI is a known resolver name
+pub fn detect_sni(config: &Config, sni: &str) -> Option<EncryptedDnsType> {
+    let known = config.encrypted_dns.doh_servers.iter().any(|server| {
+        sni == server || sni.ends_with(&format!(".{}", server))
+    });
+    if known {
+        Some(EncryptedDnsType::DOH)
+    } else {
+        None
+    }
+}
+
+/// Counts a flow that has just been flagged
+pub fn count(stats: &Arc<Stats>, dns_type: &EncryptedDnsType) {
+    match dns_type {
+        EncryptedDnsType::DOT => stats.dot.fetch_add(1, Ordering::Relaxed),
+        EncryptedDnsType::DOQ => stats.doq.fetch_add(1, Ordering::Relaxed),
+        EncryptedDnsType::DOH => stats.doh.fetch_add(1, Ordering::Relaxed),
+    };
+}
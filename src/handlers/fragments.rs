@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, atomic::Ordering},
+};
+
+use crate::{
+    config::{Config, OverlapPolicy},
+    stats::Stats,
+};
+
+/// Largest datagram an IP header can describe
+const MAX_DATAGRAM: usize = 65535;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+struct FragmentKey {
+    source: IpAddr,
+    destination: IpAddr,
+    /// 0 for IPv6, where the protocol only comes with the first fragment
+    protocol: u8,
+    id: u32,
+}
+
+#[derive(Debug, Default)]
+struct Datagram {
+    first_ts: u128,
+    data: Vec<u8>,
+    /// Sorted, non adjacent byte ranges received so far
+    ranges: Vec<(usize, usize)>,
+    /// Known once the last fragment arrived
+    len: Option<usize>,
+    /// Protocol following the fragment header, taken from the first IPv6 fragment
+    next_header: Option<u8>,
+}
+
+impl Datagram {
+    fn is_complete(&self) -> bool {
+        match self.len {
+            Some(len) => self.ranges.len() == 1 && self.ranges[0] == (0, len),
+            None => false,
+        }
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.ranges.iter().any(|&(s, e)| start < e && s < end)
+    }
+
+    /// Copies the bytes of `[start, end)` not received yet
+    fn fill_gaps(&mut self, start: usize, payload: &[u8]) {
+        let end = start + payload.len();
+        let mut position = start;
+        for &(s, e) in self.ranges.iter() {
+            if e <= position || s >= end {
+                continue;
+            }
+            if s > position {
+                self.data[position..s].copy_from_slice(&payload[position - start..s - start]);
+            }
+            position = position.max(e);
+        }
+        if position < end {
+            self.data[position..end].copy_from_slice(&payload[position - start..]);
+        }
+    }
+
+    fn add_range(&mut self, start: usize, end: usize) {
+        self.ranges.push((start, end));
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.ranges.len());
+        for &(s, e) in self.ranges.iter() {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+/// One fragment as read from the IPv4 header or the IPv6 fragment header
+#[derive(Debug)]
+pub struct Fragment<'a> {
+    pub ts: u128,
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: u8,
+    pub id: u32,
+    /// In bytes
+    pub offset: usize,
+    pub more: bool,
+    /// IPv6 next header of the first fragment
+    pub next_header: Option<u8>,
+    pub payload: &'a [u8],
+}
+
+/// IP fragment reassembly of one capture thread
+#[derive(Debug)]
+pub struct Fragments {
+    pending: HashMap<FragmentKey, Datagram>,
+    memory: usize,
+    last_expiry: u128,
+    timeout: u128,
+    max_memory: usize,
+    policy: OverlapPolicy,
+}
+
+impl Fragments {
+    pub fn new(config: &Config) -> Fragments {
+        Fragments {
+            pending: HashMap::new(),
+            memory: 0,
+            last_expiry: 0,
+            timeout: config.fragments.timeout.as_micros(),
+            max_memory: config.fragments.max_memory,
+            policy: config.fragments.overlap_policy,
+        }
+    }
+
+    /// Feeds a fragment, returns the reassembled payload (and the IPv6 next header)
+    /// once every fragment of the datagram is in
+    pub fn push(&mut self, fragment: Fragment, stats: &Arc<Stats>) -> Option<(Option<u8>, Vec<u8>)> {
+        stats.fragments.fetch_add(1, Ordering::Relaxed);
+        self.expire(fragment.ts, stats);
+
+        let start = fragment.offset;
+        let end = start + fragment.payload.len();
+        let key = FragmentKey {
+            source: fragment.source,
+            destination: fragment.destination,
+            protocol: fragment.protocol,
+            id: fragment.id,
+        };
+        // Middle fragments must be multiples of 8 bytes
+        if end > MAX_DATAGRAM || (fragment.more && !fragment.payload.len().is_multiple_of(8)) {
+            self.remove(&key);
+            return None;
+        }
+
+        // Only the growth of the datagram counts, and it is never evicted to make room for itself
+        let growth = self.pending.get(&key).map_or(end, |datagram| end.saturating_sub(datagram.data.len()));
+        while self.memory + growth > self.max_memory && self.evict_oldest(&key, stats) {}
+        if self.memory + growth > self.max_memory {
+            if self.remove(&key).is_some() {
+                stats.fragments_expired.fetch_add(1, Ordering::Relaxed);
+            }
+            return None;
+        }
+        let datagram = self.pending.entry(key).or_insert_with(|| Datagram { first_ts: fragment.ts, ..Default::default() });
+
+        let overlapping = datagram.overlaps(start, end);
+        if overlapping {
+            stats.fragments_overlapping.fetch_add(1, Ordering::Relaxed);
+        }
+        if !fragment.more {
+            // Two different ends can't both be true
+            if datagram.len.is_some_and(|len| len != end) || datagram.ranges.last().is_some_and(|&(_, e)| e > end) {
+                self.remove(&key);
+                return None;
+            }
+            datagram.len = Some(end);
+        }
+        if overlapping && self.policy == OverlapPolicy::DROP {
+            self.remove(&key);
+            return None;
+        }
+        if start == 0 && fragment.next_header.is_some() {
+            datagram.next_header = fragment.next_header;
+        }
+
+        if datagram.data.len() < end {
+            self.memory += end - datagram.data.len();
+            datagram.data.resize(end, 0);
+        }
+        if overlapping && self.policy == OverlapPolicy::FIRST {
+            datagram.fill_gaps(start, fragment.payload);
+        } else {
+            datagram.data[start..end].copy_from_slice(fragment.payload);
+        }
+        datagram.add_range(start, end);
+
+        if !datagram.is_complete() {
+            return None;
+        }
+        let datagram = self.remove(&key)?;
+        stats.reassembled.fetch_add(1, Ordering::Relaxed);
+        Some((datagram.next_header, datagram.data))
+    }
+
+    fn remove(&mut self, key: &FragmentKey) -> Option<Datagram> {
+        let datagram = self.pending.remove(key)?;
+        self.memory -= datagram.data.len();
+        Some(datagram)
+    }
+
+    fn evict_oldest(&mut self, keep: &FragmentKey, stats: &Arc<Stats>) -> bool {
+        let oldest = match self.pending.iter().filter(|(key, _)| *key != keep).min_by_key(|(_, datagram)| datagram.first_ts) {
+            Some((key, _)) => *key,
+            None => return false,
+        };
+        self.remove(&oldest);
+        stats.fragments_expired.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Drops the datagrams still incomplete after `fragments.timeout`, at most once per second of capture time
+    fn expire(&mut self, now: u128, stats: &Arc<Stats>) {
+        if now < self.last_expiry + 1_000_000 {
+            return;
+        }
+        self.last_expiry = now;
+        let timeout = self.timeout;
+        let expired: Vec<FragmentKey> = self
+            .pending
+            .iter()
+            .filter(|(_, datagram)| now.saturating_sub(datagram.first_ts) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            self.remove(&key);
+            stats.fragments_expired.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn fragments(policy: OverlapPolicy, max_memory: usize) -> Fragments {
+        let mut config = Config::default();
+        config.fragments.overlap_policy = policy;
+        config.fragments.max_memory = max_memory;
+        Fragments::new(&config)
+    }
+
+    fn fragment(ts: u128, id: u32, offset: usize, more: bool, payload: &[u8]) -> Fragment<'_> {
+        Fragment {
+            ts,
+            source: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            destination: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            protocol: 17,
+            id,
+            offset,
+            more,
+            next_header: None,
+            payload,
+        }
+    }
+
+    #[test]
+    fn reassembles_in_order() {
+        let stats = Stats::new(&[]);
+        let mut fragments = fragments(OverlapPolicy::FIRST, 1 << 20);
+        assert!(fragments.push(fragment(0, 1, 0, true, &[1; 8]), &stats).is_none());
+        let (_, data) = fragments.push(fragment(0, 1, 8, false, &[2; 4]), &stats).unwrap();
+        assert_eq!(data, [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(fragments.memory, 0);
+        assert_eq!(stats.reassembled.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let stats = Stats::new(&[]);
+        let mut fragments = fragments(OverlapPolicy::FIRST, 1 << 20);
+        assert!(fragments.push(fragment(0, 1, 16, false, &[3; 2]), &stats).is_none());
+        assert!(fragments.push(fragment(0, 1, 0, true, &[1; 8]), &stats).is_none());
+        let (_, data) = fragments.push(fragment(0, 1, 8, true, &[2; 8]), &stats).unwrap();
+        assert_eq!(data.len(), 18);
+        assert_eq!(&data[..8], &[1; 8]);
+        assert_eq!(&data[8..16], &[2; 8]);
+        assert_eq!(&data[16..], &[3; 2]);
+    }
+
+    /// 0..16 of 'a', then 8..24 of 'b' as the last fragment
+    fn overlapping(policy: OverlapPolicy) -> (Option<Vec<u8>>, Fragments) {
+        let stats = Stats::new(&[]);
+        let mut fragments = fragments(policy, 1 << 20);
+        assert!(fragments.push(fragment(0, 1, 0, true, &[b'a'; 16]), &stats).is_none());
+        let data = fragments.push(fragment(0, 1, 8, false, &[b'b'; 16]), &stats).map(|(_, data)| data);
+        assert_eq!(stats.fragments_overlapping.load(Ordering::Relaxed), 1);
+        (data, fragments)
+    }
+
+    #[test]
+    fn overlap_keeps_first() {
+        let (data, _) = overlapping(OverlapPolicy::FIRST);
+        assert_eq!(data.unwrap(), [[b'a'; 16].as_slice(), &[b'b'; 8]].concat());
+    }
+
+    #[test]
+    fn overlap_keeps_last() {
+        let (data, _) = overlapping(OverlapPolicy::LAST);
+        assert_eq!(data.unwrap(), [[b'a'; 8].as_slice(), &[b'b'; 16]].concat());
+    }
+
+    #[test]
+    fn overlap_drops_datagram() {
+        let (data, fragments) = overlapping(OverlapPolicy::DROP);
+        assert!(data.is_none());
+        assert!(fragments.pending.is_empty());
+        assert_eq!(fragments.memory, 0);
+    }
+
+    #[test]
+    fn expires_incomplete_datagrams() {
+        let stats = Stats::new(&[]);
+        let mut fragments = fragments(OverlapPolicy::FIRST, 1 << 20);
+        assert!(fragments.push(fragment(0, 1, 0, true, &[1; 8]), &stats).is_none());
+        // The default timeout is 30s, timestamps are microseconds
+        let later = 31_000_000;
+        assert!(fragments.push(fragment(later, 2, 0, true, &[1; 8]), &stats).is_none());
+        assert_eq!(stats.fragments_expired.load(Ordering::Relaxed), 1);
+        assert!(fragments.push(fragment(later, 1, 8, false, &[2; 4]), &stats).is_none());
+        assert_eq!(fragments.pending.len(), 2);
+    }
+
+    #[test]
+    fn growth_is_capped_by_max_memory() {
+        let stats = Stats::new(&[]);
+        let mut fragments = fragments(OverlapPolicy::FIRST, 2048);
+        for id in 1..=3 {
+            assert!(fragments.push(fragment(id as u128, id, 0, true, &[0; 8]), &stats).is_none());
+        }
+        assert!(fragments.push(fragment(4, 3, 1016, true, &[0; 8]), &stats).is_none());
+        assert_eq!(fragments.memory, 8 + 8 + 1024);
+
+        // Growing datagram 2 to 1600 bytes evicts 1 then 3, never itself
+        assert!(fragments.push(fragment(5, 2, 1592, true, &[0; 8]), &stats).is_none());
+        assert!(fragments.memory <= 2048);
+        assert_eq!(fragments.pending.len(), 1);
+        assert_eq!(fragments.pending.values().next().unwrap().data.len(), 1600);
+        assert_eq!(stats.fragments_expired.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn drops_a_datagram_larger_than_max_memory() {
+        let stats = Stats::new(&[]);
+        let mut fragments = fragments(OverlapPolicy::FIRST, 64);
+        assert!(fragments.push(fragment(0, 1, 0, true, &[0; 8]), &stats).is_none());
+        assert!(fragments.push(fragment(0, 2, 0, true, &[0; 8]), &stats).is_none());
+        assert!(fragments.push(fragment(0, 1, 60000, true, &[0; 8]), &stats).is_none());
+        assert!(fragments.pending.is_empty());
+        assert_eq!(fragments.memory, 0);
+    }
+}
@@ -0,0 +1,614 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    config::Config,
+    dissector::{Dissector, Flow, FlowDissector},
+    events::{AppEvent, Event},
+    extract,
+    handlers::{
+        dns,
+        http2::{self, Http2State},
+    },
+    reload::LiveConfig,
+    stats::Stats,
+    utils::AppType,
+};
+
+/// A stream is no longer parsed if its headers don't fit in this many bytes
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+const METHODS: [&str; 9] = ["GET ", "POST ", "HEAD ", "PUT ", "DELETE ", "OPTIONS ", "PATCH ", "CONNECT ", "TRACE "];
+
+#[derive(Debug, Clone, Default)]
+pub struct HttpRequest {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub host: Option<String>,
+    pub user_agent: Option<String>,
+    pub content_type: Option<String>,
+    pub upgrade: Option<String>,
+    pub body_len: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HttpResponse {
+    pub version: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub body_len: usize,
+    /// De-chunked body, only kept when the response is being carved
+    pub body: Option<Vec<u8>>,
+}
+
+/// A request and its response, a missing response means the connection closed first
+#[derive(Debug, Clone)]
+pub struct HttpTransaction {
+    pub request: HttpRequest,
+    pub response: Option<HttpResponse>,
+}
+
+#[derive(Debug, PartialEq)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+}
+
+#[derive(Debug, PartialEq)]
+enum BodyState {
+    Headers,
+    Length(usize),
+    Chunked(ChunkState),
+    UntilClose,
+}
+
+/// HTTP/1.x parser state of a TCP connection, fed with the reassembled streams
+#[derive(Debug)]
+pub struct HttpState {
+    client_buf: Vec<u8>,
+    client_state: BodyState,
+    server_buf: Vec<u8>,
+    server_state: BodyState,
+    /// Requests waiting for their response, in pipelining order
+    pending: VecDeque<HttpRequest>,
+    response: Option<(HttpRequest, HttpResponse)>,
+    /// Request that switched the connection to HTTP/2 (h2c upgrade)
+    upgrade: Option<HttpRequest>,
+    /// Content-type prefixes and size limit of the response bodies to keep
+    capture: Option<(Vec<String>, usize)>,
+    broken: bool,
+}
+
+/// Returns true if a client payload starts with an HTTP/1.x request line
+pub fn is_request(payload: &[u8]) -> bool {
+    METHODS.iter().any(|method| payload.starts_with(method.as_bytes()))
+}
+
+impl HttpState {
+    pub fn new() -> HttpState {
+        HttpState {
+            client_buf: Vec::new(),
+            client_state: BodyState::Headers,
+            server_buf: Vec::new(),
+            server_state: BodyState::Headers,
+            pending: VecDeque::new(),
+            response: None,
+            upgrade: None,
+            capture: None,
+            broken: false,
+        }
+    }
+
+    /// Parser that also keeps the bodies of the responses whose content type
+    /// starts with one of `content_types`, up to `max_size` bytes
+    pub fn with_capture(content_types: Vec<String>, max_size: usize) -> HttpState {
+        let mut state = HttpState::new();
+        state.capture = Some((content_types, max_size));
+        state
+    }
+
+    /// Feeds client to server bytes, returns the requests whose headers were completed
+    pub fn on_client_data(&mut self, data: &[u8]) -> Vec<HttpRequest> {
+        let mut requests = Vec::new();
+        if self.broken {
+            return requests;
+        }
+        self.client_buf.extend_from_slice(data);
+        loop {
+            // After an h2c upgrade request the client goes on with the HTTP/2 preface
+            if self.client_state == BodyState::Headers && self.client_buf.starts_with(b"PRI * HTTP/2.0") {
+                break;
+            }
+            if self.client_state == BodyState::Headers {
+                let head = match take_headers(&mut self.client_buf) {
+                    Ok(Some(head)) => head,
+                    Ok(None) => break,
+                    Err(_) => {
+                        self.broken = true;
+                        break;
+                    }
+                };
+                let request = match parse_request(&head) {
+                    Some((request, state)) => {
+                        self.client_state = state;
+                        request
+                    }
+                    None => {
+                        self.broken = true;
+                        break;
+                    }
+                };
+                requests.push(request.clone());
+                self.pending.push_back(request);
+            }
+            let pending = &mut self.pending;
+            match consume_body(&mut self.client_buf, &mut self.client_state, &mut |chunk| {
+                if let Some(request) = pending.back_mut() {
+                    request.body_len += chunk.len();
+                }
+            }) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(()) => {
+                    self.broken = true;
+                    break;
+                }
+            }
+            self.client_state = BodyState::Headers;
+        }
+        requests
+    }
+
+    /// Feeds server to client bytes, returns the transactions whose response is complete
+    pub fn on_server_data(&mut self, data: &[u8]) -> Vec<HttpTransaction> {
+        let mut transactions = Vec::new();
+        if self.broken || self.upgrade.is_some() {
+            return transactions;
+        }
+        self.server_buf.extend_from_slice(data);
+        loop {
+            if self.server_state == BodyState::Headers {
+                let head = match take_headers(&mut self.server_buf) {
+                    Ok(Some(head)) => head,
+                    Ok(None) => break,
+                    Err(_) => {
+                        self.broken = true;
+                        break;
+                    }
+                };
+                let request = self.pending.front().cloned().unwrap_or_default();
+                let (response, state) = match parse_response(&head, &request.method) {
+                    Some(parsed) => parsed,
+                    None => {
+                        self.broken = true;
+                        break;
+                    }
+                };
+                let h2c = request.upgrade.as_ref().is_some_and(|upgrade| upgrade.to_lowercase().contains("h2c"));
+                if response.status == 101 && h2c {
+                    // The rest of both streams is HTTP/2, see take_upgrade
+                    self.pending.pop_front();
+                    transactions.push(HttpTransaction { request: request.clone(), response: Some(response) });
+                    self.upgrade = Some(request);
+                    break;
+                }
+                // Interim responses don't answer the request
+                if response.status >= 100 && response.status < 200 {
+                    continue;
+                }
+                self.pending.pop_front();
+                self.server_state = state;
+                let mut response = response;
+                if let Some((content_types, _)) = &self.capture {
+                    let content_type = response.content_type.clone().unwrap_or_default().to_lowercase();
+                    if content_types.iter().any(|allowed| content_type.starts_with(&allowed.to_lowercase())) {
+                        response.body = Some(Vec::new());
+                    }
+                }
+                self.response = Some((request, response));
+            }
+            let current = &mut self.response;
+            let max_size = self.capture.as_ref().map_or(0, |(_, max_size)| *max_size);
+            let done = consume_body(&mut self.server_buf, &mut self.server_state, &mut |chunk| {
+                if let Some((_, response)) = current {
+                    response.body_len += chunk.len();
+                    // Bodies over the limit are not carved at all
+                    if response.body_len > max_size {
+                        response.body = None;
+                    }
+                    if let Some(body) = &mut response.body {
+                        body.extend_from_slice(chunk);
+                    }
+                }
+            });
+            match done {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(()) => {
+                    self.broken = true;
+                    break;
+                }
+            }
+            self.server_state = BodyState::Headers;
+            if let Some((request, response)) = self.response.take() {
+                transactions.push(HttpTransaction { request, response: Some(response) });
+            }
+        }
+        transactions
+    }
+
+    /// Returns the upgrade request and the unparsed client and server bytes once
+    /// the server accepted an h2c upgrade
+    pub fn take_upgrade(&mut self) -> Option<(HttpRequest, Vec<u8>, Vec<u8>)> {
+        let request = self.upgrade.take()?;
+        self.broken = true;
+        Some((request, std::mem::take(&mut self.client_buf), std::mem::take(&mut self.server_buf)))
+    }
+
+    /// Ends the connection: a close-delimited response is complete and unanswered
+    /// requests are reported without a response
+    pub fn on_close(&mut self) -> Vec<HttpTransaction> {
+        let mut transactions = Vec::new();
+        if let Some((request, mut response)) = self.response.take() {
+            // Only a close-delimited body is complete when the connection ends
+            if self.server_state != BodyState::UntilClose {
+                response.body = None;
+            }
+            transactions.push(HttpTransaction { request, response: Some(response) });
+        }
+        for request in self.pending.drain(..) {
+            transactions.push(HttpTransaction { request, response: None });
+        }
+        transactions
+    }
+}
+
+/// Removes a complete header block from the buffer, Ok(None) if it isn't complete yet
+fn take_headers(buf: &mut Vec<u8>) -> Result<Option<String>, ()> {
+    match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => {
+            let head = String::from_utf8_lossy(&buf[..end]).to_string();
+            buf.drain(..end + 4);
+            Ok(Some(head))
+        }
+        None if buf.len() > MAX_HEADER_LEN => Err(()),
+        None => Ok(None),
+    }
+}
+
+fn header_lines(head: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    head.split("\r\n").skip(1).filter_map(|line| {
+        let colon = line.find(':')?;
+        Some((line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_string()))
+    })
+}
+
+/// Body framing from the headers (RFC 7230 section 3.3.3)
+fn body_state(head: &str, close_delimited: bool) -> BodyState {
+    let mut state = if close_delimited { BodyState::UntilClose } else { BodyState::Length(0) };
+    for (name, value) in header_lines(head) {
+        if name == "transfer-encoding" && value.to_lowercase().contains("chunked") {
+            return BodyState::Chunked(ChunkState::Size);
+        }
+        if name == "content-length" {
+            if let Ok(len) = value.parse::<usize>() {
+                state = BodyState::Length(len);
+            }
+        }
+    }
+    state
+}
+
+fn parse_request(head: &str) -> Option<(HttpRequest, BodyState)> {
+    let mut parts = head.lines().next()?.split(' ');
+    let mut request = HttpRequest {
+        method: parts.next()?.to_string(),
+        uri: parts.next()?.to_string(),
+        version: parts.next()?.to_string(),
+        ..Default::default()
+    };
+    if !request.version.starts_with("HTTP/1.") {
+        return None;
+    }
+    for (name, value) in header_lines(head) {
+        match name.as_str() {
+            "host" => request.host = Some(value.to_lowercase()),
+            "user-agent" => request.user_agent = Some(value),
+            "content-type" => request.content_type = Some(value),
+            "upgrade" => request.upgrade = Some(value),
+            _ => (),
+        }
+    }
+    // Requests without a length have no body
+    Some((request, body_state(head, false)))
+}
+
+fn parse_response(head: &str, method: &str) -> Option<(HttpResponse, BodyState)> {
+    let mut parts = head.lines().next()?.splitn(3, ' ');
+    let mut response = HttpResponse { version: parts.next()?.to_string(), status: parts.next()?.parse().ok()?, ..Default::default() };
+    if !response.version.starts_with("HTTP/1.") {
+        return None;
+    }
+    for (name, value) in header_lines(head) {
+        match name.as_str() {
+            "content-type" => response.content_type = Some(value),
+            "content-encoding" => response.content_encoding = Some(value.to_lowercase()),
+            _ => (),
+        }
+    }
+    let no_body =
+        method == "HEAD" || response.status == 204 || response.status == 304 || (method == "CONNECT" && response.status / 100 == 2);
+    let state = if no_body { BodyState::Length(0) } else { body_state(head, true) };
+    Some((response, state))
+}
+
+/// Consumes body bytes from the buffer, handing the de-chunked content to `sink`.
+/// Returns true once the body is complete, Err if the chunked framing is broken
+fn consume_body(buf: &mut Vec<u8>, state: &mut BodyState, sink: &mut dyn FnMut(&[u8])) -> Result<bool, ()> {
+    loop {
+        match state {
+            BodyState::Headers => return Ok(true),
+            BodyState::Length(remaining) => {
+                let len = (*remaining).min(buf.len());
+                sink(&buf[..len]);
+                buf.drain(..len);
+                *remaining -= len;
+                return Ok(*remaining == 0);
+            }
+            BodyState::UntilClose => {
+                sink(&buf[..]);
+                buf.clear();
+                return Ok(false);
+            }
+            BodyState::Chunked(chunk) => match chunk {
+                ChunkState::Size => {
+                    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+                        Some(end) => end,
+                        None if buf.len() > MAX_HEADER_LEN => return Err(()),
+                        None => return Ok(false),
+                    };
+                    let line = String::from_utf8_lossy(&buf[..end]).to_string();
+                    buf.drain(..end + 2);
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size = usize::from_str_radix(size, 16).map_err(|_| ())?;
+                    *chunk = if size == 0 { ChunkState::Trailers } else { ChunkState::Data(size) };
+                }
+                ChunkState::Data(remaining) => {
+                    let len = (*remaining).min(buf.len());
+                    sink(&buf[..len]);
+                    buf.drain(..len);
+                    *remaining -= len;
+                    if *remaining > 0 {
+                        return Ok(false);
+                    }
+                    *chunk = ChunkState::DataEnd;
+                }
+                ChunkState::DataEnd => {
+                    if buf.len() < 2 {
+                        return Ok(false);
+                    }
+                    // The chunk size was wrong, the rest can't be framed
+                    if buf[..2] != *b"\r\n" {
+                        return Err(());
+                    }
+                    buf.drain(..2);
+                    *chunk = ChunkState::Size;
+                }
+                ChunkState::Trailers => {
+                    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+                        Some(end) => end,
+                        None if buf.len() > MAX_HEADER_LEN => return Err(()),
+                        None => return Ok(false),
+                    };
+                    buf.drain(..end + 2);
+                    // An empty line ends the trailers
+                    if end == 0 {
+                        return Ok(true);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Follows the flows whose client starts with an HTTP/1.x request line or the HTTP/2 preface
+pub struct HttpDissector {
+    pub config: Arc<LiveConfig>,
+    pub stats: Arc<Stats>,
+}
+
+struct HttpFlow {
+    /// Snapshot taken when the flow started, a reload doesn't change it
+    config: Arc<Config>,
+    stats: Arc<Stats>,
+    http: Option<HttpState>,
+    http2: Option<Http2State>,
+}
+
+impl Dissector for HttpDissector {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn probe(&self, _flow: &Flow, from_client: bool, payload: &[u8]) -> bool {
+        from_client && (http2::is_preface(payload) || is_request(payload))
+    }
+
+    fn start(&self) -> Box<dyn FlowDissector> {
+        Box::new(HttpFlow { config: self.config.get(), stats: self.stats.clone(), http: None, http2: None })
+    }
+}
+
+impl FlowDissector for HttpFlow {
+    fn parse(&mut self, flow: &mut Flow, from_client: bool, data: &[u8], events: &mut Vec<Event>) {
+        let (client, server) = (flow.client, flow.server);
+        if self.http.is_none() && self.http2.is_none() {
+            if http2::is_preface(data) {
+                self.http2 = Some(Http2State::new());
+            } else {
+                self.http = Some(if self.config.extract.enabled {
+                    HttpState::with_capture(self.config.extract.content_types.clone(), self.config.extract.max_size)
+                } else {
+                    HttpState::new()
+                });
+            }
+        }
+        if let Some(http2_state) = &mut self.http2 {
+            let streams = if from_client { http2_state.on_client_data(data) } else { http2_state.on_server_data(data) };
+            for stream in streams {
+                events.push(Event::App(AppEvent::http2(flow.ts, client, server, &stream)));
+            }
+            return;
+        }
+        let http_state = match &mut self.http {
+            Some(http_state) => http_state,
+            None => return,
+        };
+        if from_client {
+            for request in http_state.on_client_data(data) {
+                if flow.app_type == AppType::NONE {
+                    if let Some(app_type) = request.host.as_ref().and_then(|host| dns::dns_to_app(&self.config, host)) {
+                        flow.app_type = app_type;
+                    }
+                }
+            }
+            return;
+        }
+        for transaction in http_state.on_server_data(data) {
+            events.push(Event::App(AppEvent::http(flow.ts, client, server, &transaction)));
+            if self.config.extract.enabled {
+                extract::write(&self.config, flow.ts, client, server, &transaction, &self.stats);
+            }
+        }
+        // h2c upgrade, the rest of the connection is HTTP/2
+        if let Some((request, client_data, server_data)) = http_state.take_upgrade() {
+            let mut http2_state = Http2State::upgraded(&request);
+            for stream in http2_state.on_client_data(&client_data).into_iter().chain(http2_state.on_server_data(&server_data)) {
+                events.push(Event::App(AppEvent::http2(flow.ts, client, server, &stream)));
+            }
+            self.http = None;
+            self.http2 = Some(http2_state);
+        }
+    }
+
+    /// Logs what the parsers still hold
+    fn close(&mut self, flow: &mut Flow, events: &mut Vec<Event>) {
+        let (client, server) = (flow.client, flow.server);
+        if let Some(http_state) = &mut self.http {
+            for transaction in http_state.on_close() {
+                events.push(Event::App(AppEvent::http(flow.ts, client, server, &transaction)));
+                if self.config.extract.enabled {
+                    extract::write(&self.config, flow.ts, client, server, &transaction, &self.stats);
+                }
+            }
+        }
+        if let Some(http2_state) = &mut self.http2 {
+            for stream in http2_state.on_close() {
+                events.push(Event::App(AppEvent::http2(flow.ts, client, server, &stream)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: Example.com\r\nUser-Agent: curl/8.0\r\n\r\n";
+
+    /// Feeds `data` one byte at a time, as the worst segmentation would
+    fn server_bytes(state: &mut HttpState, data: &[u8]) -> Vec<HttpTransaction> {
+        data.iter().flat_map(|byte| state.on_server_data(&[*byte])).collect()
+    }
+
+    #[test]
+    fn parses_a_chunked_response() {
+        let mut state = HttpState::with_capture(vec!["application/octet-stream".to_string()], 1024);
+        let requests = state.on_client_data(REQUEST);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].host.as_deref(), Some("example.com"));
+
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6;name=value\r\n world\r\n0\r\nX-Checksum: 1\r\n\r\n";
+        let transactions = server_bytes(&mut state, response);
+        assert_eq!(transactions.len(), 1);
+        let response = transactions[0].response.as_ref().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body_len, 11);
+        assert_eq!(response.body.as_deref(), Some(&b"hello world"[..]));
+    }
+
+    #[test]
+    fn stops_at_a_chunk_without_its_crlf() {
+        let mut state = HttpState::new();
+        state.on_client_data(REQUEST);
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhelloXX\r\n0\r\n\r\n";
+        assert!(state.on_server_data(response).is_empty());
+        assert!(state.on_server_data(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").is_empty());
+    }
+
+    #[test]
+    fn stops_at_a_bad_chunk_size() {
+        let mut state = HttpState::new();
+        state.on_client_data(REQUEST);
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n";
+        assert!(state.on_server_data(response).is_empty());
+    }
+
+    #[test]
+    fn matches_pipelined_requests_in_order() {
+        let mut state = HttpState::new();
+        let requests = state.on_client_data(
+            b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nbodyGET /b HTTP/1.1\r\nHost: a\r\n\r\nHEAD /c HTTP/1.1\r\nHost: a\r\n\r\n",
+        );
+        assert_eq!(requests.iter().map(|request| request.uri.as_str()).collect::<Vec<_>>(), ["/a", "/b", "/c"]);
+
+        let transactions = state.on_server_data(
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok\
+              HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found\
+              HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n",
+        );
+        let answered: Vec<(&str, usize, u16, usize)> = transactions
+            .iter()
+            .map(|transaction| {
+                let response = transaction.response.as_ref().unwrap();
+                (transaction.request.uri.as_str(), transaction.request.body_len, response.status, response.body_len)
+            })
+            .collect();
+        // HEAD responses have no body whatever their Content-Length says
+        assert_eq!(answered, [("/a", 4, 201, 2), ("/b", 0, 404, 9), ("/c", 0, 200, 0)]);
+    }
+
+    #[test]
+    fn ends_a_close_delimited_response_with_the_connection() {
+        let mut state = HttpState::with_capture(vec!["text/".to_string()], 1024);
+        state.on_client_data(REQUEST);
+        let response = b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\nuntil the end";
+        assert!(server_bytes(&mut state, response).is_empty());
+
+        let transactions = state.on_close();
+        assert_eq!(transactions.len(), 1);
+        let response = transactions[0].response.as_ref().unwrap();
+        assert_eq!(response.body_len, 13);
+        assert_eq!(response.body.as_deref(), Some(&b"until the end"[..]));
+    }
+
+    #[test]
+    fn reports_unanswered_requests_on_close() {
+        let mut state = HttpState::new();
+        state.on_client_data(REQUEST);
+        state.on_client_data(b"GET /next HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        // A cut Content-Length body isn't kept
+        state.on_server_data(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc");
+
+        let transactions = state.on_close();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].response.as_ref().unwrap().body_len, 3);
+        assert_eq!(transactions[1].request.uri, "/next");
+        assert!(transactions[1].response.is_none());
+    }
+}
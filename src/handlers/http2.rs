@@ -0,0 +1,491 @@
+use std::{collections::BTreeMap, fmt, panic::{self, AssertUnwindSafe}};
+
+use hpack::Decoder;
+
+use crate::handlers::http::HttpRequest;
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+/// Frames larger than the maximum SETTINGS_MAX_FRAME_SIZE mean we lost track of the stream
+const MAX_FRAME_LEN: usize = 16_777_215;
+/// Completed streams kept on the context, the oldest are dropped first
+const MAX_COMPLETED: usize = 1024;
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PUSH_PROMISE: u8 = 0x5;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+/// A request/response exchange on one HTTP/2 stream
+#[derive(Debug, Clone, Default)]
+pub struct Http2Stream {
+    pub method: Option<String>,
+    pub authority: Option<String>,
+    pub path: Option<String>,
+    pub status: Option<u16>,
+    pub content_type: Option<String>,
+    pub grpc_status: Option<String>,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+    pub reset: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    Client,
+    Server,
+}
+
+/// HTTP/2 parser state of a TCP connection, fed with the reassembled streams
+pub struct Http2State {
+    client_buf: Vec<u8>,
+    server_buf: Vec<u8>,
+    preface_seen: bool,
+    client_decoder: Decoder<'static>,
+    server_decoder: Decoder<'static>,
+    /// Header block waiting for its CONTINUATION frames, per direction
+    client_block: Option<(u32, u8, Vec<u8>)>,
+    server_block: Option<(u32, u8, Vec<u8>)>,
+    pub active: BTreeMap<u32, Http2Stream>,
+    pub completed: Vec<Http2Stream>,
+    pub client_settings: Vec<(u16, u32)>,
+    pub server_settings: Vec<(u16, u32)>,
+    /// Last stream id and error code of a GOAWAY
+    pub goaway: Option<(u32, u32)>,
+    broken: bool,
+}
+
+impl fmt::Debug for Http2State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Http2State")
+            .field("active", &self.active)
+            .field("completed", &self.completed.len())
+            .field("goaway", &self.goaway)
+            .finish()
+    }
+}
+
+/// Returns true if a client stream starts with the HTTP/2 connection preface
+pub fn is_preface(data: &[u8]) -> bool {
+    data.starts_with(PREFACE)
+}
+
+impl Http2State {
+    pub fn new() -> Http2State {
+        Http2State {
+            client_buf: Vec::new(),
+            server_buf: Vec::new(),
+            preface_seen: false,
+            client_decoder: Decoder::new(),
+            server_decoder: Decoder::new(),
+            client_block: None,
+            server_block: None,
+            active: BTreeMap::new(),
+            completed: Vec::new(),
+            client_settings: Vec::new(),
+            server_settings: Vec::new(),
+            goaway: None,
+            broken: false,
+        }
+    }
+
+    /// State of a connection upgraded from HTTP/1.1, the upgrade request is stream 1
+    pub fn upgraded(request: &HttpRequest) -> Http2State {
+        let mut state = Http2State::new();
+        state.active.insert(
+            1,
+            Http2Stream {
+                method: Some(request.method.clone()),
+                authority: request.host.clone(),
+                path: Some(request.uri.clone()),
+                request_bytes: request.body_len,
+                ..Default::default()
+            },
+        );
+        state
+    }
+
+    /// Feeds client to server bytes, returns the streams that completed
+    pub fn on_client_data(&mut self, data: &[u8]) -> Vec<Http2Stream> {
+        if self.broken {
+            return Vec::new();
+        }
+        self.client_buf.extend_from_slice(data);
+        if !self.preface_seen {
+            if self.client_buf.len() < PREFACE.len() {
+                return Vec::new();
+            }
+            if !is_preface(&self.client_buf) {
+                self.broken = true;
+                return Vec::new();
+            }
+            self.client_buf.drain(..PREFACE.len());
+            self.preface_seen = true;
+        }
+        self.parse_frames(Direction::Client)
+    }
+
+    /// Feeds server to client bytes, returns the streams that completed
+    pub fn on_server_data(&mut self, data: &[u8]) -> Vec<Http2Stream> {
+        if self.broken {
+            return Vec::new();
+        }
+        self.server_buf.extend_from_slice(data);
+        self.parse_frames(Direction::Server)
+    }
+
+    /// Ends the connection, returning the streams that were still open
+    pub fn on_close(&mut self) -> Vec<Http2Stream> {
+        let streams: Vec<Http2Stream> = std::mem::take(&mut self.active).into_values().collect();
+        for stream in &streams {
+            self.complete(stream.clone());
+        }
+        streams
+    }
+
+    fn complete(&mut self, stream: Http2Stream) {
+        if self.completed.len() >= MAX_COMPLETED {
+            self.completed.remove(0);
+        }
+        self.completed.push(stream);
+    }
+
+    fn finish(&mut self, id: u32, done: &mut Vec<Http2Stream>) {
+        if let Some(stream) = self.active.remove(&id) {
+            self.complete(stream.clone());
+            done.push(stream);
+        }
+    }
+
+    fn parse_frames(&mut self, direction: Direction) -> Vec<Http2Stream> {
+        let mut done = Vec::new();
+        loop {
+            let buf = match direction {
+                Direction::Client => &mut self.client_buf,
+                Direction::Server => &mut self.server_buf,
+            };
+            if buf.len() < FRAME_HEADER_LEN {
+                break;
+            }
+            let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+            if len > MAX_FRAME_LEN {
+                self.broken = true;
+                break;
+            }
+            if buf.len() < FRAME_HEADER_LEN + len {
+                break;
+            }
+            let frame_type = buf[3];
+            let flags = buf[4];
+            let stream_id = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & 0x7FFF_FFFF;
+            let payload: Vec<u8> = buf.drain(..FRAME_HEADER_LEN + len).skip(FRAME_HEADER_LEN).collect();
+            self.handle_frame(direction, frame_type, flags, stream_id, &payload, &mut done);
+            if self.broken {
+                break;
+            }
+        }
+        done
+    }
+
+    fn handle_frame(
+        &mut self,
+        direction: Direction,
+        frame_type: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+        done: &mut Vec<Http2Stream>,
+    ) {
+        match frame_type {
+            FRAME_DATA => {
+                let data_len = match unpad(payload, flags) {
+                    Some(data) => data.len(),
+                    None => return,
+                };
+                let stream = self.stream(stream_id);
+                match direction {
+                    Direction::Client => stream.request_bytes += data_len,
+                    Direction::Server => stream.response_bytes += data_len,
+                }
+                if direction == Direction::Server && flags & FLAG_END_STREAM != 0 {
+                    self.finish(stream_id, done);
+                }
+            }
+            FRAME_HEADERS => {
+                let mut fragment = match unpad(payload, flags) {
+                    Some(fragment) => fragment,
+                    None => return,
+                };
+                if flags & FLAG_PRIORITY != 0 {
+                    fragment = fragment.get(5..).unwrap_or(&[]);
+                }
+                self.header_fragment(direction, stream_id, flags, fragment, done);
+            }
+            FRAME_PUSH_PROMISE => {
+                // The promised request is decoded into the promised stream
+                let fragment = match unpad(payload, flags) {
+                    Some(fragment) if fragment.len() >= 4 => fragment,
+                    _ => return,
+                };
+                let promised = u32::from_be_bytes([fragment[0], fragment[1], fragment[2], fragment[3]]) & 0x7FFF_FFFF;
+                // Decoded with the server's context but describing a request
+                self.header_fragment(direction, promised, flags & !FLAG_END_STREAM, &fragment[4..], done);
+            }
+            FRAME_CONTINUATION => {
+                let block = match direction {
+                    Direction::Client => &mut self.client_block,
+                    Direction::Server => &mut self.server_block,
+                };
+                let complete = match block {
+                    Some((id, _, buf)) if *id == stream_id => {
+                        buf.extend_from_slice(payload);
+                        flags & FLAG_END_HEADERS != 0
+                    }
+                    _ => return,
+                };
+                if complete {
+                    let (id, block_flags, buf) = block.take().unwrap();
+                    self.decode_block(direction, id, block_flags, &buf, done);
+                }
+            }
+            FRAME_RST_STREAM if payload.len() >= 4 => {
+                self.stream(stream_id).reset = Some(u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]));
+                self.finish(stream_id, done);
+            }
+            FRAME_SETTINGS => {
+                if flags & FLAG_ACK != 0 {
+                    return;
+                }
+                let settings: Vec<(u16, u32)> = payload
+                    .chunks_exact(6)
+                    .map(|s| (u16::from_be_bytes([s[0], s[1]]), u32::from_be_bytes([s[2], s[3], s[4], s[5]])))
+                    .collect();
+                match direction {
+                    Direction::Client => self.client_settings = settings,
+                    Direction::Server => self.server_settings = settings,
+                }
+            }
+            FRAME_GOAWAY if payload.len() >= 8 => {
+                let last_stream = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7FFF_FFFF;
+                let error_code = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+                self.goaway = Some((last_stream, error_code));
+            }
+            _ => (),
+        }
+    }
+
+    fn header_fragment(&mut self, direction: Direction, stream_id: u32, flags: u8, fragment: &[u8], done: &mut Vec<Http2Stream>) {
+        if flags & FLAG_END_HEADERS != 0 {
+            self.decode_block(direction, stream_id, flags, fragment, done);
+        } else {
+            let block = Some((stream_id, flags, fragment.to_vec()));
+            match direction {
+                Direction::Client => self.client_block = block,
+                Direction::Server => self.server_block = block,
+            }
+        }
+    }
+
+    fn decode_block(&mut self, direction: Direction, stream_id: u32, flags: u8, block: &[u8], done: &mut Vec<Http2Stream>) {
+        let decoder = match direction {
+            Direction::Client => &mut self.client_decoder,
+            Direction::Server => &mut self.server_decoder,
+        };
+        // A decoding error desynchronises the dynamic table for good. hpack panics
+        // on a truncated table size update instead of returning an error
+        let headers = match panic::catch_unwind(AssertUnwindSafe(|| decoder.decode(block))) {
+            Ok(Ok(headers)) => headers,
+            _ => {
+                self.broken = true;
+                return;
+            }
+        };
+        let stream = self.stream(stream_id);
+        for (name, value) in headers {
+            let value = String::from_utf8_lossy(&value).to_string();
+            match &name[..] {
+                b":method" => stream.method = Some(value),
+                b":authority" => stream.authority = Some(value.to_lowercase()),
+                b":path" => stream.path = Some(value),
+                b":status" => stream.status = value.parse().ok(),
+                b"host" if stream.authority.is_none() => stream.authority = Some(value.to_lowercase()),
+                b"content-type" => stream.content_type = Some(value),
+                b"grpc-status" => stream.grpc_status = Some(value),
+                _ => (),
+            }
+        }
+        if direction == Direction::Server && flags & FLAG_END_STREAM != 0 {
+            self.finish(stream_id, done);
+        }
+    }
+
+    fn stream(&mut self, id: u32) -> &mut Http2Stream {
+        self.active.entry(id).or_default()
+    }
+}
+
+/// Strips the padding of a DATA, HEADERS or PUSH_PROMISE payload
+fn unpad(payload: &[u8], flags: u8) -> Option<&[u8]> {
+    if flags & FLAG_PADDED == 0 {
+        return Some(payload);
+    }
+    let pad = *payload.first()? as usize;
+    if pad + 1 > payload.len() {
+        return None;
+    }
+    Some(&payload[1..payload.len() - pad])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::http::HttpState;
+    use hpack::Encoder;
+
+    fn frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.push(frame_type);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn block(encoder: &mut Encoder, headers: &[(&str, &str)]) -> Vec<u8> {
+        let headers: Vec<(Vec<u8>, Vec<u8>)> =
+            headers.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect();
+        encoder.encode(&headers)
+    }
+
+    /// Feeds `data` one byte at a time, as the worst segmentation would
+    fn server_bytes(state: &mut Http2State, data: &[u8]) -> Vec<Http2Stream> {
+        data.iter().flat_map(|byte| state.on_server_data(&[*byte])).collect()
+    }
+
+    #[test]
+    fn parses_an_h2_exchange() {
+        let mut client = Encoder::new();
+        let mut server = Encoder::new();
+        let mut state = Http2State::new();
+
+        let mut data = PREFACE.to_vec();
+        data.extend(frame(FRAME_SETTINGS, 0, 0, &[0, 3, 0, 0, 0, 100]));
+        let request = block(&mut client, &[(":method", "GET"), (":scheme", "https"), (":authority", "Example.com"), (":path", "/")]);
+        data.extend(frame(FRAME_HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &request));
+        // The second request is split over a CONTINUATION frame
+        let request = block(&mut client, &[(":method", "POST"), (":authority", "example.com"), (":path", "/upload")]);
+        data.extend(frame(FRAME_HEADERS, 0, 3, &request[..2]));
+        data.extend(frame(FRAME_CONTINUATION, FLAG_END_HEADERS, 3, &request[2..]));
+        data.extend(frame(FRAME_DATA, FLAG_END_STREAM | FLAG_PADDED, 3, &[2, b'a', b'b', b'c', 0, 0]));
+        assert!(state.on_client_data(&data).is_empty());
+        assert_eq!(state.client_settings, [(3, 100)]);
+
+        let mut data = frame(FRAME_SETTINGS, 0, 0, &[]);
+        let response = block(&mut server, &[(":status", "200"), ("content-type", "text/html")]);
+        data.extend(frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &response));
+        data.extend(frame(FRAME_DATA, 0, 1, b"hello"));
+        data.extend(frame(FRAME_DATA, FLAG_END_STREAM, 1, b" world"));
+        data.extend(frame(FRAME_RST_STREAM, 0, 3, &8u32.to_be_bytes()));
+        data.extend(frame(FRAME_GOAWAY, 0, 0, &[0, 0, 0, 3, 0, 0, 0, 0]));
+        let streams = server_bytes(&mut state, &data);
+
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0].method.as_deref(), Some("GET"));
+        assert_eq!(streams[0].authority.as_deref(), Some("example.com"));
+        assert_eq!(streams[0].path.as_deref(), Some("/"));
+        assert_eq!(streams[0].status, Some(200));
+        assert_eq!(streams[0].content_type.as_deref(), Some("text/html"));
+        assert_eq!(streams[0].response_bytes, 11);
+        assert_eq!(streams[1].path.as_deref(), Some("/upload"));
+        assert_eq!(streams[1].request_bytes, 3);
+        assert_eq!(streams[1].reset, Some(8));
+        assert_eq!(state.goaway, Some((3, 0)));
+        assert!(state.active.is_empty());
+    }
+
+    #[test]
+    fn follows_an_h2c_upgrade() {
+        let mut server = Encoder::new();
+        let mut http = HttpState::new();
+
+        let mut data = b"GET /index.html HTTP/1.1\r\nHost: Example.com\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+            Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQCAAAAAAIAAAAA\r\n\r\n"
+            .to_vec();
+        data.extend_from_slice(PREFACE);
+        data.extend(frame(FRAME_SETTINGS, 0, 0, &[]));
+        assert_eq!(http.on_client_data(&data).len(), 1);
+
+        let mut data = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n".to_vec();
+        data.extend(frame(FRAME_SETTINGS, 0, 0, &[]));
+        let response = block(&mut server, &[(":status", "200")]);
+        data.extend(frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &response));
+        data.extend(frame(FRAME_DATA, FLAG_END_STREAM, 1, b"hello"));
+        let transactions = http.on_server_data(&data);
+        assert_eq!(transactions[0].response.as_ref().unwrap().status, 101);
+
+        let (request, client_data, server_data) = http.take_upgrade().unwrap();
+        let mut state = Http2State::upgraded(&request);
+        assert!(state.on_client_data(&client_data).is_empty());
+        let streams = state.on_server_data(&server_data);
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].method.as_deref(), Some("GET"));
+        assert_eq!(streams[0].authority.as_deref(), Some("example.com"));
+        assert_eq!(streams[0].path.as_deref(), Some("/index.html"));
+        assert_eq!(streams[0].status, Some(200));
+        assert_eq!(streams[0].response_bytes, 5);
+    }
+
+    #[test]
+    fn skips_malformed_frames() {
+        let mut server = Encoder::new();
+        let mut state = Http2State::new();
+        state.on_client_data(PREFACE);
+
+        // Padding longer than the frame, short RST_STREAM, GOAWAY and PUSH_PROMISE,
+        // a CONTINUATION without its HEADERS
+        let mut data = frame(FRAME_DATA, FLAG_PADDED | FLAG_END_STREAM, 1, &[9, 0]);
+        data.extend(frame(FRAME_HEADERS, FLAG_PADDED | FLAG_END_HEADERS, 1, &[]));
+        data.extend(frame(FRAME_RST_STREAM, 0, 1, &[0, 0]));
+        data.extend(frame(FRAME_GOAWAY, 0, 0, &[0, 0, 0, 1]));
+        data.extend(frame(FRAME_PUSH_PROMISE, FLAG_END_HEADERS, 1, &[0, 0]));
+        data.extend(frame(FRAME_CONTINUATION, FLAG_END_HEADERS, 5, &[0x82]));
+        data.extend(frame(0xFF, 0, 1, b"unknown"));
+        assert!(state.on_server_data(&data).is_empty());
+        assert_eq!(state.goaway, None);
+
+        // The connection is still followed
+        let response = block(&mut server, &[(":status", "204")]);
+        let streams = state.on_server_data(&frame(FRAME_HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &response));
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].status, Some(204));
+    }
+
+    #[test]
+    fn stops_at_a_bad_preface() {
+        let mut state = Http2State::new();
+        assert!(state.on_client_data(b"PRI * HTTP/1.1\r\n\r\nSM\r\n\r\n").is_empty());
+        assert!(state.broken);
+        assert!(state.on_server_data(&frame(FRAME_HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &[0x88])).is_empty());
+    }
+
+    #[test]
+    fn stops_at_a_bad_hpack_block() {
+        for block in [&[0x3F][..], &[0x3F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF], &[0xFF, 0x7F], &[0x40, 0x85, b'a']] {
+            let mut state = Http2State::new();
+            state.on_client_data(PREFACE);
+            assert!(state.on_server_data(&frame(FRAME_HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, block)).is_empty());
+            assert!(state.broken, "{:02x?}", block);
+            assert!(state.on_server_data(&frame(FRAME_HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 3, &[0x88])).is_empty());
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex, atomic::Ordering},
+};
+
+use etherparse::Ipv4Header;
+
+use crate::{
+    handlers::{tcp::{Quad, TcpContext}, udp::UdpContext},
+    stats::Stats,
+    utils::{ProtocolType, QueuePacket},
+};
+
+/// Echo requests without a reply are forgotten after 10 seconds (in microseconds)
+const ECHO_TIMEOUT: u128 = 10_000_000;
+
+/// Error reported by ICMP about a TCP or UDP flow
+#[derive(Debug, Clone)]
+pub struct IcmpError {
+    /// Capture time in milliseconds, like the flow it is attached to
+    pub ts: u128,
+    pub reporter: Ipv4Addr,
+    pub icmp_type: u8,
+    pub code: u8,
+    pub description: &'static str,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq)]
+struct EchoKey {
+    requester: IpAddr,
+    responder: IpAddr,
+    id: u16,
+    seq: u16,
+}
+
+#[derive(Debug, Default)]
+pub struct RttStats {
+    pub count: usize,
+    pub min: u128,
+    pub max: u128,
+    pub total: u128,
+}
+
+/// Echo tracking and type/code counters, shared with the report thread
+#[derive(Debug, Default)]
+pub struct IcmpState {
+    echoes: HashMap<EchoKey, u128>,
+    /// (requester, responder) -> RTT in microseconds
+    pub rtts: HashMap<(IpAddr, IpAddr), RttStats>,
+    /// (IP version, type, code) -> packets
+    pub counts: HashMap<(u8, u8, u8), usize>,
+    /// Capture time of the last ICMP packet, echoes expire relative to it so offline captures work too
+    last_ts: u128,
+}
+
+pub fn handle(
+    state: &Arc<Mutex<IcmpState>>,
+    packet: QueuePacket,
+    tcp_connections: &Arc<Mutex<HashMap<Quad, TcpContext>>>,
+    udp_connections: &Arc<Mutex<HashMap<Quad, UdpContext>>>,
+    stats: &Arc<Stats>,
+) {
+    let payload = packet.payload();
+    if payload.len() < 8 {
+        return;
+    }
+    stats.icmp.fetch_add(1, Ordering::Relaxed);
+
+    let (version, source, destination) = match (packet.source6, packet.destination6) {
+        (Some(source), Some(destination)) => (6, IpAddr::V6(source), IpAddr::V6(destination)),
+        _ => (4, IpAddr::V4(packet.source), IpAddr::V4(packet.destination)),
+    };
+    let icmp_type = payload[0];
+    let code = payload[1];
+    let id = u16::from_be_bytes([payload[4], payload[5]]);
+    let seq = u16::from_be_bytes([payload[6], payload[7]]);
+
+    let mut state = state.lock().unwrap();
+    *state.counts.entry((version, icmp_type, code)).or_insert(0) += 1;
+    state.last_ts = state.last_ts.max(packet.ts);
+
+    match (version, icmp_type) {
+        // Echo request
+        (4, 8) | (6, 128) => {
+            state.echoes.insert(EchoKey { requester: source, responder: destination, id, seq }, packet.ts);
+        }
+        // Echo reply
+        (4, 0) | (6, 129) => {
+            let key = EchoKey { requester: destination, responder: source, id, seq };
+            if let Some(sent) = state.echoes.remove(&key) {
+                let rtt = packet.ts.saturating_sub(sent);
+                let entry = state.rtts.entry((destination, source)).or_default();
+                if entry.count == 0 || rtt < entry.min {
+                    entry.min = rtt;
+                }
+                entry.max = entry.max.max(rtt);
+                entry.total += rtt;
+                entry.count += 1;
+                log::debug!("[ICMP] {} -> {} id {} seq {} rtt {:.3} ms", destination, source, id, seq, rtt as f64 / 1000.0);
+            }
+        }
+        // Destination unreachable, time exceeded
+        (4, 3) | (4, 11) => {
+            drop(state);
+            annotate(&packet, icmp_type, code, tcp_connections, udp_connections);
+        }
+        // Destination unreachable, packet too big, time exceeded, parameter problem
+        (6, 1..=4) => {
+            log::debug!("[ICMP] {} reports {} ({}/{})", source, describe(version, icmp_type, code), icmp_type, code);
+        }
+        _ => (),
+    }
+}
+
+/// Decodes the IPv4 header and ports quoted by an ICMP error and attaches the error to the flow
+fn annotate(
+    packet: &QueuePacket,
+    icmp_type: u8,
+    code: u8,
+    tcp_connections: &Arc<Mutex<HashMap<Quad, TcpContext>>>,
+    udp_connections: &Arc<Mutex<HashMap<Quad, UdpContext>>>,
+) {
+    let reporter = packet.source;
+    let (header, transport) = match Ipv4Header::read_from_slice(&packet.payload()[8..]) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    // Only the first 8 bytes of the transport header are guaranteed to be quoted
+    if transport.len() < 4 {
+        return;
+    }
+    let quad = Quad {
+        src: (Ipv4Addr::from(header.source), u16::from_be_bytes([transport[0], transport[1]])),
+        dst: (Ipv4Addr::from(header.destination), u16::from_be_bytes([transport[2], transport[3]])),
+        vlan: packet.flow_vlan,
+        tunnel: packet.flow_tunnel,
+    };
+    let error = IcmpError { ts: packet.millis(), reporter, icmp_type, code, description: describe(4, icmp_type, code) };
+    log::debug!(
+        "[ICMP] {} reports {} for {}:{} -> {}:{}",
+        reporter, error.description, quad.src.0, quad.src.1, quad.dst.0, quad.dst.1
+    );
+
+    if header.protocol == ProtocolType::TCP as u8 {
+        if let Some(ctx) = tcp_connections.lock().unwrap().get_mut(&quad) {
+            ctx.icmp_error = Some(error);
+        }
+    } else if header.protocol == ProtocolType::UDP as u8 {
+        if let Some(ctx) = udp_connections.lock().unwrap().get_mut(&quad) {
+            ctx.icmp_error = Some(error);
+        }
+    }
+}
+
+/// Logs the type/code counters and the RTT per host pair, and forgets unanswered echoes
+pub fn report(state: &Arc<Mutex<IcmpState>>) {
+    let mut state = state.lock().unwrap();
+    let now = state.last_ts;
+    state.echoes.retain(|_, sent| now.saturating_sub(*sent) < ECHO_TIMEOUT);
+
+    for ((version, icmp_type, code), count) in state.counts.iter() {
+        log::info!("[ICMP] v{} {} ({}/{}): {}", version, describe(*version, *icmp_type, *code), icmp_type, code, count);
+    }
+    for ((requester, responder), rtt) in state.rtts.iter() {
+        log::info!(
+            "[ICMP] {} -> {} replies: {} rtt min/avg/max {:.3}/{:.3}/{:.3} ms",
+            requester,
+            responder,
+            rtt.count,
+            rtt.min as f64 / 1000.0,
+            rtt.total as f64 / rtt.count as f64 / 1000.0,
+            rtt.max as f64 / 1000.0
+        );
+    }
+}
+
+pub fn describe(version: u8, icmp_type: u8, code: u8) -> &'static str {
+    match (version, icmp_type, code) {
+        (4, 0, _) => "echo reply",
+        (4, 3, 0) => "network unreachable",
+        (4, 3, 1) => "host unreachable",
+        (4, 3, 2) => "protocol unreachable",
+        (4, 3, 3) => "port unreachable",
+        (4, 3, 4) => "fragmentation needed",
+        (4, 3, 9) | (4, 3, 10) | (4, 3, 13) => "administratively prohibited",
+        (4, 3, _) => "destination unreachable",
+        (4, 5, _) => "redirect",
+        (4, 8, _) => "echo request",
+        (4, 11, 0) => "TTL exceeded in transit",
+        (4, 11, _) => "fragment reassembly time exceeded",
+        (4, 12, _) => "parameter problem",
+        (6, 1, 0) => "no route to destination",
+        (6, 1, 1) => "administratively prohibited",
+        (6, 1, 3) => "address unreachable",
+        (6, 1, 4) => "port unreachable",
+        (6, 1, _) => "destination unreachable",
+        (6, 2, _) => "packet too big",
+        (6, 3, 0) => "hop limit exceeded in transit",
+        (6, 3, _) => "fragment reassembly time exceeded",
+        (6, 4, _) => "parameter problem",
+        (6, 128, _) => "echo request",
+        (6, 129, _) => "echo reply",
+        (6, 133, _) => "router solicitation",
+        (6, 134, _) => "router advertisement",
+        (6, 135, _) => "neighbor solicitation",
+        (6, 136, _) => "neighbor advertisement",
+        (6, 143, _) => "MLDv2 report",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::{IpTrafficClass, UdpHeader};
+
+    use super::*;
+    use crate::{config::Config, dissector::Registry, handlers::udp, pool::PacketBuffer};
+
+    const CLIENT: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 1, 10), 50000);
+    const SERVER: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 1), 9999);
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+
+    fn packet(ts: u128, protocol: u8, source: Ipv4Addr, destination: Ipv4Addr, data: Vec<u8>) -> QueuePacket {
+        QueuePacket {
+            ts,
+            interface: 0,
+            protocol,
+            source,
+            destination,
+            source6: None,
+            destination6: None,
+            vlans: Vec::new(),
+            flow_vlan: 0,
+            flow_tunnel: 0,
+            tunnels: Vec::new(),
+            payload_len: data.len() as u16,
+            buffer: PacketBuffer::from_vec(data),
+            payload_offset: 0,
+        }
+    }
+
+    #[test]
+    fn stamps_the_error_like_the_flow() {
+        let udp_connections = Arc::new(Mutex::new(HashMap::new()));
+        let stats = Stats::new(&[]);
+        let mut datagram = Vec::new();
+        UdpHeader { source_port: CLIENT.1, destination_port: SERVER.1, length: 8, checksum: 0 }.write(&mut datagram).unwrap();
+        let hosts = Arc::new(Mutex::new(HashMap::new()));
+        let flow = packet(5_000_000, 17, CLIENT.0, SERVER.0, datagram.clone());
+        udp::handle(&Arc::new(Config::default()), &udp_connections, flow, &hosts, &Registry::default(), &stats);
+
+        // Port unreachable quoting the IPv4 header and the UDP header of the datagram
+        let mut error = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        Ipv4Header::new(8, 64, IpTrafficClass::Udp, CLIENT.0.octets(), SERVER.0.octets()).write(&mut error).unwrap();
+        error.extend_from_slice(&datagram);
+        let state = Arc::new(Mutex::new(IcmpState::default()));
+        handle(&state, packet(6_000_000, 1, ROUTER, CLIENT.0, error), &Arc::new(Mutex::new(HashMap::new())), &udp_connections, &stats);
+
+        let connections = udp_connections.lock().unwrap();
+        let ctx = connections.values().next().unwrap();
+        assert_eq!(ctx.first_ts, 5000);
+        assert_eq!(ctx.icmp_error.as_ref().map(|error| (error.ts, error.reporter)), Some((6000, ROUTER)));
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, net::Ipv4Addr, sync::{Arc, Mutex, atomic::Ordering}};
+
+use crate::{dissector::{Dissector, Flow, FlowDissector}, events::Event, hosts::{self, HostEntry, NameSource}, stats::Stats};
+
+/// Harvests host names and services from mDNS and LLMNR traffic,
+/// both use the DNS wire format so `dns_parser` does the heavy lifting
+pub fn handle(
+    ts: u128,
+    source: Ipv4Addr,
+    payload: &[u8],
+    name_source: NameSource,
+    hosts: &Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
+    stats: &Arc<Stats>,
+) {
+    let dns_packet = match dns_parser::Packet::parse(payload) {
+        Ok(dns_packet) => dns_packet,
+        Err(_) => return,
+    };
+    // Only responses (and mDNS announcements, which are unsolicited responses) carry names
+    if dns_packet.header.query {
+        return;
+    }
+    stats.names.fetch_add(1, Ordering::Relaxed);
+
+    let mut hosts = hosts.lock().unwrap();
+
+    // A records first so SRV targets announced in the same packet resolve to the right host
+    let mut addresses: HashMap<String, Ipv4Addr> = HashMap::new();
+    for record in dns_packet.answers.iter().chain(dns_packet.additional.iter()) {
+        if let dns_parser::RData::A(data) = record.data {
+            let name = record.name.to_string();
+            hosts::add_name(&mut hosts, data.0, &name, name_source, ts);
+            addresses.insert(name.to_lowercase(), data.0);
+        }
+    }
+
+    for record in dns_packet.answers.iter().chain(dns_packet.additional.iter()) {
+        if let dns_parser::RData::SRV(data) = record.data {
+            let instance = record.name.to_string();
+            let target = data.target.to_string();
+            let ip = addresses.get(&target.to_lowercase()).cloned().unwrap_or(source);
+            hosts::add_service(&mut hosts, ip, &service_name(&instance, data.port), name_source, ts);
+            if ip == source {
+                hosts::add_name(&mut hosts, ip, &target, name_source, ts);
+            }
+        }
+    }
+}
+
+/// Turns `My Printer._ipp._tcp.local` + 631 into `_ipp._tcp:631 (My Printer)`
+fn service_name(instance: &str, port: u16) -> String {
+    match instance.find("._") {
+        Some(idx) => {
+            let label = &instance[..idx];
+            let service = instance[idx + 1..].trim_end_matches(".local");
+            format!("{}:{} ({})", service, port, label)
+        }
+        None => format!("{}:{}", instance, port),
+    }
+}
+
+/// Harvests names from the flows to or from the mDNS (5353) or LLMNR (5355) port
+#[derive(Clone)]
+pub struct MdnsDissector {
+    pub port: u16,
+    pub name_source: NameSource,
+    pub hosts: Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
+    pub stats: Arc<Stats>,
+}
+
+impl Dissector for MdnsDissector {
+    fn name(&self) -> &'static str {
+        self.name_source.as_str()
+    }
+
+    fn probe(&self, flow: &Flow, _from_client: bool, _payload: &[u8]) -> bool {
+        flow.client.1 == self.port || flow.server.1 == self.port
+    }
+
+    fn start(&self) -> Box<dyn FlowDissector> {
+        Box::new(self.clone())
+    }
+}
+
+impl FlowDissector for MdnsDissector {
+    fn parse(&mut self, flow: &mut Flow, from_client: bool, data: &[u8], _events: &mut Vec<Event>) {
+        let source = if from_client { flow.client.0 } else { flow.server.0 };
+        handle(flow.ts, source, data, self.name_source, &self.hosts, &self.stats);
+    }
+}
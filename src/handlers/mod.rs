@@ -1,3 +1,16 @@
 pub mod tcp;
 pub mod udp;
-pub mod dns;
\ No newline at end of file
+pub mod dns;
+pub mod mdns;
+pub mod nbns;
+pub mod tls;
+pub mod encrypted_dns;
+pub mod dns_analytics;
+pub mod quic;
+pub mod reassembly;
+pub mod http;
+pub mod http2;
+pub mod icmp;
+pub mod arp;
+pub mod fragments;
+pub mod whatsapp;
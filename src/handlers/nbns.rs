@@ -0,0 +1,162 @@
+use std::{collections::HashMap, net::Ipv4Addr, sync::{Arc, Mutex, atomic::Ordering}};
+
+use crate::{dissector::{Dissector, Flow, FlowDissector}, events::Event, hosts::{self, HostEntry, NameSource}, stats::Stats};
+
+const NB_TYPE: u16 = 0x0020;
+const NBSTAT_TYPE: u16 = 0x0021;
+
+/// Harvests NetBIOS names from name registrations, positive query responses
+/// and node status responses (RFC 1002)
+pub fn handle(
+    ts: u128,
+    source: Ipv4Addr,
+    payload: &[u8],
+    hosts: &Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
+    stats: &Arc<Stats>,
+) {
+    if payload.len() < 12 {
+        return;
+    }
+    let questions = read_u16(payload, 4);
+    let records = read_u16(payload, 6) as usize + read_u16(payload, 8) as usize + read_u16(payload, 10) as usize;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = match skip_name(payload, offset) {
+            Some(end) => end + 4,
+            None => return,
+        };
+    }
+
+    let mut harvested = false;
+    let mut hosts = hosts.lock().unwrap();
+    for _ in 0..records {
+        let name = match decode_name(payload, offset) {
+            Some(name) => name,
+            None => break,
+        };
+        offset = match skip_name(payload, offset) {
+            Some(end) => end,
+            None => break,
+        };
+        if offset + 10 > payload.len() {
+            break;
+        }
+        let rtype = read_u16(payload, offset);
+        let rdlength = read_u16(payload, offset + 8) as usize;
+        let rdata_start = offset + 10;
+        offset = rdata_start + rdlength;
+        if offset > payload.len() {
+            break;
+        }
+        let rdata = &payload[rdata_start..offset];
+
+        match rtype {
+            NB_TYPE => {
+                // Each NB entry is 2 bytes of flags followed by the IPv4 address
+                for entry in rdata.chunks_exact(6) {
+                    let ip = Ipv4Addr::new(entry[2], entry[3], entry[4], entry[5]);
+                    hosts::add_name(&mut hosts, ip, &name, NameSource::NBNS, ts);
+                    harvested = true;
+                }
+            }
+            NBSTAT_TYPE => {
+                // Node status: count followed by 18-byte entries (15 name, 1 suffix, 2 flags)
+                if rdata.is_empty() {
+                    continue;
+                }
+                let count = rdata[0] as usize;
+                for entry in rdata[1..].chunks_exact(18).take(count) {
+                    let suffix = entry[15];
+                    let group = entry[16] & 0x80 != 0;
+                    let name = String::from_utf8_lossy(&entry[..15]).trim_end().to_string();
+                    // Workstation (0x00) unique names are the machine name, the rest are services/groups
+                    if suffix == 0x00 && !group {
+                        hosts::add_name(&mut hosts, source, &name, NameSource::NBNS, ts);
+                    } else {
+                        hosts::add_service(&mut hosts, source, &format!("{}<{:02x}>", name, suffix), NameSource::NBNS, ts);
+                    }
+                    harvested = true;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if harvested {
+        stats.names.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// Returns the offset right after the name at `offset`
+fn skip_name(data: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(offset)? as usize;
+        if len == 0 {
+            return Some(offset + 1);
+        }
+        if len & 0xC0 == 0xC0 {
+            return Some(offset + 2);
+        }
+        offset += len + 1;
+    }
+}
+
+/// Decodes the first-level encoded NetBIOS name (32 half-ASCII bytes) at `offset`,
+/// following a single compression pointer if needed
+fn decode_name(data: &[u8], offset: usize) -> Option<String> {
+    let len = *data.get(offset)? as usize;
+    if len & 0xC0 == 0xC0 {
+        let pointer = ((len & 0x3F) << 8) | *data.get(offset + 1)? as usize;
+        if pointer >= offset {
+            return None;
+        }
+        return decode_name(data, pointer);
+    }
+    if len != 32 {
+        return None;
+    }
+    let encoded = data.get(offset + 1..offset + 33)?;
+    let mut decoded = Vec::with_capacity(16);
+    for pair in encoded.chunks_exact(2) {
+        if !(b'A'..=b'P').contains(&pair[0]) || !(b'A'..=b'P').contains(&pair[1]) {
+            return None;
+        }
+        decoded.push(((pair[0] - b'A') << 4) | (pair[1] - b'A'));
+    }
+    // The 16th byte is the suffix (service type), the name is space padded
+    let name = String::from_utf8_lossy(&decoded[..15]).trim_end().to_string();
+    Some(name)
+}
+
+/// Harvests names from the flows to or from the NetBIOS name service port
+#[derive(Clone)]
+pub struct NbnsDissector {
+    pub hosts: Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
+    pub stats: Arc<Stats>,
+}
+
+impl Dissector for NbnsDissector {
+    fn name(&self) -> &'static str {
+        NameSource::NBNS.as_str()
+    }
+
+    fn probe(&self, flow: &Flow, _from_client: bool, _payload: &[u8]) -> bool {
+        flow.client.1 == 137 || flow.server.1 == 137
+    }
+
+    fn start(&self) -> Box<dyn FlowDissector> {
+        Box::new(self.clone())
+    }
+}
+
+impl FlowDissector for NbnsDissector {
+    fn parse(&mut self, flow: &mut Flow, from_client: bool, data: &[u8], _events: &mut Vec<Event>) {
+        let source = if from_client { flow.client.0 } else { flow.server.0 };
+        handle(flow.ts, source, data, &self.hosts, &self.stats);
+    }
+}
@@ -0,0 +1,453 @@
+use aes::{cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit}, Aes128};
+use aes_gcm::{aead::{Aead, Payload}, Aes128Gcm, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use std::sync::{Arc, atomic::Ordering};
+
+use crate::{
+    config::Config,
+    dissector::{Dissector, Flow, FlowDissector},
+    events::{AppEvent, Event},
+    handlers::{dns, tls::{self, ClientHello}},
+    reload::LiveConfig,
+    stats::Stats,
+};
+
+pub const QUIC_V1: u32 = 0x0000_0001;
+pub const QUIC_V2: u32 = 0x6b33_43cf;
+
+const SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17,
+    0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+];
+const SALT_V2: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93,
+    0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_ACK: u64 = 0x02;
+const FRAME_ACK_ECN: u64 = 0x03;
+const FRAME_CRYPTO: u64 = 0x06;
+
+/// The CRYPTO stream of a flow is given up past this size
+const MAX_CRYPTO_LEN: u64 = 16384;
+
+/// Reassembly state of the client Initial CRYPTO stream of a UDP flow
+#[derive(Debug, Default)]
+pub struct QuicState {
+    pub version: Option<u32>,
+    fragments: Vec<(u64, Vec<u8>)>,
+    done: bool,
+}
+
+/// Parses every client Initial packet coalesced in a datagram and returns the
+/// ClientHello once its CRYPTO frames have been fully reassembled
+pub fn handle(state: &mut QuicState, datagram: &[u8]) -> Option<ClientHello> {
+    if state.done {
+        return None;
+    }
+    let mut offset = 0;
+    while offset < datagram.len() {
+        let packet = &datagram[offset..];
+        let (version, packet_len) = match initial_packet_len(packet) {
+            Some(parsed) => parsed,
+            None => break,
+        };
+        if let Some(frames) = decrypt_initial(version, &packet[..packet_len]) {
+            state.version = Some(version);
+            collect_crypto_frames(state, &frames);
+        }
+        offset += packet_len;
+    }
+    let hello = reassemble(state).and_then(|handshake| tls::parse_client_hello(&handshake));
+    if hello.is_some() {
+        state.done = true;
+        state.fragments.clear();
+    }
+    hello
+}
+
+/// Returns true for a long header packet of a version we know how to decrypt
+pub fn is_long_header(datagram: &[u8]) -> bool {
+    datagram.len() > 5
+        && datagram[0] & 0xC0 == 0xC0
+        && matches!(read_u32(datagram, 1), Some(QUIC_V1) | Some(QUIC_V2))
+}
+
+fn is_initial(version: u32, first_byte: u8) -> bool {
+    let packet_type = (first_byte >> 4) & 0x03;
+    match version {
+        QUIC_V1 => packet_type == 0,
+        QUIC_V2 => packet_type == 1,
+        _ => false,
+    }
+}
+
+/// Returns the version and the total length of the Initial packet at the start
+/// of `packet`, or None if it isn't one
+fn initial_packet_len(packet: &[u8]) -> Option<(u32, usize)> {
+    if !is_long_header(packet) {
+        return None;
+    }
+    let version = read_u32(packet, 1)?;
+    if !is_initial(version, packet[0]) {
+        return None;
+    }
+    let mut offset = 5;
+    let dcid_len = *packet.get(offset)? as usize;
+    offset += 1 + dcid_len;
+    let scid_len = *packet.get(offset)? as usize;
+    offset += 1 + scid_len;
+    let (token_len, size) = read_varint(packet, offset)?;
+    offset += size + token_len as usize;
+    let (length, size) = read_varint(packet, offset)?;
+    offset += size;
+    let end = offset + length as usize;
+    if end > packet.len() {
+        return None;
+    }
+    Some((version, end))
+}
+
+/// Removes header protection and decrypts a client Initial packet, returning its frames
+fn decrypt_initial(version: u32, packet: &[u8]) -> Option<Vec<u8>> {
+    let dcid_len = *packet.get(5)? as usize;
+    let dcid = packet.get(6..6 + dcid_len)?;
+    let mut offset = 6 + dcid_len;
+    offset += 1 + *packet.get(offset)? as usize;
+    let (token_len, size) = read_varint(packet, offset)?;
+    offset += size + token_len as usize;
+    let (_, size) = read_varint(packet, offset)?;
+    let pn_offset = offset + size;
+
+    let (key, iv, hp) = client_initial_keys(version, dcid)?;
+
+    // Header protection, the sample starts 4 bytes after the packet number offset
+    let sample = packet.get(pn_offset + 4..pn_offset + 20)?;
+    let mut mask = GenericArray::clone_from_slice(sample);
+    Aes128::new(GenericArray::from_slice(&hp)).encrypt_block(&mut mask);
+
+    let mut header = packet[..pn_offset].to_vec();
+    header[0] ^= mask[0] & 0x0F;
+    let pn_len = (header[0] & 0x03) as usize + 1;
+    let mut packet_number: u64 = 0;
+    for i in 0..pn_len {
+        let byte = *packet.get(pn_offset + i)? ^ mask[1 + i];
+        header.push(byte);
+        packet_number = (packet_number << 8) | byte as u64;
+    }
+
+    let mut nonce = iv;
+    for (i, byte) in packet_number.to_be_bytes().iter().enumerate() {
+        nonce[4 + i] ^= byte;
+    }
+
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(&key));
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &packet[pn_offset + pn_len..],
+                aad: &header,
+            },
+        )
+        .ok()
+}
+
+/// Derives the client Initial key, IV and header protection key (RFC 9001 section 5.2, RFC 9369 section 3.3)
+fn client_initial_keys(version: u32, dcid: &[u8]) -> Option<([u8; 16], [u8; 12], [u8; 16])> {
+    let (salt, key_label, iv_label, hp_label): (&[u8], &[u8], &[u8], &[u8]) = match version {
+        QUIC_V1 => (&SALT_V1, b"quic key", b"quic iv", b"quic hp"),
+        QUIC_V2 => (&SALT_V2, b"quicv2 key", b"quicv2 iv", b"quicv2 hp"),
+        _ => return None,
+    };
+    let (initial_secret, _) = Hkdf::<Sha256>::extract(Some(salt), dcid);
+    let mut client_secret = [0u8; 32];
+    expand_label(&initial_secret, b"client in", &mut client_secret)?;
+
+    let mut key = [0u8; 16];
+    let mut iv = [0u8; 12];
+    let mut hp = [0u8; 16];
+    expand_label(&client_secret, key_label, &mut key)?;
+    expand_label(&client_secret, iv_label, &mut iv)?;
+    expand_label(&client_secret, hp_label, &mut hp)?;
+    Some((key, iv, hp))
+}
+
+/// TLS 1.3 HKDF-Expand-Label with an empty context
+fn expand_label(secret: &[u8], label: &[u8], out: &mut [u8]) -> Option<()> {
+    let mut info = Vec::with_capacity(4 + 6 + label.len());
+    info.extend_from_slice(&(out.len() as u16).to_be_bytes());
+    info.push((6 + label.len()) as u8);
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label);
+    info.push(0);
+    let hkdf = Hkdf::<Sha256>::from_prk(secret).ok()?;
+    hkdf.expand(&info, out).ok()
+}
+
+/// Walks the decrypted frames, keeping CRYPTO data and stopping at anything
+/// a client Initial isn't expected to carry
+fn collect_crypto_frames(state: &mut QuicState, frames: &[u8]) {
+    let mut offset = 0;
+    while offset < frames.len() {
+        let (frame_type, size) = match read_varint(frames, offset) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        offset += size;
+        match frame_type {
+            FRAME_PADDING | FRAME_PING => (),
+            FRAME_ACK | FRAME_ACK_ECN => match skip_ack(frames, offset, frame_type == FRAME_ACK_ECN) {
+                Some(end) => offset = end,
+                None => return,
+            },
+            FRAME_CRYPTO => {
+                let (crypto_offset, size) = match read_varint(frames, offset) {
+                    Some(parsed) => parsed,
+                    None => return,
+                };
+                offset += size;
+                let (length, size) = match read_varint(frames, offset) {
+                    Some(parsed) => parsed,
+                    None => return,
+                };
+                offset += size;
+                let data = match frames.get(offset..offset + length as usize) {
+                    Some(data) => data,
+                    None => return,
+                };
+                if crypto_offset + length <= MAX_CRYPTO_LEN {
+                    state.fragments.push((crypto_offset, data.to_vec()));
+                }
+                offset += length as usize;
+            }
+            _ => return,
+        }
+    }
+}
+
+fn skip_ack(frames: &[u8], mut offset: usize, ecn: bool) -> Option<usize> {
+    // largest acknowledged, ack delay, range count, first range
+    let mut fields = Vec::with_capacity(4);
+    for _ in 0..4 {
+        let (value, size) = read_varint(frames, offset)?;
+        fields.push(value);
+        offset += size;
+    }
+    // gap and length for each additional range
+    for _ in 0..fields[2] * 2 {
+        offset += read_varint(frames, offset)?.1;
+    }
+    if ecn {
+        for _ in 0..3 {
+            offset += read_varint(frames, offset)?.1;
+        }
+    }
+    Some(offset)
+}
+
+/// Returns the CRYPTO stream from offset 0 once the whole ClientHello is contiguous
+fn reassemble(state: &mut QuicState) -> Option<Vec<u8>> {
+    state.fragments.sort_by_key(|(offset, _)| *offset);
+    let mut stream: Vec<u8> = Vec::new();
+    for (offset, data) in &state.fragments {
+        let offset = *offset as usize;
+        if offset > stream.len() {
+            break;
+        }
+        if offset + data.len() > stream.len() {
+            stream.extend_from_slice(&data[stream.len() - offset..]);
+        }
+    }
+    if stream.len() < 4 {
+        return None;
+    }
+    let handshake_len = u32::from_be_bytes([0, stream[1], stream[2], stream[3]]) as usize;
+    if stream.len() < 4 + handshake_len {
+        return None;
+    }
+    stream.truncate(4 + handshake_len);
+    Some(stream)
+}
+
+/// QUIC variable-length integer, returns the value and its encoded size
+fn read_varint(data: &[u8], offset: usize) -> Option<(u64, usize)> {
+    let first = *data.get(offset)?;
+    let size = 1 << (first >> 6);
+    let bytes = data.get(offset..offset + size)?;
+    let mut value = (first & 0x3F) as u64;
+    for byte in &bytes[1..] {
+        value = (value << 8) | *byte as u64;
+    }
+    Some((value, size))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Follows the flows starting with a QUIC long header and reports their ClientHello
+pub struct QuicDissector {
+    pub config: Arc<LiveConfig>,
+    pub stats: Arc<Stats>,
+}
+
+struct QuicFlow {
+    /// Snapshot taken when the flow started, a reload doesn't change it
+    config: Arc<Config>,
+    state: QuicState,
+    stats: Arc<Stats>,
+}
+
+impl Dissector for QuicDissector {
+    fn name(&self) -> &'static str {
+        "quic"
+    }
+
+    fn probe(&self, _flow: &Flow, _from_client: bool, payload: &[u8]) -> bool {
+        is_long_header(payload)
+    }
+
+    fn start(&self) -> Box<dyn FlowDissector> {
+        Box::new(QuicFlow { config: self.config.get(), state: QuicState::default(), stats: self.stats.clone() })
+    }
+}
+
+impl FlowDissector for QuicFlow {
+    fn parse(&mut self, flow: &mut Flow, _from_client: bool, data: &[u8], events: &mut Vec<Event>) {
+        // Client Initials carry the ClientHello
+        if !is_long_header(data) {
+            return;
+        }
+        let hello = match handle(&mut self.state, data) {
+            Some(hello) => hello,
+            None => return,
+        };
+        self.stats.quic.fetch_add(1, Ordering::Relaxed);
+        flow.sni = hello.sni;
+        events.push(Event::App(AppEvent::hello(flow.ts, "quic", flow.client, flow.server, &flow.sni)));
+        if let Some(app_type) = flow.sni.as_ref().and_then(|sni| dns::dns_to_app(&self.config, sni)) {
+            flow.app_type = app_type;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Destination connection ID of the RFC 9001 Appendix A packets
+    const DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+    /// CRYPTO frame of the client Initial in RFC 9001 Appendix A.2, the ClientHello of example.com
+    const CRYPTO_FRAME: &str = "
+        060040f1010000ed0303ebf8fa56f12939b9584a3896472ec40bb863cfd3e868
+        04fe3a47f06a2b69484c00000413011302010000c000000010000e00000b6578
+        616d706c652e636f6dff01000100000a00080006001d00170018001000070005
+        04616c706e000500050100000000003300260024001d00209370b2c9caa47fba
+        baf4fe3b00c34a0c6be9e6e0a94c1d20bdb82fc26b9b47c0002b000302030400
+        0d0010000e0403050306030203080408050806002d00020101001c0002400100
+        3900320408ffffffffffffffff05048000ffff07048000ffff08011001048000
+        75300901100f088394c8f03e51570806048000ffff";
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).unwrap())
+            .collect()
+    }
+
+    /// Long header of a v1 client Initial with a 4 byte packet number, no SCID and no token
+    fn header(packet_number: u32, payload_len: usize) -> Vec<u8> {
+        let mut header = vec![0xc3, 0x00, 0x00, 0x00, 0x01, DCID.len() as u8];
+        header.extend_from_slice(&DCID);
+        header.extend_from_slice(&[0x00, 0x00]);
+        header.extend_from_slice(&(0x4000 | (4 + payload_len + 16) as u16).to_be_bytes());
+        header.extend_from_slice(&packet_number.to_be_bytes());
+        header
+    }
+
+    /// Encrypts and protects a client Initial as RFC 9001 section 5 does
+    fn protect(header: &[u8], payload: &[u8]) -> Vec<u8> {
+        let (key, iv, hp) = client_initial_keys(QUIC_V1, &DCID).unwrap();
+        let pn_offset = header.len() - 4;
+        let packet_number = u32::from_be_bytes([header[pn_offset], header[pn_offset + 1], header[pn_offset + 2], header[pn_offset + 3]]);
+        let mut nonce = iv;
+        for (i, byte) in (packet_number as u64).to_be_bytes().iter().enumerate() {
+            nonce[4 + i] ^= byte;
+        }
+        let cipher = Aes128Gcm::new(GenericArray::from_slice(&key));
+        let sealed = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: payload, aad: header }).unwrap();
+
+        let mut packet = [header, &sealed].concat();
+        let mut mask = GenericArray::clone_from_slice(&packet[pn_offset + 4..pn_offset + 20]);
+        Aes128::new(GenericArray::from_slice(&hp)).encrypt_block(&mut mask);
+        packet[0] ^= mask[0] & 0x0F;
+        for i in 0..4 {
+            packet[pn_offset + i] ^= mask[1 + i];
+        }
+        packet
+    }
+
+    /// The 1200 byte client Initial of RFC 9001 Appendix A.2
+    fn rfc_9001_initial() -> Vec<u8> {
+        let mut payload = hex(CRYPTO_FRAME);
+        payload.resize(1162, 0);
+        protect(&header(2, payload.len()), &payload)
+    }
+
+    #[test]
+    fn derives_the_rfc_9001_client_keys() {
+        let (key, iv, hp) = client_initial_keys(QUIC_V1, &DCID).unwrap();
+        assert_eq!(key.to_vec(), hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(hp.to_vec(), hex("9f50449e04a0e810283a1e9933adedd2"));
+    }
+
+    #[test]
+    fn reads_the_rfc_9001_client_initial() {
+        let packet = rfc_9001_initial();
+        // The protected header and the sample RFC 9001 Appendix A.2 gives
+        assert_eq!(packet.len(), 1200);
+        assert_eq!(packet[..22].to_vec(), hex("c000000001088394c8f03e5157080000449e7b9aec34"));
+        assert_eq!(packet[22..38].to_vec(), hex("d1b1c98dd7689fb8ec11d242b123dc9b"));
+
+        let mut state = QuicState::default();
+        let hello = handle(&mut state, &packet).unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, ["alpn"]);
+        assert_eq!(state.version, Some(QUIC_V1));
+        // Parsed once per flow
+        assert!(handle(&mut state, &packet).is_none());
+    }
+
+    #[test]
+    fn reassembles_a_client_hello_split_over_two_initials() {
+        let handshake = &hex(CRYPTO_FRAME)[4..];
+        let (first, second) = handshake.split_at(100);
+        let mut first_frame = vec![0x06, 0x00, 0x40, first.len() as u8];
+        first_frame.extend_from_slice(first);
+        let mut second_frame = vec![0x06, 0x40, 100, 0x40 | (second.len() >> 8) as u8, second.len() as u8];
+        second_frame.extend_from_slice(second);
+
+        let mut state = QuicState::default();
+        // The second half first, as a reordered datagram would bring it
+        assert!(handle(&mut state, &protect(&header(3, second_frame.len()), &second_frame)).is_none());
+        let hello = handle(&mut state, &protect(&header(2, first_frame.len()), &first_frame)).unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn ignores_a_corrupted_initial() {
+        let mut packet = rfc_9001_initial();
+        packet[100] ^= 0x01;
+        assert!(handle(&mut QuicState::default(), &packet).is_none());
+        assert!(handle(&mut QuicState::default(), &packet[..600]).is_none());
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+/// Out of order segments are dropped past this many buffered bytes per direction
+const MAX_PENDING: usize = 1024 * 1024;
+
+/// Puts the segments of one direction of a TCP connection back in order
+#[derive(Debug, Default)]
+pub struct StreamBuffer {
+    next_seq: Option<u32>,
+    pending: BTreeMap<u32, Vec<u8>>,
+    pending_len: usize,
+}
+
+impl StreamBuffer {
+    /// Sets the sequence number of the first payload byte (ISN + 1)
+    pub fn init(&mut self, next_seq: u32) {
+        self.next_seq = Some(next_seq);
+    }
+
+    /// Feeds a segment and returns the bytes that became contiguous, in order
+    pub fn push(&mut self, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        if payload.is_empty() {
+            return data;
+        }
+        let next_seq = *self.next_seq.get_or_insert(seq);
+
+        let offset = seq.wrapping_sub(next_seq) as i32;
+        if offset > 0 {
+            // Hole before this segment, keep it for later
+            if self.pending_len + payload.len() <= MAX_PENDING && !self.pending.contains_key(&seq) {
+                self.pending_len += payload.len();
+                self.pending.insert(seq, payload.to_vec());
+            }
+            return data;
+        }
+        // Retransmission or overlap, only keep the new bytes
+        let skip = (-offset) as usize;
+        if skip >= payload.len() {
+            return data;
+        }
+        data.extend_from_slice(&payload[skip..]);
+        let mut next_seq = next_seq.wrapping_add(data.len() as u32);
+
+        // Drain the pending segments that now fit, nearest first as the sequence numbers can wrap
+        while let Some(seq) = self.pending.keys().copied().min_by_key(|seq| seq.wrapping_sub(next_seq) as i32) {
+            let offset = seq.wrapping_sub(next_seq) as i32;
+            if offset > 0 {
+                break;
+            }
+            let segment = self.pending.remove(&seq).unwrap();
+            self.pending_len -= segment.len();
+            let skip = (-offset) as usize;
+            if skip < segment.len() {
+                data.extend_from_slice(&segment[skip..]);
+                next_seq = next_seq.wrapping_add((segment.len() - skip) as u32);
+            }
+        }
+        self.next_seq = Some(next_seq);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_in_order_segments_through() {
+        let mut stream = StreamBuffer::default();
+        stream.init(100);
+        assert_eq!(stream.push(100, b"GET "), b"GET ");
+        assert_eq!(stream.push(104, b"/ HTTP/1.1"), b"/ HTTP/1.1");
+    }
+
+    #[test]
+    fn waits_for_the_hole_to_be_filled() {
+        let mut stream = StreamBuffer::default();
+        stream.init(100);
+        assert!(stream.push(107, b"world").is_empty());
+        assert!(stream.push(105, b", ").is_empty());
+        assert_eq!(stream.push(100, b"hello"), b"hello, world");
+        assert_eq!(stream.pending_len, 0);
+    }
+
+    #[test]
+    fn keeps_only_the_new_bytes_of_a_retransmission() {
+        let mut stream = StreamBuffer::default();
+        stream.init(100);
+        assert_eq!(stream.push(100, b"hello"), b"hello");
+        assert!(stream.push(100, b"hello").is_empty());
+        assert_eq!(stream.push(103, b"lo world"), b" world");
+    }
+
+    #[test]
+    fn reassembles_across_the_sequence_number_wrap() {
+        let mut stream = StreamBuffer::default();
+        stream.init(u32::MAX - 1);
+        assert!(stream.push(1, b"d").is_empty());
+        assert!(stream.push(u32::MAX, b"b").is_empty());
+        assert!(stream.push(0, b"c").is_empty());
+        assert_eq!(stream.push(u32::MAX - 1, b"a"), b"abcd");
+        assert_eq!(stream.push(2, b"e"), b"e");
+    }
+
+    #[test]
+    fn drops_segments_past_max_pending() {
+        let mut stream = StreamBuffer::default();
+        stream.init(0);
+        assert!(stream.push(10, &vec![0; MAX_PENDING + 1]).is_empty());
+        assert_eq!(stream.pending_len, 0);
+        assert_eq!(stream.push(0, b"0123456789"), b"0123456789");
+    }
+}
@@ -2,24 +2,27 @@ use etherparse::TcpHeader;
 use std::collections::btree_map::Entry;
 use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::{
     collections::HashMap,
     net::Ipv4Addr,
     sync::{atomic::Ordering, Arc, Mutex},
-    thread,
-    time::Duration,
     u16, usize,
 };
 
 use crate::config::Config;
-use crate::utils::{AppType, Files};
+use crate::dissector::{Flow, FlowDissector, Registry};
+use crate::hosts::{self, HostEntry};
+use crate::events::{Event, Events, FlowRecord};
+use crate::utils::{AppType, EncryptedDnsType, Tunnel};
 use crate::{
-    handlers::dns,
+    handlers::{dns, encrypted_dns, icmp::IcmpError, reassembly::StreamBuffer},
     stats::Stats,
     utils::{DnsRecord, QueuePacket},
 };
 
+/// TCP connections are idle-expired after 120 seconds
+const TCP_TIMEOUT: u128 = 120000;
+
 #[derive(Debug)]
 pub struct TcpContext {
     pub src_ip: Ipv4Addr,
@@ -30,19 +33,44 @@ pub struct TcpContext {
     pub first_ts: u128,
     pub last_ts: u128,
     pub app_type: AppType,
-    pub associated_dns: Vec<String> 
+    pub associated_dns: Vec<String>,
+    pub src_name: Option<String>,
+    pub dst_name: Option<String>,
+    pub sni: Option<String>,
+    pub encrypted_dns: Option<EncryptedDnsType>,
+    pub client_stream: StreamBuffer,
+    pub server_stream: StreamBuffer,
+    /// Dissectors following the connection, chosen on its first payload
+    pub dissectors: Vec<Box<dyn FlowDissector>>,
+    pub probed: bool,
+    /// The connection is closed once both sides sent their FIN, or on a RST
+    pub client_fin: bool,
+    pub server_fin: bool,
+    pub icmp_error: Option<IcmpError>,
+    /// Outer tunnels (TEID, VNI, GRE key) the flow was carried in
+    pub tunnels: Vec<Tunnel>,
+    /// Capture sources the flow was seen on, both directions can come from different taps
+    pub interfaces: Vec<u16>,
+    /// Snapshot taken when the flow started, a reload doesn't change it
+    pub config: Arc<Config>,
 }
 
 #[derive(Debug, Clone, Copy, Eq)]
 pub struct Quad {
-    src: (Ipv4Addr, u16),
-    dst: (Ipv4Addr, u16),
+    pub src: (Ipv4Addr, u16),
+    pub dst: (Ipv4Addr, u16),
+    /// 0 unless VLANs are part of the flow key
+    pub vlan: u16,
+    /// 0 unless tunnel IDs are part of the flow key
+    pub tunnel: u32,
 }
 
 impl PartialEq for Quad {
     fn eq(&self, other: &Self) -> bool {
-        (self.src == other.src && self.dst == other.dst)
-            || (self.src == other.dst && self.dst == other.src)
+        self.vlan == other.vlan
+            && self.tunnel == other.tunnel
+            && ((self.src == other.src && self.dst == other.dst)
+                || (self.src == other.dst && self.dst == other.src))
     }
 }
 
@@ -51,21 +79,27 @@ impl Hash for Quad {
     fn hash<H: Hasher>(&self, hasher: &mut H) {
         let src: u64 = (u32::from(self.src.0) + u32::from(self.src.1)).into();
         let dst: u64 = (u32::from(self.dst.0) + u32::from(self.dst.1)).into();
-        let sum: u64 = src + dst;
+        let sum: u64 = src + dst + u64::from(self.vlan) + u64::from(self.tunnel);
         sum.hash(hasher);
     }
 }
 
+/// Tracks the connection and feeds its reassembled streams to the dissectors, returns what they found
 pub fn handle(
-    files: &mut Files,
-    config: &Config,
-    connections: Arc<Mutex<HashMap<Quad, TcpContext>>>,
+    config: &Arc<Config>,
+    connections: &Arc<Mutex<HashMap<Quad, TcpContext>>>,
     packet: QueuePacket,
     dns_records: &Arc<Mutex<HashMap<DnsRecord, String>>>,
+    hosts: &Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
+    dissectors: &Registry,
     stats: &Arc<Stats>,
-) {
-    match TcpHeader::read_from_slice(&packet.payload[..]) {
-        Err(_) => todo!(),
+) -> Vec<Event> {
+    let mut events = Vec::new();
+    match TcpHeader::read_from_slice(packet.payload()) {
+        // Cut by the snaplen or a first fragment too short for the header
+        Err(_) => {
+            stats.malformed.fetch_add(1, Ordering::Relaxed);
+        }
         Ok((tcp_header, tcp_payload)) => {
             // Check for SYN ACK
             if tcp_header.syn && tcp_header.ack {
@@ -74,9 +108,11 @@ pub fn handle(
                 if mut_connections.contains_key(&Quad {
                     src: (packet.source, tcp_header.source_port),
                     dst: (packet.destination, tcp_header.destination_port),
+                    vlan: packet.flow_vlan,
+                    tunnel: packet.flow_tunnel,
                 }) {
                     // if the context is found, we ignore this packet
-                    return;
+                    return events;
                 } else {
                     // if new context we add it
                     // first we need to find the dns associated with the ips
@@ -91,7 +127,7 @@ pub fn handle(
                     let mut app_type: AppType = AppType::NONE;
 
                     for x in &dns_results {
-                        let dns_app_type = dns::dns_to_app(&x);
+                        let dns_app_type = dns::dns_to_app(config, &x);
                         match dns_app_type {
                             Some(t) => {
                                 app_type = t;
@@ -101,87 +137,93 @@ pub fn handle(
                         }
                     }
 
-                    let ts = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis();
+                    // The SYN ACK comes from the server
+                    let encrypted_dns_type = encrypted_dns::detect_tcp(
+                        config,
+                        (packet.source, tcp_header.source_port),
+                        (packet.destination, tcp_header.destination_port),
+                    );
+                    if let Some(dns_type) = &encrypted_dns_type {
+                        encrypted_dns::count(stats, dns_type);
+                    }
+
+                    // The server's first byte follows its ISN, the client's is what the server acks
+                    let mut server_stream = StreamBuffer::default();
+                    server_stream.init(tcp_header.sequence_number.wrapping_add(1));
+                    let mut client_stream = StreamBuffer::default();
+                    client_stream.init(tcp_header.acknowledgment_number);
+
+                    let ts = packet.millis();
+                    let ctx = TcpContext {
+                        src_ip: packet.source,
+                        dst_ip: packet.destination,
+                        src_port: tcp_header.source_port,
+                        dst_port: tcp_header.destination_port,
+                        first_ts: ts,
+                        last_ts: ts,
+                        len: 1,
+                        app_type: app_type,
+                        associated_dns: dns_results,
+                        src_name: hosts::lookup(hosts, &packet.source),
+                        dst_name: hosts::lookup(hosts, &packet.destination),
+                        sni: None,
+                        encrypted_dns: encrypted_dns_type,
+                        client_stream,
+                        server_stream,
+                        dissectors: Vec::new(),
+                        probed: false,
+                        client_fin: false,
+                        server_fin: false,
+                        icmp_error: None,
+                        tunnels: packet.tunnels.clone(),
+                        interfaces: vec![packet.interface],
+                        config: config.clone(),
+                    };
+                    events.push(Event::FlowStarted(record(&ctx, packet.flow_vlan)));
                     mut_connections.insert(
                         Quad {
                             src: (packet.source, tcp_header.source_port),
                             dst: (packet.destination, tcp_header.destination_port),
+                            vlan: packet.flow_vlan,
+                            tunnel: packet.flow_tunnel,
                         },
-                        TcpContext {
-                            src_ip: packet.source,
-                            dst_ip: packet.destination,
-                            src_port: tcp_header.source_port,
-                            dst_port: tcp_header.destination_port,
-                            first_ts: ts,
-                            last_ts: ts,
-                            len: 1,
-                            app_type: app_type,
-                            associated_dns: dns_results
-                        },
+                        ctx,
                     );
 
-                    println!("{:?} {:?}", packet.source, packet.destination);
-
                     stats.ctx.fetch_add(1, Ordering::Relaxed);
-                    // Start the kill thread
-                    let stats = stats.clone();
-                    drop(mut_connections);
-                    thread::spawn(move || loop {
-                        thread::sleep(Duration::from_secs(1));
-                        let mut mut_connections = connections.lock().unwrap();
-                        if mut_connections.contains_key(&Quad {
-                            src: (packet.source, tcp_header.source_port),
-                            dst: (packet.destination, tcp_header.destination_port),
-                        }) {
-                            let ts = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap()
-                                .as_millis();
-                            let ctx = mut_connections
-                                .get(&Quad {
-                                    src: (packet.source, tcp_header.source_port),
-                                    dst: (packet.destination, tcp_header.destination_port),
-                                })
-                                .unwrap();
-
-                            if ts - ctx.last_ts >= 120000 {
-                                mut_connections.remove(&Quad {
-                                    src: (packet.source, tcp_header.source_port),
-                                    dst: (packet.destination, tcp_header.destination_port),
-                                });
-                                if stats.ctx.load(Ordering::Relaxed) > 0 {
-                                    stats.ctx.fetch_sub(1, Ordering::Relaxed);
-                                    break;
-                                }
-                            }
-                        } else {
-                            // Can't find the context, that means FIN or RST received
-                            mut_connections.remove(&Quad {
-                                src: (packet.source, tcp_header.source_port),
-                                dst: (packet.destination, tcp_header.destination_port),
-                            });
-                            if stats.ctx.load(Ordering::Relaxed) > 0 {
-                                stats.ctx.fetch_sub(1, Ordering::Relaxed);
-                                break;
-                            }
-                        }
-                    });
                 }
-            } else if (tcp_header.fin && tcp_header.ack) || tcp_header.rst {
+            } else if tcp_header.fin || tcp_header.rst {
                 let mut mut_connections = connections.lock().unwrap();
-                // we drop the context
-                if mut_connections.contains_key(&Quad {
+                let quad = Quad {
                     src: (packet.source, tcp_header.source_port),
                     dst: (packet.destination, tcp_header.destination_port),
-                }) {
-                    // if the context is found, we drop it
-                    mut_connections.remove(&Quad {
-                        src: (packet.source, tcp_header.source_port),
-                        dst: (packet.destination, tcp_header.destination_port),
-                    });
+                    vlan: packet.flow_vlan,
+                    tunnel: packet.flow_tunnel,
+                };
+                // A half-closed connection still carries the other direction
+                let closed = match mut_connections.get_mut(&quad) {
+                    Some(ctx) => {
+                        // the last segment can still carry data
+                        if handle_stream(ctx, &packet, &tcp_header, tcp_payload, dissectors, &mut events) {
+                            on_sni(ctx, stats);
+                        }
+                        if tcp_header.fin {
+                            if packet.source == ctx.dst_ip && tcp_header.source_port == ctx.dst_port {
+                                ctx.client_fin = true;
+                            } else {
+                                ctx.server_fin = true;
+                            }
+                        }
+                        tcp_header.rst || (ctx.client_fin && ctx.server_fin)
+                    }
+                    None => false,
+                };
+                // we drop the context
+                if closed {
+                    if let Some(mut ctx) = mut_connections.remove(&quad) {
+                        close(&mut ctx, packet.flow_vlan, &mut events);
+                        stats.ctx.fetch_sub(1, Ordering::Relaxed);
+                    }
                 }
             } else {
                 let mut mut_connections = connections.lock().unwrap();
@@ -191,33 +233,31 @@ pub fn handle(
                 if !mut_connections.contains_key(&Quad {
                     src: (packet.source, tcp_header.source_port),
                     dst: (packet.destination, tcp_header.destination_port),
+                    vlan: packet.flow_vlan,
+                    tunnel: packet.flow_tunnel,
                 }) {
                     // if the context is not found, we ignore this packet
-                    return;
+                    return events;
                 } else {
-                    if tcp_payload.len() <= 3 {
-                        return;
-                    }
-                    
                     match mut_connections.get_mut(&Quad {
                         src: (packet.source, tcp_header.source_port),
                         dst: (packet.destination, tcp_header.destination_port),
+                        vlan: packet.flow_vlan,
+                        tunnel: packet.flow_tunnel,
                     }) {
                         Some(ctx) => {
-                            ctx.len += 1;
-                            ctx.last_ts = SystemTime::now()
-                                    .duration_since(UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_millis();
-                            // handling applications
-                            // Whatsapp
-                            if tcp_payload[0] == 69 && tcp_payload[1] == 68 && tcp_payload[2] == 0 && tcp_payload[3] == 1 {
-                                ctx.app_type = AppType::WHATSAPP;
+                            if !ctx.interfaces.contains(&packet.interface) {
+                                ctx.interfaces.push(packet.interface);
+                            }
+                            if handle_stream(ctx, &packet, &tcp_header, tcp_payload, dissectors, &mut events) {
+                                on_sni(ctx, stats);
                             }
 
-                            if ctx.app_type == AppType::WHATSAPP {
-                                println!("[0]Whatsapp packet len: {:?}", packet.payload_len);
+                            if tcp_payload.len() <= 3 {
+                                return events;
                             }
+                            ctx.len += 1;
+                            ctx.last_ts = packet.millis();
                         },
                         None => (),
                     }
@@ -228,4 +268,272 @@ pub fn handle(
             stats.tcp.fetch_add(1, Ordering::Relaxed);
         }
     };
+    events
+}
+
+/// Lets the dissectors log what they still hold for a connection that is going away
+/// and adds its flow record
+fn close(ctx: &mut TcpContext, vlan: u16, events: &mut Vec<Event>) {
+    let mut flow = flow(ctx);
+    for dissector in ctx.dissectors.iter_mut() {
+        dissector.close(&mut flow, events);
+    }
+    events.push(Event::FlowEnded(record(ctx, vlan)));
+}
+
+/// The context was created from the SYN ACK, so dst is the client
+fn flow(ctx: &TcpContext) -> Flow {
+    Flow {
+        protocol: 6,
+        client: (ctx.dst_ip, ctx.dst_port),
+        server: (ctx.src_ip, ctx.src_port),
+        app_type: ctx.app_type,
+        sni: ctx.sni.clone(),
+        ts: ctx.last_ts,
+    }
+}
+
+fn record(ctx: &TcpContext, vlan: u16) -> FlowRecord {
+    FlowRecord {
+        protocol: 6,
+        client: (ctx.dst_ip, ctx.dst_port),
+        server: (ctx.src_ip, ctx.src_port),
+        vlan,
+        first_ts: ctx.first_ts,
+        last_ts: ctx.last_ts,
+        packets: ctx.len,
+        app_type: ctx.app_type,
+        sni: ctx.sni.clone(),
+        client_name: ctx.dst_name.clone(),
+        server_name: ctx.src_name.clone(),
+        icmp_error: ctx.icmp_error.clone(),
+        interfaces: ctx.interfaces.clone(),
+        tunnels: ctx.tunnels.clone(),
+    }
+}
+
+/// Closes the connections that have been idle for longer than `TCP_TIMEOUT` at `ts`, the capture clock of the worker
+pub fn expire(connections: &Arc<Mutex<HashMap<Quad, TcpContext>>>, ts: u128, events: &Arc<Events>, stats: &Arc<Stats>) {
+    let mut expired = Vec::new();
+    let mut mut_connections = connections.lock().unwrap();
+    mut_connections.retain(|quad, ctx| {
+        // A clock stepping back keeps the connection
+        if ts.saturating_sub(ctx.last_ts) < TCP_TIMEOUT {
+            return true;
+        }
+        close(ctx, quad.vlan, &mut expired);
+        stats.ctx.fetch_sub(1, Ordering::Relaxed);
+        false
+    });
+    drop(mut_connections);
+    for event in expired {
+        events.emit(event);
+    }
+}
+
+/// Closes every connection still open once the input has ended, returns how many there were
+pub fn flush(connections: &Arc<Mutex<HashMap<Quad, TcpContext>>>, events: &Arc<Events>, stats: &Arc<Stats>) -> usize {
+    let mut closed = Vec::new();
+    let mut mut_connections = connections.lock().unwrap();
+    let count = mut_connections.len();
+    for (quad, mut ctx) in mut_connections.drain() {
+        close(&mut ctx, quad.vlan, &mut closed);
+    }
+    drop(mut_connections);
+    stats.ctx.fetch_sub(count.min(stats.ctx.load(Ordering::Relaxed)), Ordering::Relaxed);
+    for event in closed {
+        events.emit(event);
+    }
+    count
+}
+
+/// Reassembles the payload of a known connection, starts the dissectors on its first
+/// in-order bytes and feeds them the rest. Returns true when they found the SNI
+fn handle_stream(
+    ctx: &mut TcpContext,
+    packet: &QueuePacket,
+    tcp_header: &TcpHeader,
+    payload: &[u8],
+    dissectors: &Registry,
+    events: &mut Vec<Event>,
+) -> bool {
+    if payload.is_empty() {
+        return false;
+    }
+    // The context was created from the SYN ACK, so dst is the client
+    let from_client = packet.source == ctx.dst_ip && tcp_header.source_port == ctx.dst_port;
+    let data = if from_client {
+        ctx.client_stream.push(tcp_header.sequence_number, payload)
+    } else {
+        ctx.server_stream.push(tcp_header.sequence_number, payload)
+    };
+    if data.is_empty() {
+        return false;
+    }
+    let mut flow = flow(ctx);
+    flow.ts = packet.millis();
+    if !ctx.probed {
+        ctx.probed = true;
+        ctx.dissectors = dissectors.start(&flow, from_client, &data);
+    }
+    for dissector in ctx.dissectors.iter_mut() {
+        dissector.parse(&mut flow, from_client, &data, events);
+    }
+    let learned_sni = flow.sni.is_some() && ctx.sni.is_none();
+    if flow.app_type == ctx.app_type && !learned_sni {
+        return false;
+    }
+    ctx.app_type = flow.app_type;
+    if learned_sni {
+        ctx.sni = flow.sni;
+    }
+    events.push(Event::FlowUpdated(record(ctx, packet.flow_vlan)));
+    learned_sni
+}
+
+/// DoH servers are recognized by their SNI, with the servers of the flow's config
+fn on_sni(ctx: &mut TcpContext, stats: &Arc<Stats>) {
+    if ctx.encrypted_dns.is_some() {
+        return;
+    }
+    if let Some(sni) = &ctx.sni {
+        ctx.encrypted_dns = encrypted_dns::detect_sni(&ctx.config, sni);
+        if let Some(dns_type) = &ctx.encrypted_dns {
+            encrypted_dns::count(stats, dns_type);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::PacketBuffer;
+
+    const CLIENT: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 1, 10), 50000);
+    const SERVER: (Ipv4Addr, u16) = (Ipv4Addr::new(93, 184, 216, 34), 80);
+
+    struct Connection {
+        config: Arc<Config>,
+        connections: Arc<Mutex<HashMap<Quad, TcpContext>>>,
+        dns_records: Arc<Mutex<HashMap<DnsRecord, String>>>,
+        hosts: Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
+        stats: Arc<Stats>,
+    }
+
+    impl Connection {
+        /// Starts with the SYN ACK of the server
+        fn new() -> Connection {
+            let connection = Connection {
+                config: Arc::new(Config::default()),
+                connections: Arc::new(Mutex::new(HashMap::new())),
+                dns_records: Arc::new(Mutex::new(HashMap::new())),
+                hosts: Arc::new(Mutex::new(HashMap::new())),
+                stats: Stats::new(&[]),
+            };
+            let events = connection.segment(SERVER, CLIENT, |header| {
+                header.syn = true;
+                header.ack = true;
+            });
+            assert!(matches!(events.as_slice(), [Event::FlowStarted(_)]));
+            connection
+        }
+
+        fn segment(&self, from: (Ipv4Addr, u16), to: (Ipv4Addr, u16), flags: impl Fn(&mut TcpHeader)) -> Vec<Event> {
+            let mut header = TcpHeader::new(from.1, to.1, 1000, 65535);
+            flags(&mut header);
+            let mut data = Vec::new();
+            header.write(&mut data).unwrap();
+            self.packet(from, to, data)
+        }
+
+        fn packet(&self, from: (Ipv4Addr, u16), to: (Ipv4Addr, u16), data: Vec<u8>) -> Vec<Event> {
+            let packet = QueuePacket {
+                ts: 0,
+                interface: 0,
+                protocol: 6,
+                source: from.0,
+                destination: to.0,
+                source6: None,
+                destination6: None,
+                vlans: Vec::new(),
+                flow_vlan: 0,
+                flow_tunnel: 0,
+                tunnels: Vec::new(),
+                payload_len: data.len() as u16,
+                buffer: PacketBuffer::from_vec(data),
+                payload_offset: 0,
+            };
+            handle(&self.config, &self.connections, packet, &self.dns_records, &self.hosts, &Registry::default(), &self.stats)
+        }
+
+        fn open(&self) -> bool {
+            !self.connections.lock().unwrap().is_empty()
+        }
+    }
+
+    #[test]
+    fn closes_after_both_fins() {
+        let connection = Connection::new();
+        let fin = |header: &mut TcpHeader| {
+            header.fin = true;
+            header.ack = true;
+        };
+        assert!(connection.segment(CLIENT, SERVER, fin).is_empty());
+        assert!(connection.open());
+        // A retransmitted FIN doesn't close the other direction
+        assert!(connection.segment(CLIENT, SERVER, fin).is_empty());
+        assert!(connection.open());
+
+        let events = connection.segment(SERVER, CLIENT, fin);
+        assert!(!connection.open());
+        match events.as_slice() {
+            [Event::FlowEnded(record)] => {
+                assert_eq!(record.client, CLIENT);
+                assert_eq!(record.server, SERVER);
+            }
+            other => panic!("expected the flow record, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn counts_a_cut_header_as_malformed() {
+        let connection = Connection::new();
+        assert!(connection.packet(CLIENT, SERVER, vec![0xc3, 0x50, 0x00]).is_empty());
+        assert_eq!(connection.stats.malformed.load(Ordering::Relaxed), 1);
+        assert!(connection.open());
+    }
+
+    #[test]
+    fn expires_on_the_capture_clock() {
+        let connection = Connection::new();
+        let events = Arc::new(Events::default());
+        for ctx in connection.connections.lock().unwrap().values_mut() {
+            ctx.last_ts = 10 * TCP_TIMEOUT;
+        }
+        // A clock stepping back keeps the connection
+        expire(&connection.connections, 0, &events, &connection.stats);
+        assert!(connection.open());
+        expire(&connection.connections, 11 * TCP_TIMEOUT - 1, &events, &connection.stats);
+        assert!(connection.open());
+        expire(&connection.connections, 11 * TCP_TIMEOUT, &events, &connection.stats);
+        assert!(!connection.open());
+    }
+
+    #[test]
+    fn quads_of_different_tunnels_differ() {
+        let quad = Quad { src: SERVER, dst: CLIENT, vlan: 0, tunnel: 100 };
+        let reversed = Quad { src: CLIENT, dst: SERVER, vlan: 0, tunnel: 100 };
+        let mut connections = HashMap::new();
+        connections.insert(quad, ());
+        assert!(connections.contains_key(&reversed));
+        assert!(!connections.contains_key(&Quad { tunnel: 200, ..quad }));
+    }
+
+    #[test]
+    fn closes_on_rst() {
+        let connection = Connection::new();
+        let events = connection.segment(CLIENT, SERVER, |header| header.rst = true);
+        assert!(!connection.open());
+        assert!(matches!(events.as_slice(), [Event::FlowEnded(_)]));
+    }
 }
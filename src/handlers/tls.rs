@@ -0,0 +1,128 @@
+use crate::{
+    dissector::{Dissector, Flow, FlowDissector},
+    events::{AppEvent, Event},
+};
+
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_ALPN: u16 = 0x0010;
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientHello {
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+}
+
+/// Parses a TLS ClientHello out of the first record of a TCP payload.
+/// A ClientHello split over several segments is parsed as far as the first one goes.
+pub fn parse_record(payload: &[u8]) -> Option<ClientHello> {
+    if payload.len() < 5 || payload[0] != HANDSHAKE_RECORD || payload[1] != 0x03 {
+        return None;
+    }
+    let record_len = read_u16(payload, 3)? as usize;
+    let end = (5 + record_len).min(payload.len());
+    parse_client_hello(&payload[5..end])
+}
+
+/// Parses a ClientHello handshake message (without the record layer), as carried
+/// by TLS records or QUIC CRYPTO frames
+pub fn parse_client_hello(handshake: &[u8]) -> Option<ClientHello> {
+    if *handshake.first()? != CLIENT_HELLO {
+        return None;
+    }
+    // type (1) + length (3) + legacy_version (2) + random (32)
+    let mut offset = 4 + 2 + 32;
+    // session id
+    offset += *handshake.get(offset)? as usize + 1;
+    // cipher suites
+    offset += read_u16(handshake, offset)? as usize + 2;
+    // compression methods
+    offset += *handshake.get(offset)? as usize + 1;
+
+    let mut hello = ClientHello::default();
+    let extensions_len = read_u16(handshake, offset)? as usize;
+    offset += 2;
+    let extensions_end = (offset + extensions_len).min(handshake.len());
+
+    while offset + 4 <= extensions_end {
+        let ext_type = read_u16(handshake, offset)?;
+        let ext_len = read_u16(handshake, offset + 2)? as usize;
+        offset += 4;
+        let ext = match handshake.get(offset..offset + ext_len) {
+            Some(ext) => ext,
+            None => break,
+        };
+        match ext_type {
+            EXT_SERVER_NAME => hello.sni = parse_server_name(ext),
+            EXT_ALPN => hello.alpn = parse_alpn(ext),
+            _ => (),
+        }
+        offset += ext_len;
+    }
+    Some(hello)
+}
+
+fn parse_server_name(ext: &[u8]) -> Option<String> {
+    // list length (2), name type (1), name length (2), name
+    if *ext.get(2)? != 0 {
+        return None;
+    }
+    let len = read_u16(ext, 3)? as usize;
+    let name = ext.get(5..5 + len)?;
+    Some(String::from_utf8_lossy(name).to_lowercase())
+}
+
+fn parse_alpn(ext: &[u8]) -> Vec<String> {
+    let mut protocols = Vec::new();
+    let mut offset = 2;
+    while let Some(&len) = ext.get(offset) {
+        let len = len as usize;
+        match ext.get(offset + 1..offset + 1 + len) {
+            Some(protocol) => protocols.push(String::from_utf8_lossy(protocol).to_string()),
+            None => break,
+        }
+        offset += len + 1;
+    }
+    protocols
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]))
+}
+
+/// Reports the ClientHello of the flows whose client starts with a TLS handshake record
+pub struct TlsDissector;
+
+/// The ClientHello is only looked for in the first client bytes
+#[derive(Default)]
+struct TlsFlow {
+    done: bool,
+}
+
+impl Dissector for TlsDissector {
+    fn name(&self) -> &'static str {
+        "tls"
+    }
+
+    fn probe(&self, _flow: &Flow, from_client: bool, payload: &[u8]) -> bool {
+        from_client && payload.len() >= 2 && payload[0] == HANDSHAKE_RECORD && payload[1] == 0x03
+    }
+
+    fn start(&self) -> Box<dyn FlowDissector> {
+        Box::new(TlsFlow::default())
+    }
+}
+
+impl FlowDissector for TlsFlow {
+    fn parse(&mut self, flow: &mut Flow, from_client: bool, data: &[u8], events: &mut Vec<Event>) {
+        if self.done || !from_client {
+            return;
+        }
+        self.done = true;
+        if let Some(hello) = parse_record(data) {
+            flow.sni = hello.sni;
+            events.push(Event::App(AppEvent::hello(flow.ts, "tls", flow.client, flow.server, &flow.sni)));
+        }
+    }
+}
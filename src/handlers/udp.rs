@@ -1,30 +1,199 @@
-use std::{collections::HashMap, sync::{Arc, Mutex, atomic::Ordering}};
+use std::{collections::HashMap, net::Ipv4Addr, sync::{Arc, Mutex, atomic::Ordering}};
 use etherparse::UdpHeader;
 
-use crate::{stats::Stats, utils::DnsRecord};
+use crate::{config::Config, dissector::{Flow, FlowDissector, Registry}, events::{Event, Events, FlowRecord}, hosts::{self, HostEntry}, stats::Stats, utils::{AppType, EncryptedDnsType, Tunnel}};
 use crate::utils::QueuePacket;
-use crate::handlers::dns;
+use crate::handlers::{encrypted_dns, icmp::IcmpError, tcp::Quad};
 
-pub fn handle(packet: QueuePacket, dns_records: &Arc<Mutex<HashMap<DnsRecord, String>>>, stats: &Arc<Stats>) {
+/// UDP flows are idle-expired after 60 seconds
+const UDP_TIMEOUT: u128 = 60000;
 
-    match UdpHeader::read_from_slice(&packet.payload[..]) {
-        Err(_) => todo!(),
+#[derive(Debug)]
+pub struct UdpContext {
+    pub src_ip: Ipv4Addr,
+    pub dst_ip: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub len: usize,
+    pub first_ts: u128,
+    pub last_ts: u128,
+    pub app_type: AppType,
+    pub encrypted_dns: Option<EncryptedDnsType>,
+    pub sni: Option<String>,
+    pub src_name: Option<String>,
+    pub dst_name: Option<String>,
+    /// Dissectors following the flow, chosen on its first datagram
+    pub dissectors: Vec<Box<dyn FlowDissector>>,
+    pub probed: bool,
+    pub icmp_error: Option<IcmpError>,
+    /// Outer tunnels (TEID, VNI, GRE key) the flow was carried in
+    pub tunnels: Vec<Tunnel>,
+    /// Capture sources the flow was seen on, both directions can come from different taps
+    pub interfaces: Vec<u16>,
+    /// Snapshot taken when the flow started, a reload doesn't change it
+    pub config: Arc<Config>,
+}
+
+/// Tracks the flow and feeds its datagrams to the dissectors, returns what they found
+pub fn handle(
+    config: &Arc<Config>,
+    connections: &Arc<Mutex<HashMap<Quad, UdpContext>>>,
+    packet: QueuePacket,
+    hosts: &Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
+    dissectors: &Registry,
+    stats: &Arc<Stats>,
+) -> Vec<Event> {
+    let mut events = Vec::new();
+    match UdpHeader::read_from_slice(packet.payload()) {
+        // Cut by the snaplen or a first fragment too short for the header
+        Err(_) => {
+            stats.malformed.fetch_add(1, Ordering::Relaxed);
+        }
         Ok((udp_header, udp_payload)) => {
             stats.udp.fetch_add(1, Ordering::Relaxed);
-            /*println!(
-                "[Thread:{}][UDP] {}:{} -> {}:{} | len: {}",
-                i,
-                u8_to_ipv4(ipv4_header.source),
-                udp_header.source_port,
-                u8_to_ipv4(ipv4_header.destination),
-                udp_header.destination_port,
-                ipv4_header.payload_len
-            );*/
-
-            //Check for DNS
-            if udp_header.source_port == 53 || udp_header.destination_port == 53 {
-                dns::handle(udp_payload, &dns_records, &stats);
+
+            let ts = packet.millis();
+            let mut mut_connections = connections.lock().unwrap();
+            let ctx = mut_connections
+                .entry(Quad {
+                    src: (packet.source, udp_header.source_port),
+                    dst: (packet.destination, udp_header.destination_port),
+                    vlan: packet.flow_vlan,
+                    tunnel: packet.flow_tunnel,
+                })
+                .or_insert_with(|| {
+                    let encrypted_dns_type = encrypted_dns::detect_udp(
+                        config,
+                        (packet.source, udp_header.source_port),
+                        (packet.destination, udp_header.destination_port),
+                    );
+                    if let Some(dns_type) = &encrypted_dns_type {
+                        encrypted_dns::count(stats, dns_type);
+                    }
+                    UdpContext {
+                        src_ip: packet.source,
+                        dst_ip: packet.destination,
+                        src_port: udp_header.source_port,
+                        dst_port: udp_header.destination_port,
+                        len: 0,
+                        first_ts: ts,
+                        last_ts: ts,
+                        app_type: AppType::NONE,
+                        encrypted_dns: encrypted_dns_type,
+                        sni: None,
+                        src_name: hosts::lookup(hosts, &packet.source),
+                        dst_name: hosts::lookup(hosts, &packet.destination),
+                        dissectors: Vec::new(),
+                        probed: false,
+                        icmp_error: None,
+                        tunnels: packet.tunnels.clone(),
+                        interfaces: vec![packet.interface],
+                        config: config.clone(),
+                    }
+                });
+            let started = ctx.len == 0;
+            ctx.len += 1;
+            ctx.last_ts = ts;
+            if !ctx.interfaces.contains(&packet.interface) {
+                ctx.interfaces.push(packet.interface);
+            }
+            if started {
+                events.push(Event::FlowStarted(record(ctx, packet.flow_vlan)));
+            }
+
+            // The first packet decides who the client is
+            let from_client = packet.source == ctx.src_ip && udp_header.source_port == ctx.src_port;
+            let mut flow = flow(ctx);
+            if !ctx.probed {
+                ctx.probed = true;
+                ctx.dissectors = dissectors.start(&flow, from_client, udp_payload);
+            }
+            for dissector in ctx.dissectors.iter_mut() {
+                dissector.parse(&mut flow, from_client, udp_payload, &mut events);
+            }
+            let learned_sni = flow.sni.is_some() && ctx.sni.is_none();
+            if flow.app_type != ctx.app_type || learned_sni {
+                ctx.app_type = flow.app_type;
+                if learned_sni {
+                    ctx.sni = flow.sni;
+                }
+                events.push(Event::FlowUpdated(record(ctx, packet.flow_vlan)));
+            }
+            if learned_sni && ctx.encrypted_dns.is_none() {
+                if let Some(sni) = &ctx.sni {
+                    ctx.encrypted_dns = encrypted_dns::detect_sni(&ctx.config, sni);
+                    if let Some(dns_type) = &ctx.encrypted_dns {
+                        encrypted_dns::count(stats, dns_type);
+                    }
+                }
             }
         },
     }
-}
\ No newline at end of file
+    events
+}
+
+/// Drops the UDP flows that have been idle for longer than `UDP_TIMEOUT` at `ts`, the capture clock of the worker
+pub fn expire(connections: &Arc<Mutex<HashMap<Quad, UdpContext>>>, ts: u128, events: &Arc<Events>) {
+    let mut mut_connections = connections.lock().unwrap();
+    mut_connections.retain(|quad, ctx| {
+        // A clock stepping back keeps the flow
+        if ts.saturating_sub(ctx.last_ts) < UDP_TIMEOUT {
+            return true;
+        }
+        close(quad, ctx, events);
+        false
+    });
+}
+
+/// Drops every flow once the input has ended, returns how many there were
+pub fn flush(connections: &Arc<Mutex<HashMap<Quad, UdpContext>>>, events: &Arc<Events>) -> usize {
+    let mut mut_connections = connections.lock().unwrap();
+    let count = mut_connections.len();
+    for (quad, mut ctx) in mut_connections.drain() {
+        close(&quad, &mut ctx, events);
+    }
+    count
+}
+
+/// Lets the dissectors log what they still hold and emits the flow record
+fn close(quad: &Quad, ctx: &mut UdpContext, events: &Arc<Events>) {
+    let mut found = Vec::new();
+    let mut flow = flow(ctx);
+    for dissector in ctx.dissectors.iter_mut() {
+        dissector.close(&mut flow, &mut found);
+    }
+    found.push(Event::FlowEnded(record(ctx, quad.vlan)));
+    for event in found {
+        events.emit(event);
+    }
+}
+
+fn flow(ctx: &UdpContext) -> Flow {
+    Flow {
+        protocol: 17,
+        client: (ctx.src_ip, ctx.src_port),
+        server: (ctx.dst_ip, ctx.dst_port),
+        app_type: ctx.app_type,
+        sni: ctx.sni.clone(),
+        ts: ctx.last_ts,
+    }
+}
+
+fn record(ctx: &UdpContext, vlan: u16) -> FlowRecord {
+    FlowRecord {
+        protocol: 17,
+        client: (ctx.src_ip, ctx.src_port),
+        server: (ctx.dst_ip, ctx.dst_port),
+        vlan,
+        first_ts: ctx.first_ts,
+        last_ts: ctx.last_ts,
+        packets: ctx.len,
+        app_type: ctx.app_type,
+        sni: ctx.sni.clone(),
+        client_name: ctx.src_name.clone(),
+        server_name: ctx.dst_name.clone(),
+        icmp_error: ctx.icmp_error.clone(),
+        interfaces: ctx.interfaces.clone(),
+        tunnels: ctx.tunnels.clone(),
+    }
+}
@@ -0,0 +1,32 @@
+use crate::{
+    dissector::{Dissector, Flow, FlowDissector},
+    events::Event,
+    utils::AppType,
+};
+
+/// First bytes of the WhatsApp client handshake ("ED" 0 1)
+const HANDSHAKE: [u8; 4] = [69, 68, 0, 1];
+
+/// Classifies the flows starting with the WhatsApp handshake
+#[derive(Clone)]
+pub struct WhatsappDissector;
+
+impl Dissector for WhatsappDissector {
+    fn name(&self) -> &'static str {
+        "whatsapp"
+    }
+
+    fn probe(&self, _flow: &Flow, _from_client: bool, payload: &[u8]) -> bool {
+        payload.starts_with(&HANDSHAKE)
+    }
+
+    fn start(&self) -> Box<dyn FlowDissector> {
+        Box::new(self.clone())
+    }
+}
+
+impl FlowDissector for WhatsappDissector {
+    fn parse(&mut self, flow: &mut Flow, _from_client: bool, _data: &[u8], _events: &mut Vec<Event>) {
+        flow.app_type = AppType::WHATSAPP;
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameSource {
+    MDNS,
+    LLMNR,
+    NBNS,
+}
+
+impl NameSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NameSource::MDNS => "mdns",
+            NameSource::LLMNR => "llmnr",
+            NameSource::NBNS => "nbns",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HostEntry {
+    pub ip: Ipv4Addr,
+    pub names: Vec<String>,
+    pub services: Vec<String>,
+    pub sources: Vec<NameSource>,
+    pub first_ts: u128,
+    pub last_ts: u128,
+}
+
+impl HostEntry {
+    pub fn new(ip: Ipv4Addr, ts: u128) -> HostEntry {
+        HostEntry {
+            ip,
+            names: Vec::new(),
+            services: Vec::new(),
+            sources: Vec::new(),
+            first_ts: ts,
+            last_ts: ts,
+        }
+    }
+
+    /// Returns the first name announced for this host, which is what flows get labelled with
+    pub fn name(&self) -> Option<&String> {
+        self.names.first()
+    }
+}
+
+fn touch(hosts: &mut HashMap<Ipv4Addr, HostEntry>, ip: Ipv4Addr, source: NameSource, ts: u128) -> &mut HostEntry {
+    let entry = hosts.entry(ip).or_insert_with(|| HostEntry::new(ip, ts));
+    entry.last_ts = ts;
+    if !entry.sources.contains(&source) {
+        entry.sources.push(source);
+    }
+    entry
+}
+
+/// `ts` is the capture time in milliseconds of the announcement
+pub fn add_name(hosts: &mut HashMap<Ipv4Addr, HostEntry>, ip: Ipv4Addr, name: &str, source: NameSource, ts: u128) {
+    if ip.is_unspecified() || name.is_empty() {
+        return;
+    }
+    let entry = touch(hosts, ip, source, ts);
+    if !entry.names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+        entry.names.push(name.to_string());
+    }
+}
+
+pub fn add_service(hosts: &mut HashMap<Ipv4Addr, HostEntry>, ip: Ipv4Addr, service: &str, source: NameSource, ts: u128) {
+    if ip.is_unspecified() || service.is_empty() {
+        return;
+    }
+    let entry = touch(hosts, ip, source, ts);
+    if !entry.services.iter().any(|s| s == service) {
+        entry.services.push(service.to_string());
+    }
+}
+
+/// Returns the harvested name of a host, if it announced one
+pub fn lookup(hosts: &Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>, ip: &Ipv4Addr) -> Option<String> {
+    let hosts = hosts.lock().unwrap();
+    hosts.get(ip).and_then(|entry| entry.name().cloned())
+}
+
+/// Writes the host table as CSV: ip,names,services,sources,first_ts,last_ts
+pub fn export(hosts: &HashMap<Ipv4Addr, HostEntry>, out: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(out, "ip,names,services,sources,first_ts,last_ts")?;
+    let mut entries: Vec<&HostEntry> = hosts.values().collect();
+    entries.sort_by_key(|entry| u32::from(entry.ip));
+    for entry in entries {
+        let sources: Vec<&str> = entry.sources.iter().map(|s| s.as_str()).collect();
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            entry.ip,
+            csv_field(&entry.names.join(";")),
+            csv_field(&entry.services.join(";")),
+            sources.join(";"),
+            entry.first_ts,
+            entry.last_ts
+        )?;
+    }
+    Ok(())
+}
+
+pub fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -1,124 +1,1007 @@
-use std::{collections::HashMap, net::Ipv4Addr, sync::{Arc, Mutex, atomic::Ordering, mpsc::Sender}, thread::{self, JoinHandle}};
+use std::{borrow::Cow, collections::HashMap, fs, path::Path, net::{IpAddr, Ipv4Addr, Ipv6Addr}, sync::{Arc, Mutex, atomic::Ordering}, thread::{self, JoinHandle}, time::{Duration, Instant}};
 
-use etherparse::{Ethernet2Header, IpHeader, Ipv4Header};
-use pcap::{Capture};
+use etherparse::{Ethernet2Header, IpHeader, Ipv4Header, Ipv6Header};
+use pcap::{Activated, Active, Capture, Linktype, Offline, Packet, PacketHeader};
 
 use crate::{
-    config::Config,
+    config::{Config, Mode},
+    events::Events,
+    handlers::{arp::{self, ETHERTYPE_ARP}, fragments::{Fragment, Fragments}},
+    inventory::ArpEntry,
+    pool::{BufferPool, PacketBuffer},
+    queue::Producer,
+    shutdown::Shutdown,
     stats::Stats,
-    utils::{DnsRecord, QueuePacket},
+    utils::{ProtocolType, QueuePacket, Tunnel},
 };
 
+/// Names of the capture sources, a packet's `interface` is its index in this list
+pub fn sources(config: &Config) -> Vec<String> {
+    if config.general.mode != Mode::INTERFACE {
+        // The files are read as one capture
+        match files(config).as_slice() {
+            [file] => vec![file.clone()],
+            files => vec![format!("{} files", files.len())],
+        }
+    } else if !config.general.interfaces.is_empty() {
+        config.general.interfaces.clone()
+    } else if config.general.interface.is_empty() {
+        vec![default_device().unwrap_or_default()]
+    } else {
+        vec![config.general.interface.clone()]
+    }
+}
+
+/// The device libpcap would pick, used when no interface is configured
+pub fn default_device() -> Result<String, pcap::Error> {
+    pcap::Device::lookup().map(|device| device.name)
+}
+
+/// Starts one capture thread per source, all feeding the same worker queues.
+/// Every source is opened before the first thread starts
 pub fn run(
     config: &Config,
-    queue: &Sender<QueuePacket>,
-    dns_records: &Arc<Mutex<HashMap<DnsRecord, String>>>,
+    queues: &[Producer],
+    pool: &Arc<BufferPool>,
+    arp_table: &Arc<Mutex<HashMap<Ipv4Addr, ArpEntry>>>,
+    events: &Arc<Events>,
     stats: &Arc<Stats>,
-) -> JoinHandle<()> {
-    if config.general.mode == "interface" {
-        run_interface(config, queue, dns_records, stats)
+    shutdown: &Shutdown,
+) -> Result<Vec<JoinHandle<()>>, String> {
+    if config.general.mode == Mode::INTERFACE {
+        let caps = sources(config)
+            .iter()
+            .map(|name| open_interface(config, name))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(caps
+            .into_iter()
+            .enumerate()
+            .map(|(index, cap)| {
+                let mut decoder = Decoder::new(config, index as u16, queues, pool, arp_table, events, stats);
+                decoder.set_link_type(cap.get_datalink());
+                run_interface(cap, decoder, shutdown)
+            })
+            .collect())
     } else {
-        run_file(config, queue, dns_records, stats)
+        let inputs = open_files(config)?;
+        let decoder = Decoder::new(config, 0, queues, pool, arp_table, events, stats);
+        Ok(vec![run_files(inputs, decoder, shutdown)])
     }
 }
 
-fn run_interface(
-    config: &Config,
-    queue: &Sender<QueuePacket>,
-    dns_records: &Arc<Mutex<HashMap<DnsRecord, String>>>,
-    stats: &Arc<Stats>,
-) -> JoinHandle<()> {
-    let mut cap = Capture::from_device(config.general.interface.as_str())
-        .unwrap()
-        .immediate_mode(true)
-        .open()
-        .unwrap();
-    let queue = queue.clone();
-    let stats = stats.clone();
-    let _dns_records = dns_records.clone();
+fn open_interface(config: &Config, name: &str) -> Result<Capture<Active>, String> {
+    let general = &config.general;
+    let mut inactive = match Capture::from_device(name) {
+        Ok(inactive) => inactive
+            .immediate_mode(true)
+            .promisc(general.promiscuous)
+            .snaplen(general.snaplen)
+            .timeout(general.timeout.as_millis() as i32),
+        Err(e) => return Err(format!("Couldn't find interface {}: {}", name, e)),
+    };
+    if general.buffer_size > 0 {
+        inactive = inactive.buffer_size(general.buffer_size as i32);
+    }
+    let mut cap = inactive.open().map_err(|e| format!("Couldn't open interface {}: {}", name, e))?;
+    apply_filter(&mut cap, &general.filter)?;
+    Ok(cap)
+}
+
+fn run_interface(mut cap: Capture<Active>, mut decoder: Decoder, shutdown: &Shutdown) -> JoinHandle<()> {
+    let shutdown = shutdown.clone();
 
     thread::spawn(move || {
-        while let Ok(packet) = cap.next() {
-            // Parse the Ethernet and IP header
-            match Ethernet2Header::read_from_slice(packet.data) {
-                Err(value) => println!("Err {:?}", value),
-                Ok((_, eth_payload)) => match IpHeader::read_from_slice(eth_payload) {
-                    Err(_) => (),
-                    Ok((ip_header, _)) => match ip_header {
-                        IpHeader::Version4(_) => {
-                            stats.ipv4.fetch_add(1, Ordering::Relaxed);
-                            match Ipv4Header::read_from_slice(eth_payload) {
-                                Err(value) => println!("Err {:?}", value),
-                                Ok((ipv4_header, payload)) => {
-                                    // Push to the queue
-                                    match queue.send(QueuePacket {
-                                        protocol: ipv4_header.protocol,
-                                        source: Ipv4Addr::from(ipv4_header.source),
-                                        destination: Ipv4Addr::from(ipv4_header.destination),
-                                        payload_len: ipv4_header.payload_len,
-                                        payload: payload.to_vec(),
-                                    }) {
-                                        Ok(_) => (),
-                                        Err(err) => println!("{}", err),
-                                    }
-                                }
-                            }
-                        }
-                        IpHeader::Version6(_) => {
-                            stats.udp.fetch_add(1, Ordering::Relaxed);
-                        }
-                    },
-                },
+        let index = decoder.interface as usize;
+        let mut last_poll = Instant::now();
+        while !shutdown.requested() {
+            match cap.next() {
+                Ok(packet) => decoder.handle_packet(&packet),
+                // Nothing arrived within the read timeout
+                Err(pcap::Error::TimeoutExpired) => (),
+                Err(_) => break,
+            }
+            if last_poll.elapsed() >= Duration::from_secs(1) {
+                last_poll = Instant::now();
+                if let Ok(pcap_stats) = cap.stats() {
+                    let dropped = pcap_stats.dropped as usize + pcap_stats.if_dropped as usize;
+                    decoder.stats.interfaces[index].dropped.store(dropped, Ordering::Relaxed);
+                }
             }
         }
     })
 }
 
-fn run_file(
-    config: &Config,
-    queue: &Sender<QueuePacket>,
-    dns_records: &Arc<Mutex<HashMap<DnsRecord, String>>>,
-    stats: &Arc<Stats>,
-) -> JoinHandle<()> {
-    let mut cap = Capture::from_file(config.general.file.as_str()).unwrap();
+/// An offline capture and the packet it will hand out next
+struct Input {
+    path: String,
+    cap: Capture<Offline>,
+    link_type: Linktype,
+    next: Option<PacketHeader>,
+    /// Bytes of the next packet, reused from one packet to the next
+    data: Vec<u8>,
+}
 
-    let queue = queue.clone();
-    let stats = stats.clone();
-    let _dns_records = dns_records.clone();
+impl Input {
+    fn advance(&mut self) {
+        self.data.clear();
+        self.next = match self.cap.next() {
+            Ok(packet) => {
+                self.data.extend_from_slice(packet.data);
+                Some(*packet.header)
+            }
+            Err(_) => None,
+        };
+    }
+
+    fn next_ts(&self) -> Option<u128> {
+        self.next.as_ref().map(|header| header.ts.tv_sec as u128 * 1_000_000 + header.ts.tv_usec as u128)
+    }
+}
+
+fn open_files(config: &Config) -> Result<Vec<Input>, String> {
+    let paths = files(config);
+    if paths.is_empty() {
+        return Err("No capture file to read, check general.file and general.files".to_string());
+    }
+    paths
+        .into_iter()
+        .map(|path| {
+            let mut cap = Capture::from_file(path.as_str())
+                .map_err(|e| format!("Couldn't open capture file {}: {}", path, e))?;
+            apply_filter(&mut cap, &config.general.filter)?;
+            let link_type = cap.get_datalink();
+            Ok(Input { path, cap, link_type, next: None, data: Vec::new() })
+        })
+        .collect()
+}
+
+/// Reads every capture file as one continuous capture, merging them by timestamp.
+/// The thread ends with the input, which lets the pipeline drain
+fn run_files(mut inputs: Vec<Input>, mut decoder: Decoder, shutdown: &Shutdown) -> JoinHandle<()> {
+    let shutdown = shutdown.clone();
 
     thread::spawn(move || {
-        while let Ok(packet) = cap.next() {
-            // Parse the Ethernet and IP header
-            match Ethernet2Header::read_from_slice(packet.data) {
-                Err(value) => println!("Err {:?}", value),
-                Ok((_, eth_payload)) => match IpHeader::read_from_slice(eth_payload) {
-                    Err(_) => (),
-                    Ok((ip_header, _)) => match ip_header {
-                        IpHeader::Version4(_) => {
-                            stats.ipv4.fetch_add(1, Ordering::Relaxed);
-                            match Ipv4Header::read_from_slice(eth_payload) {
-                                Err(value) => println!("Err {:?}", value),
-                                Ok((ipv4_header, payload)) => {
-                                    // Push to the queue
-                                    match queue.send(QueuePacket {
-                                        protocol: ipv4_header.protocol,
-                                        source: Ipv4Addr::from(ipv4_header.source),
-                                        destination: Ipv4Addr::from(ipv4_header.destination),
-                                        payload_len: ipv4_header.payload_len,
-                                        payload: payload.to_vec(),
+        let start = Instant::now();
+        let mut packets: usize = 0;
+        for input in inputs.iter_mut() {
+            input.advance();
+        }
+        while !shutdown.requested() {
+            // Oldest pending packet first, ties go to the file listed first
+            let index = match (0..inputs.len())
+                .filter_map(|index| inputs[index].next_ts().map(|ts| (ts, index)))
+                .min()
+            {
+                Some((_, index)) => index,
+                None => break,
+            };
+            let input = &mut inputs[index];
+            if let Some(header) = input.next.take() {
+                decoder.set_link_type(input.link_type);
+                decoder.handle_packet(&Packet::new(&header, &input.data));
+                packets += 1;
+            }
+            input.advance();
+        }
+        let paths: Vec<&str> = inputs.iter().map(|input| input.path.as_str()).collect();
+        log::info!(
+            "Read {} packets from {} file(s) in {:.1}s: {}",
+            packets,
+            paths.len(),
+            start.elapsed().as_secs_f64(),
+            paths.join(", ")
+        );
+    })
+}
+
+/// Expands `general.files` (files, directories and `*`/`?` patterns in the file name)
+/// in order, falling back on `general.file`
+pub fn files(config: &Config) -> Vec<String> {
+    if config.general.files.is_empty() {
+        return vec![config.general.file.clone()];
+    }
+    let mut paths = Vec::new();
+    for entry in config.general.files.iter() {
+        let path = Path::new(entry);
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        if path.is_dir() {
+            paths.extend(list_dir(path, |name| {
+                name.ends_with(".pcap") || name.ends_with(".pcapng") || name.ends_with(".cap")
+            }));
+        } else if name.contains('*') || name.contains('?') {
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            paths.extend(list_dir(parent, |file_name| wildcard(name.as_bytes(), file_name.as_bytes())));
+        } else {
+            paths.push(entry.clone());
+        }
+    }
+    paths
+}
+
+/// Files of a directory whose name matches, sorted by name
+fn list_dir(directory: &Path, matches: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut paths: Vec<String> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.file_name().and_then(|name| name.to_str()).is_some_and(&matches))
+            .filter_map(|path| path.to_str().map(|path| path.to_string()))
+            .collect(),
+        Err(e) => {
+            log::warn!("Couldn't list {:?}: {}", directory, e);
+            Vec::new()
+        }
+    };
+    paths.sort();
+    paths
+}
+
+/// Shell style matching of `*` and `?`
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => wildcard(&pattern[1..], name) || (!name.is_empty() && wildcard(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => wildcard(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Compiles the capture filter against a dead Ethernet handle so a typo is reported
+/// before any capture is opened
+pub fn check_filter(config: &Config) -> Result<(), pcap::Error> {
+    if config.general.filter.is_empty() {
+        return Ok(());
+    }
+    let dead = Capture::dead(Linktype(DLT_EN10MB))?;
+    dead.compile(&config.general.filter, true).map(|_| ())
+}
+
+fn apply_filter<T: Activated + ?Sized>(cap: &mut Capture<T>, filter: &str) -> Result<(), String> {
+    if filter.is_empty() {
+        return Ok(());
+    }
+    cap.filter(filter, true).map_err(|e| format!("Invalid capture filter \"{}\": {}", filter, e))
+}
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const ETHERTYPE_QINQ_OLD: u16 = 0x9100;
+const ETHERTYPE_MPLS: u16 = 0x8847;
+const ETHERTYPE_MPLS_MULTICAST: u16 = 0x8848;
+/// Transparent Ethernet bridging, Ethernet carried by GRE or GENEVE
+const ETHERTYPE_TEB: u16 = 0x6558;
+const ETHERTYPE_ERSPAN_II: u16 = 0x88be;
+const ETHERTYPE_ERSPAN_III: u16 = 0x22eb;
+
+const IPPROTO_IPIP: u8 = 4;
+const IPPROTO_IPV6: u8 = 41;
+const IPPROTO_GRE: u8 = 47;
+
+const VXLAN_PORT: u16 = 4789;
+const GENEVE_PORT: u16 = 6081;
+const GTPU_PORT: u16 = 2152;
+
+const DLT_NULL: i32 = 0;
+const DLT_EN10MB: i32 = 1;
+const DLT_RAW: i32 = 12;
+const LINKTYPE_RAW: i32 = 101;
+const DLT_IEEE802_11: i32 = 105;
+const DLT_LOOP: i32 = 108;
+const DLT_LINUX_SLL: i32 = 113;
+const DLT_IEEE802_11_RADIO: i32 = 127;
+const DLT_IPV4: i32 = 228;
+const DLT_IPV6: i32 = 229;
+const DLT_LINUX_SLL2: i32 = 276;
+
+/// Tunnels inside tunnels are given up on past this depth
+const MAX_DEPTH: usize = 8;
+
+/// What the outer layers told us about the packet being decoded
+#[derive(Debug, Default)]
+struct Frame<'a> {
+    ts: u128,
+    /// Captured bytes, copied to a pooled buffer along with the packet
+    data: &'a [u8],
+    vlans: Vec<u16>,
+    tunnels: Vec<Tunnel>,
+}
+
+/// Link and network layer decoder of one capture thread, feeds the worker queues
+struct Decoder {
+    /// Index of the capture source
+    interface: u16,
+    link_type: i32,
+    /// Also applied to offline captures, which pcap can't truncate
+    snaplen: usize,
+    /// One per worker, a flow always goes to the same one
+    queues: Vec<Producer>,
+    pool: Arc<BufferPool>,
+    arp_table: Option<Arc<Mutex<HashMap<Ipv4Addr, ArpEntry>>>>,
+    /// Receives the ARP alerts
+    events: Arc<Events>,
+    vlan_flow_key: bool,
+    tunnel_flow_key: bool,
+    fragments: Option<Fragments>,
+    stats: Arc<Stats>,
+}
+
+impl Decoder {
+    /// `set_link_type` must be called before the first packet
+    fn new(
+        config: &Config,
+        interface: u16,
+        queues: &[Producer],
+        pool: &Arc<BufferPool>,
+        arp_table: &Arc<Mutex<HashMap<Ipv4Addr, ArpEntry>>>,
+        events: &Arc<Events>,
+        stats: &Arc<Stats>,
+    ) -> Decoder {
+        Decoder {
+            interface,
+            link_type: -1,
+            // libpcap reads 0 as its default snapshot length
+            snaplen: if config.general.snaplen > 0 { config.general.snaplen as usize } else { usize::MAX },
+            queues: queues.to_vec(),
+            pool: pool.clone(),
+            arp_table: if config.arp.enabled { Some(arp_table.clone()) } else { None },
+            events: events.clone(),
+            vlan_flow_key: config.capture.vlan_flow_key,
+            tunnel_flow_key: config.capture.tunnel_flow_key,
+            fragments: if config.fragments.enabled { Some(Fragments::new(config)) } else { None },
+            stats: stats.clone(),
+        }
+    }
+
+    /// Offline captures can switch link type from one file to the next
+    fn set_link_type(&mut self, link_type: Linktype) {
+        if link_type.0 == self.link_type {
+            return;
+        }
+        match link_type.0 {
+            DLT_NULL | DLT_EN10MB | DLT_RAW | LINKTYPE_RAW | DLT_IEEE802_11 | DLT_LOOP | DLT_LINUX_SLL
+            | DLT_IEEE802_11_RADIO | DLT_IPV4 | DLT_IPV6 | DLT_LINUX_SLL2 => (),
+            _ => log::warn!("Unsupported link type {:?}, decoding as Ethernet", link_type.get_name()),
+        }
+        self.link_type = link_type.0;
+    }
+
+    fn handle_packet(&mut self, packet: &Packet) {
+        let data = &packet.data[..packet.data.len().min(self.snaplen)];
+        let mut frame = Frame {
+            ts: packet.header.ts.tv_sec as u128 * 1_000_000 + packet.header.ts.tv_usec as u128,
+            data,
+            ..Default::default()
+        };
+        if let Some(interface) = self.stats.interfaces.get(self.interface as usize) {
+            interface.packets.fetch_add(1, Ordering::Relaxed);
+            interface.bytes.fetch_add(packet.header.len as usize, Ordering::Relaxed);
+        }
+        match self.link_type {
+            DLT_RAW | LINKTYPE_RAW | DLT_IPV4 | DLT_IPV6 => self.ip(&mut frame, data),
+            DLT_NULL | DLT_LOOP => {
+                if let Some(ether_type) = loopback(self.link_type, data) {
+                    self.dispatch(&mut frame, ether_type, &data[4..]);
+                }
+            }
+            DLT_LINUX_SLL | DLT_LINUX_SLL2 => {
+                let (protocol, payload) = match sll(self.link_type, data) {
+                    Some(value) => value,
+                    None => return,
+                };
+                if let Some((ether_type, payload)) = decapsulate(protocol, payload, &mut frame.vlans) {
+                    self.dispatch(&mut frame, ether_type, payload);
+                }
+            }
+            DLT_IEEE802_11_RADIO | DLT_IEEE802_11 => {
+                let frame_data = if self.link_type == DLT_IEEE802_11_RADIO {
+                    match radiotap(data) {
+                        Some(value) => value,
+                        None => return,
+                    }
+                } else {
+                    data
+                };
+                if let Some((ether_type, payload)) = ieee802_11(frame_data) {
+                    self.dispatch(&mut frame, ether_type, payload);
+                }
+            }
+            _ => self.ethernet(&mut frame, data),
+        }
+    }
+
+    fn ethernet(&mut self, frame: &mut Frame, data: &[u8]) {
+        match Ethernet2Header::read_from_slice(data) {
+            Err(value) => log::debug!("Err {:?}", value),
+            Ok((eth_header, eth_payload)) => {
+                if let Some((ether_type, payload)) = decapsulate(eth_header.ether_type, eth_payload, &mut frame.vlans) {
+                    self.dispatch(frame, ether_type, payload);
+                }
+            }
+        }
+    }
+
+    /// Hands the payload to the decoder of its ethertype, ARP is handled right away
+    /// since it never reaches the flow handlers
+    fn dispatch(&mut self, frame: &mut Frame, ether_type: u16, payload: &[u8]) {
+        match ether_type {
+            ETHERTYPE_ARP => {
+                if let Some(arp_table) = &self.arp_table {
+                    let mut alerts = Vec::new();
+                    arp::handle(arp_table, frame.ts / 1000, payload, &mut alerts, &self.stats);
+                    for alert in alerts {
+                        self.events.emit(alert);
+                    }
+                }
+            }
+            ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => self.ip(frame, payload),
+            _ => (),
+        }
+    }
+
+    /// Parses the IP header and pushes the packet to the queue, unless it is a tunnel
+    fn ip(&mut self, frame: &mut Frame, ip_payload: &[u8]) {
+        let outer = frame.tunnels.is_empty();
+        match IpHeader::read_from_slice(ip_payload) {
+            Err(_) => (),
+            Ok((ip_header, _)) => match ip_header {
+                IpHeader::Version4(_) => {
+                    if outer {
+                        self.stats.ipv4.fetch_add(1, Ordering::Relaxed);
+                    }
+                    match Ipv4Header::read_from_slice(ip_payload) {
+                        Err(value) => log::debug!("Err {:?}", value),
+                        Ok((ipv4_header, payload)) => {
+                            // Ethernet pads short frames, only trust the IP length
+                            let payload = &payload[..payload.len().min(ipv4_header.payload_len as usize)];
+                            let payload = if ipv4_header.more_fragments || ipv4_header.fragments_offset != 0 {
+                                match self.reassemble(Fragment {
+                                    ts: frame.ts,
+                                    source: IpAddr::V4(Ipv4Addr::from(ipv4_header.source)),
+                                    destination: IpAddr::V4(Ipv4Addr::from(ipv4_header.destination)),
+                                    protocol: ipv4_header.protocol,
+                                    id: ipv4_header.identification as u32,
+                                    offset: ipv4_header.fragments_offset as usize * 8,
+                                    more: ipv4_header.more_fragments,
+                                    next_header: None,
+                                    payload,
+                                }) {
+                                    Some((_, data)) => Cow::Owned(data),
+                                    None => return,
+                                }
+                            } else {
+                                Cow::Borrowed(payload)
+                            };
+                            if self.tunnel(frame, ipv4_header.protocol, &payload) {
+                                return;
+                            }
+                            let (buffer, payload_offset) = self.buffer(frame, &payload);
+                            self.push(frame, QueuePacket {
+                                ts: frame.ts,
+                                interface: 0,
+                                protocol: ipv4_header.protocol,
+                                source: Ipv4Addr::from(ipv4_header.source),
+                                destination: Ipv4Addr::from(ipv4_header.destination),
+                                source6: None,
+                                destination6: None,
+                                vlans: Vec::new(),
+                                flow_vlan: 0,
+                                flow_tunnel: 0,
+                                tunnels: Vec::new(),
+                                payload_len: payload.len() as u16,
+                                buffer,
+                                payload_offset,
+                            });
+                        }
+                    }
+                }
+                IpHeader::Version6(_) => {
+                    if outer {
+                        self.stats.ipv6.fetch_add(1, Ordering::Relaxed);
+                    }
+                    match Ipv6Header::read_from_slice(ip_payload) {
+                        Err(value) => log::debug!("Err {:?}", value),
+                        Ok((ipv6_header, payload)) => {
+                            let payload = &payload[..payload.len().min(ipv6_header.payload_length as usize)];
+                            let (protocol, payload, fragment) = match ipv6_extensions(ipv6_header.next_header, payload) {
+                                Some(value) => value,
+                                None => return,
+                            };
+                            let (protocol, payload) = match fragment {
+                                Some((id, offset, more)) => {
+                                    let (next_header, data) = match self.reassemble(Fragment {
+                                        ts: frame.ts,
+                                        source: IpAddr::V6(Ipv6Addr::from(ipv6_header.source)),
+                                        destination: IpAddr::V6(Ipv6Addr::from(ipv6_header.destination)),
+                                        protocol: 0,
+                                        id,
+                                        offset,
+                                        more,
+                                        next_header: Some(protocol),
+                                        payload,
                                     }) {
-                                        Ok(_) => (),
-                                        Err(err) => println!("{}", err),
+                                        Some(value) => value,
+                                        None => return,
+                                    };
+                                    // Extension headers after the fragment header are part of the reassembled data
+                                    match ipv6_extensions(next_header.unwrap_or(protocol), &data) {
+                                        Some((protocol, payload, None)) => (protocol, Cow::Owned(payload.to_vec())),
+                                        _ => return,
                                     }
                                 }
+                                None => (protocol, Cow::Borrowed(payload)),
+                            };
+                            if self.tunnel(frame, protocol, &payload) {
+                                return;
                             }
+                            // The flow handlers are IPv4 only, ICMPv6 is the only IPv6 traffic we follow
+                            if protocol != ProtocolType::ICMPV6 as u8 {
+                                return;
+                            }
+                            let (buffer, payload_offset) = self.buffer(frame, &payload);
+                            self.push(frame, QueuePacket {
+                                ts: frame.ts,
+                                interface: 0,
+                                protocol,
+                                source: Ipv4Addr::UNSPECIFIED,
+                                destination: Ipv4Addr::UNSPECIFIED,
+                                source6: Some(Ipv6Addr::from(ipv6_header.source)),
+                                destination6: Some(Ipv6Addr::from(ipv6_header.destination)),
+                                vlans: Vec::new(),
+                                flow_vlan: 0,
+                                flow_tunnel: 0,
+                                tunnels: Vec::new(),
+                                payload_len: payload.len() as u16,
+                                buffer,
+                                payload_offset,
+                            });
                         }
-                        IpHeader::Version6(_) => {
-                            stats.udp.fetch_add(1, Ordering::Relaxed);
-                        }
-                    },
-                },
+                    }
+                }
+            },
+        }
+    }
+
+    /// Feeds a fragment to the reassembly stage, without it only first fragments go through
+    /// since the others have no transport header
+    fn reassemble(&mut self, fragment: Fragment) -> Option<(Option<u8>, Vec<u8>)> {
+        match &mut self.fragments {
+            Some(fragments) => fragments.push(fragment, &self.stats),
+            None if fragment.offset == 0 => Some((fragment.next_header, fragment.payload.to_vec())),
+            None => None,
+        }
+    }
+
+    /// Copies the frame up to the end of the payload to a pooled buffer, returns it with
+    /// the offset of the payload. Reassembled payloads are copied alone
+    fn buffer(&self, frame: &Frame, payload: &[u8]) -> (PacketBuffer, usize) {
+        match offset_in(frame.data, payload) {
+            Some(payload_offset) => (self.pool.take(&frame.data[..payload_offset + payload.len()]), payload_offset),
+            None => (self.pool.take(payload), 0),
+        }
+    }
+
+    /// Fills in what the outer layers recorded and pushes the packet to the queue of its worker
+    fn push(&self, frame: &mut Frame, mut packet: QueuePacket) {
+        packet.interface = self.interface;
+        // The innermost tag is the one that separates the address spaces
+        if self.vlan_flow_key {
+            packet.flow_vlan = frame.vlans.last().copied().unwrap_or(0);
+        }
+        if self.tunnel_flow_key {
+            packet.flow_tunnel = frame.tunnels.last().map_or(0, Tunnel::id);
+        }
+        packet.vlans = std::mem::take(&mut frame.vlans);
+        packet.tunnels = std::mem::take(&mut frame.tunnels);
+        let worker = worker(&packet, self.queues.len());
+        self.queues[worker].send(packet);
+    }
+
+    /// Decapsulates IP-in-IP, GRE, VXLAN, GENEVE and GTP-U and decodes the inner packet,
+    /// returns false when the payload isn't a tunnel we know
+    fn tunnel(&mut self, frame: &mut Frame, protocol: u8, payload: &[u8]) -> bool {
+        if frame.tunnels.len() >= MAX_DEPTH {
+            return false;
+        }
+        let decapsulated = match protocol {
+            IPPROTO_IPIP => Some((Tunnel::IPIP, ETHERTYPE_IPV4, payload)),
+            IPPROTO_IPV6 => Some((Tunnel::IPIP, ETHERTYPE_IPV6, payload)),
+            IPPROTO_GRE => gre(payload),
+            17 if payload.len() >= 8 => {
+                let source_port = u16::from_be_bytes([payload[0], payload[1]]);
+                let destination_port = u16::from_be_bytes([payload[2], payload[3]]);
+                let udp_payload = &payload[8..];
+                match destination_port {
+                    VXLAN_PORT => vxlan(udp_payload),
+                    GENEVE_PORT => geneve(udp_payload),
+                    GTPU_PORT => gtpu(udp_payload),
+                    _ if source_port == GTPU_PORT => gtpu(udp_payload),
+                    _ => None,
+                }
             }
+            _ => None,
+        };
+        let (tunnel, ether_type, inner) = match decapsulated {
+            Some(value) => value,
+            None => return false,
+        };
+        if frame.tunnels.is_empty() {
+            self.stats.tunneled.fetch_add(1, Ordering::Relaxed);
         }
-    })
+        frame.tunnels.push(tunnel);
+        match ether_type {
+            ETHERTYPE_TEB => self.ethernet(frame, inner),
+            _ => self.dispatch(frame, ether_type, inner),
+        }
+        true
+    }
+}
+
+/// Strips the VLAN tags and MPLS labels following the Ethernet header, returns the
+/// inner ethertype and its payload, VLAN IDs are appended from outermost to innermost
+fn decapsulate<'a>(mut ether_type: u16, mut payload: &'a [u8], vlans: &mut Vec<u16>) -> Option<(u16, &'a [u8])> {
+    loop {
+        match ether_type {
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_OLD => {
+                if payload.len() < 4 {
+                    return None;
+                }
+                vlans.push(u16::from_be_bytes([payload[0], payload[1]]) & 0x0fff);
+                ether_type = u16::from_be_bytes([payload[2], payload[3]]);
+                payload = &payload[4..];
+            }
+            ETHERTYPE_MPLS | ETHERTYPE_MPLS_MULTICAST => {
+                // Labels until the bottom of stack bit
+                loop {
+                    if payload.len() < 4 {
+                        return None;
+                    }
+                    let bottom = payload[2] & 0x01 == 1;
+                    payload = &payload[4..];
+                    if bottom {
+                        break;
+                    }
+                }
+                // MPLS doesn't say what it carries, guess from the IP version
+                ether_type = match payload.first().map(|byte| byte >> 4) {
+                    Some(4) => ETHERTYPE_IPV4,
+                    Some(6) => ETHERTYPE_IPV6,
+                    _ => return None,
+                };
+                return Some((ether_type, payload));
+            }
+            _ => return Some((ether_type, payload)),
+        }
+    }
+}
+
+/// GRE (RFC 2784/2890), including ERSPAN mirrored Ethernet
+fn gre(payload: &[u8]) -> Option<(Tunnel, u16, &[u8])> {
+    if payload.len() < 4 {
+        return None;
+    }
+    let flags = payload[0];
+    // Version 1 is the PPTP flavour, which carries PPP
+    if payload[1] & 0x07 != 0 {
+        return None;
+    }
+    let mut ether_type = u16::from_be_bytes([payload[2], payload[3]]);
+    let mut offset = 4;
+    // Checksum present
+    if flags & 0x80 != 0 {
+        offset += 4;
+    }
+    let mut key = None;
+    if flags & 0x20 != 0 {
+        let bytes = payload.get(offset..offset + 4)?;
+        key = Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        offset += 4;
+    }
+    // Sequence number present
+    if flags & 0x10 != 0 {
+        offset += 4;
+    }
+    match ether_type {
+        ETHERTYPE_ERSPAN_II => {
+            offset += 8;
+            ether_type = ETHERTYPE_TEB;
+        }
+        ETHERTYPE_ERSPAN_III => {
+            // The optional platform specific subheader is flagged by the O bit
+            let o_flag = payload.get(offset + 11)? & 0x01 != 0;
+            offset += if o_flag { 20 } else { 12 };
+            ether_type = ETHERTYPE_TEB;
+        }
+        _ => (),
+    }
+    Some((Tunnel::GRE(key), ether_type, payload.get(offset..)?))
+}
+
+/// VXLAN (RFC 7348), always carries Ethernet
+fn vxlan(payload: &[u8]) -> Option<(Tunnel, u16, &[u8])> {
+    // The I flag says the VNI is valid
+    if payload.len() < 8 || payload[0] & 0x08 == 0 {
+        return None;
+    }
+    let vni = u32::from_be_bytes([0, payload[4], payload[5], payload[6]]);
+    Some((Tunnel::VXLAN(vni), ETHERTYPE_TEB, &payload[8..]))
+}
+
+/// GENEVE (RFC 8926)
+fn geneve(payload: &[u8]) -> Option<(Tunnel, u16, &[u8])> {
+    if payload.len() < 8 || payload[0] >> 6 != 0 {
+        return None;
+    }
+    let options_len = (payload[0] & 0x3f) as usize * 4;
+    let ether_type = u16::from_be_bytes([payload[2], payload[3]]);
+    let vni = u32::from_be_bytes([0, payload[4], payload[5], payload[6]]);
+    Some((Tunnel::GENEVE(vni), ether_type, payload.get(8 + options_len..)?))
+}
+
+/// GTP-U (3GPP TS 29.281), only G-PDUs carry user traffic
+fn gtpu(payload: &[u8]) -> Option<(Tunnel, u16, &[u8])> {
+    if payload.len() < 8 || payload[0] >> 5 != 1 || payload[0] & 0x10 == 0 || payload[1] != 0xff {
+        return None;
+    }
+    let teid = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+    let mut offset = 8;
+    // Any of E, S or PN adds the sequence number, N-PDU number and next extension type
+    if payload[0] & 0x07 != 0 {
+        let mut next_extension = *payload.get(11)?;
+        offset += 4;
+        while next_extension != 0 {
+            let len = *payload.get(offset)? as usize * 4;
+            if len == 0 {
+                return None;
+            }
+            next_extension = *payload.get(offset + len - 1)?;
+            offset += len;
+        }
+    }
+    // GTP-U says nothing about the inner version
+    let inner = payload.get(offset..)?;
+    let ether_type = match inner.first().map(|byte| byte >> 4) {
+        Some(6) => ETHERTYPE_IPV6,
+        _ => ETHERTYPE_IPV4,
+    };
+    Some((Tunnel::GTPU(teid), ether_type, inner))
+}
+
+/// BSD loopback, the address family is in host byte order for NULL and network order for LOOP
+fn loopback(link_type: i32, data: &[u8]) -> Option<u16> {
+    let family = data.get(0..4)?;
+    let family = if link_type == DLT_LOOP {
+        u32::from_be_bytes([family[0], family[1], family[2], family[3]])
+    } else {
+        // The capturing host's byte order isn't recorded, families are small so try both
+        let little = u32::from_le_bytes([family[0], family[1], family[2], family[3]]);
+        if little > 0xffff { little.swap_bytes() } else { little }
+    };
+    match family {
+        2 => Some(ETHERTYPE_IPV4),
+        // AF_INET6 differs between Linux, the BSDs and macOS
+        10 | 24 | 28 | 30 => Some(ETHERTYPE_IPV6),
+        _ => None,
+    }
+}
+
+/// Linux cooked capture v1 and v2 (`-i any`), returns the protocol and the payload
+fn sll(link_type: i32, data: &[u8]) -> Option<(u16, &[u8])> {
+    let (protocol, header_len) = if link_type == DLT_LINUX_SLL2 {
+        let header = data.get(0..20)?;
+        (u16::from_be_bytes([header[0], header[1]]), 20)
+    } else {
+        let header = data.get(0..16)?;
+        (u16::from_be_bytes([header[14], header[15]]), 16)
+    };
+    Some((protocol, &data[header_len..]))
+}
+
+/// Skips the radiotap header, dropping the FCS when radiotap says the frame carries one
+fn radiotap(data: &[u8]) -> Option<&[u8]> {
+    let header = data.get(0..8)?;
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    let present = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let frame = data.get(len..)?;
+
+    // Extended present bitmasks are chained through bit 31
+    let mut offset = 8;
+    let mut extended = present;
+    while extended & 0x8000_0000 != 0 {
+        let bytes = data.get(offset..offset + 4)?;
+        extended = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        offset += 4;
+    }
+    // TSFT comes first and is 8 byte aligned, then the flags byte
+    if present & 0x01 != 0 {
+        offset = (offset + 7) & !7;
+        offset += 8;
+    }
+    if present & 0x02 != 0 && data.get(offset)? & 0x10 != 0 {
+        return frame.get(..frame.len().checked_sub(4)?);
+    }
+    Some(frame)
+}
+
+/// Unprotected 802.11 data frames, returns the ethertype of the LLC/SNAP payload
+fn ieee802_11(frame: &[u8]) -> Option<(u16, &[u8])> {
+    let control = frame.get(0..2)?;
+    let frame_type = (control[0] >> 2) & 0x03;
+    let subtype = control[0] >> 4;
+    let flags = control[1];
+    // Data frames only, the null function subtypes carry no body and protected ones can't be read
+    if frame_type != 2 || subtype & 0x04 != 0 || flags & 0x40 != 0 {
+        return None;
+    }
+    let mut header_len = 24;
+    // To DS and From DS both set, the fourth address is present
+    if flags & 0x03 == 0x03 {
+        header_len += 6;
+    }
+    // QoS data, with an HT control field when the order bit is set
+    if subtype & 0x08 != 0 {
+        header_len += 2;
+        if flags & 0x80 != 0 {
+            header_len += 4;
+        }
+    }
+    let llc = frame.get(header_len..header_len + 8)?;
+    if llc[0..3] != [0xaa, 0xaa, 0x03] {
+        return None;
+    }
+    Some((u16::from_be_bytes([llc[6], llc[7]]), &frame[header_len + 8..]))
+}
+
+/// Identification, offset in bytes and more fragments flag of an IPv6 fragment header
+type FragmentHeader = (u32, usize, bool);
+
+/// Walks the IPv6 extension headers up to the transport header or the fragment header
+fn ipv6_extensions(mut next_header: u8, mut payload: &[u8]) -> Option<(u8, &[u8], Option<FragmentHeader>)> {
+    loop {
+        match next_header {
+            // Hop-by-hop, routing, destination options, mobility, HIP, shim6
+            0 | 43 | 60 | 135 | 139 | 140 => {
+                let len = (*payload.get(1)? as usize + 1) * 8;
+                next_header = *payload.first()?;
+                payload = payload.get(len..)?;
+            }
+            // Authentication header counts in 4 byte units
+            51 => {
+                let len = (*payload.get(1)? as usize + 2) * 4;
+                next_header = *payload.first()?;
+                payload = payload.get(len..)?;
+            }
+            44 => {
+                let header = payload.get(0..8)?;
+                let offset_flags = u16::from_be_bytes([header[2], header[3]]);
+                let id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+                let offset = (offset_flags & 0xfff8) as usize;
+                let more = offset_flags & 0x01 != 0;
+                // Atomic fragments (RFC 6946) aren't really fragmented
+                if offset == 0 && !more {
+                    next_header = header[0];
+                    payload = &payload[8..];
+                    continue;
+                }
+                return Some((header[0], &payload[8..], Some((id, offset, more))));
+            }
+            _ => return Some((next_header, payload, None)),
+        }
+    }
+}
+
+/// Position of `inner` in `outer`, if it is a slice of it
+fn offset_in(outer: &[u8], inner: &[u8]) -> Option<usize> {
+    let base = outer.as_ptr() as usize;
+    let start = inner.as_ptr() as usize;
+    if start >= base && start + inner.len() <= base + outer.len() {
+        Some(start - base)
+    } else {
+        None
+    }
+}
+
+/// Picks the worker of a packet from its address pair, in either direction. ICMP errors go
+/// to the worker of the flow they quote, which holds the context they annotate
+fn worker(packet: &QueuePacket, workers: usize) -> usize {
+    if workers <= 1 {
+        return 0;
+    }
+    let payload = packet.payload();
+    let (source, destination) = match (packet.source6, packet.destination6) {
+        (Some(source), Some(destination)) => (u128::from(source), u128::from(destination)),
+        _ if packet.protocol == 1 && payload.len() >= 28 && (payload[0] == 3 || payload[0] == 11) => (
+            u128::from(u32::from_be_bytes([payload[20], payload[21], payload[22], payload[23]])),
+            u128::from(u32::from_be_bytes([payload[24], payload[25], payload[26], payload[27]])),
+        ),
+        _ => (u128::from(u32::from(packet.source)), u128::from(u32::from(packet.destination))),
+    };
+    let key = (source ^ destination) as u64
+        ^ ((source ^ destination) >> 64) as u64
+        ^ u64::from(packet.flow_vlan)
+        ^ (u64::from(packet.flow_tunnel) << 16);
+    // Fibonacci hashing spreads the neighbouring addresses of a subnet
+    (key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize % workers
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+
+    use super::*;
+    use crate::{config::QueuePolicy, queue::PacketQueue};
+
+    const CLIENT: [u8; 4] = [10, 0, 0, 1];
+    const SERVER: [u8; 4] = [10, 0, 0, 2];
+
+    /// A UDP datagram between the inner addresses, carried in VXLAN on `vni`
+    fn vxlan_frame(vni: u32, source: [u8; 4], destination: [u8; 4]) -> Vec<u8> {
+        let mut inner = Vec::new();
+        PacketBuilder::ethernet2([2; 6], [4; 6]).ipv4(source, destination, 64).udp(5000, 53).write(&mut inner, b"query").unwrap();
+        let mut vxlan = vec![0x08, 0, 0, 0];
+        vxlan.extend_from_slice(&(vni << 8).to_be_bytes());
+        vxlan.extend(inner);
+        let mut frame = Vec::new();
+        PacketBuilder::ethernet2([1; 6], [3; 6])
+            .ipv4([192, 168, 0, 1], [192, 168, 0, 2], 64)
+            .udp(40000, VXLAN_PORT)
+            .write(&mut frame, &vxlan)
+            .unwrap();
+        frame
+    }
+
+    /// Decodes Ethernet frames as a capture thread would
+    fn decode(config: &Config, frames: &[Vec<u8>]) -> Vec<QueuePacket> {
+        let stats = Stats::new(&["test".to_string()]);
+        let (producer, queue) = PacketQueue::new(frames.len(), QueuePolicy::BLOCK, &stats);
+        let pool = BufferPool::new(config.pool_buffers(), config.queue.buffer_size);
+        let arp_table = Arc::new(Mutex::new(HashMap::new()));
+        let events = Arc::new(Events::default());
+        let mut decoder = Decoder::new(config, 0, &[producer], &pool, &arp_table, &events, &stats);
+        decoder.set_link_type(Linktype(DLT_EN10MB));
+        for (index, data) in frames.iter().enumerate() {
+            let header = PacketHeader {
+                ts: libc::timeval { tv_sec: index as libc::time_t, tv_usec: 0 },
+                caplen: data.len() as u32,
+                len: data.len() as u32,
+            };
+            decoder.handle_packet(&Packet::new(&header, data));
+        }
+        drop(decoder);
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn tunnel_ids_are_part_of_the_flow_key_when_asked() {
+        let frames = [vxlan_frame(100, CLIENT, SERVER), vxlan_frame(200, CLIENT, SERVER)];
+        let mut config = Config::default();
+        let packets = decode(&config, &frames);
+        assert_eq!(packets.iter().map(|packet| packet.flow_tunnel).collect::<Vec<_>>(), [0, 0]);
+        assert_eq!(packets[1].tunnels, [Tunnel::VXLAN(200)]);
+
+        config.capture.tunnel_flow_key = true;
+        let packets = decode(&config, &frames);
+        assert_eq!(packets.iter().map(|packet| packet.flow_tunnel).collect::<Vec<_>>(), [100, 200]);
+        assert_eq!(packets[0].source, Ipv4Addr::from(CLIENT));
+    }
+
+    #[test]
+    fn worker_keeps_both_directions_of_a_tunneled_flow_together() {
+        let mut config = Config::default();
+        config.capture.tunnel_flow_key = true;
+        let frames: Vec<Vec<u8>> =
+            (1..=16).flat_map(|vni| vec![vxlan_frame(vni, CLIENT, SERVER), vxlan_frame(vni, SERVER, CLIENT)]).collect();
+        let packets = decode(&config, &frames);
+        let workers: Vec<usize> = packets.iter().map(|packet| worker(packet, 4)).collect();
+        for pair in workers.chunks(2) {
+            assert_eq!(pair[0], pair[1]);
+        }
+        // The same address pair is spread over the workers by its tunnel
+        assert!(workers.iter().any(|worker| *worker != workers[0]));
+    }
 }
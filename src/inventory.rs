@@ -0,0 +1,49 @@
+use std::{collections::HashMap, io::Write, net::Ipv4Addr};
+
+use crate::utils::format_mac;
+
+/// One IP address as seen in ARP, with the MAC currently answering for it
+#[derive(Debug, Clone)]
+pub struct ArpEntry {
+    pub ip: Ipv4Addr,
+    pub mac: [u8; 6],
+    /// MACs that claimed this IP before the current one
+    pub previous_macs: Vec<[u8; 6]>,
+    pub gratuitous: usize,
+    pub first_ts: u128,
+    pub last_ts: u128,
+}
+
+impl ArpEntry {
+    pub fn new(ip: Ipv4Addr, mac: [u8; 6], ts: u128) -> ArpEntry {
+        ArpEntry {
+            ip,
+            mac,
+            previous_macs: Vec::new(),
+            gratuitous: 0,
+            first_ts: ts,
+            last_ts: ts,
+        }
+    }
+}
+
+/// Writes the ARP table as CSV: ip,mac,previous_macs,gratuitous,first_ts,last_ts
+pub fn export(table: &HashMap<Ipv4Addr, ArpEntry>, out: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(out, "ip,mac,previous_macs,gratuitous,first_ts,last_ts")?;
+    let mut entries: Vec<&ArpEntry> = table.values().collect();
+    entries.sort_by_key(|entry| u32::from(entry.ip));
+    for entry in entries {
+        let previous: Vec<String> = entry.previous_macs.iter().map(format_mac).collect();
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            entry.ip,
+            format_mac(&entry.mac),
+            previous.join(";"),
+            entry.gratuitous,
+            entry.first_ts,
+            entry.last_ts
+        )?;
+    }
+    Ok(())
+}
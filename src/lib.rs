@@ -0,0 +1,47 @@
+//! Passive network traffic analyzer: flows, DNS, application protocols and alerts
+//! from live interfaces or capture files. Diagnostics and reports go through the `log` crate.
+//!
+//! ```no_run
+//! use perso::{Analyzer, Event, Source};
+//!
+//! let analyzer = Analyzer::builder()
+//!     .config(perso::config::load_config().unwrap())
+//!     .source(Source::Interface("eth0".to_string()))
+//!     .sink(|event: &Event| println!("{:?}", event))
+//!     .build()
+//!     .unwrap();
+//! analyzer.run().unwrap();
+//! ```
+
+extern crate core_affinity;
+
+mod handlers;
+mod utils;
+mod stats;
+mod interface;
+mod packet_handler;
+pub mod config;
+mod alerts;
+mod extract;
+mod export;
+mod hosts;
+mod inventory;
+pub mod shutdown;
+pub mod reload;
+mod queue;
+mod pool;
+mod analyzer;
+mod events;
+mod outputs;
+pub mod dissector;
+#[doc(hidden)]
+pub mod bench;
+
+pub use alerts::{Alert, AlertType};
+pub use analyzer::{Analyzer, AnalyzerBuilder, Error, Source};
+pub use dissector::{Dissector, Flow, FlowDissector, Registry};
+pub use events::{AppEvent, DnsTransaction, Event, EventSink, FlowRecord};
+pub use handlers::icmp::IcmpError;
+pub use shutdown::Shutdown;
+pub use stats::{InterfaceStats, Snapshot, Stats};
+pub use utils::{AppType, Tunnel};
@@ -1,66 +1,70 @@
-extern crate core_affinity;
+mod cli;
+mod logger;
 
-mod handlers;
-mod utils;
-mod stats;
-mod interface;
-mod packet_handler;
-mod config;
+use clap::Parser;
+use perso::{config, reload, shutdown, Analyzer, Error};
 
-use core_affinity::CoreId;
-use utils::{DnsRecord, QueuePacket};
-use std::{collections::HashMap, fs::File, sync::{Arc, Mutex, mpsc::{self, Receiver, Sender}}};
-
-use crate::{config::load_config, stats::Stats, utils::Files};
+use cli::Cli;
 
 fn main() {
-    // Init config
-    let config = load_config();
-    println!("{:?}", config); 
-
-    // Init the probe struct
-    let dns_records: Arc<Mutex<HashMap<DnsRecord, String>>> = Arc::new(Mutex::new(HashMap::new()));
-    let stats = Stats::new();
-
-    let (tx, rx): (Sender<QueuePacket>, Receiver<QueuePacket>) = mpsc::channel();
-
-    let receiver = Arc::new(Mutex::new(rx));
-
-    // Initializing the stats thread
-    let stats_thread = stats::run(&stats);
-
-    /* 
-    We will need 1 Thread for the stats,
-
-    For each core we will need 2 main threads
-    - Thread 1: reads from interface
-    - Thread 2: reads from the queue
-    */
-
-    // let core_ids = core_affinity::get_core_ids().unwrap();
-
-    // Initializing the interface reader thread
-    /*let _handles_two = core_ids.into_iter().map(|_id| {
-        let _interface_thread = interface::run(&config, &tx, &dns_records, &stats);
-
-    }).collect::<Vec<_>>();*/
-
-    let interface_thread = interface::run(&config, &tx, &dns_records, &stats);
-
-    /*
-    let handles = core_ids.into_iter().map(|id| {
-        let receiver = receiver.clone();
-        // Initializing the packet handler thread
-        packet_handler::run(id, receiver, &dns_records, &stats)
-    }).collect::<Vec<_>>();*/
-
-    let handler_thread = packet_handler::run(&config, CoreId { id: 1 }, receiver, &dns_records, &stats);
-
-    // Wait for the threads to finish
-    stats_thread.join().unwrap();
-    interface_thread.join().unwrap();
-    handler_thread.join().unwrap();
-    /*for handle in handles.into_iter() {
-        handle.join().unwrap();
-    }*/
+    let cli = Cli::parse();
+    logger::install();
+    if cli.dump_default_config {
+        print!("{}", config::TEMPLATE);
+        std::process::exit(0);
+    }
+
+    // Init config, the flags win over the file
+    let loaded = match &cli.config {
+        Some(path) => config::load(path),
+        None => config::default_path().and_then(|path| {
+            println!("Reading the config from {}", path.display());
+            config::load_config()
+        }),
+    };
+    let mut config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    cli.apply(&mut config);
+    if cli.check_config {
+        match config.validate() {
+            Ok(()) => println!("Config OK"),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    // SIGHUP reads the same file again, with the same flags
+    let reloaded = move || {
+        let mut config = match &cli.config {
+            Some(path) => config::load(path)?,
+            None => config::load_config()?,
+        };
+        cli.apply(&mut config);
+        Ok(config)
+    };
+    let analyzer = match Analyzer::builder().config(config).reload(reloaded).print_stats(true).build() {
+        Ok(analyzer) => analyzer,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    shutdown::install();
+    reload::install();
+
+    if let Err(e) = analyzer.run() {
+        eprintln!("{}", e);
+        std::process::exit(if let Error::Panicked = e { 2 } else { 1 });
+    }
+    // As a shell reports a process killed by the signal, 130 for SIGINT and 143 for SIGTERM
+    std::process::exit(shutdown::signal().map_or(0, |signal| 128 + signal));
 }
@@ -0,0 +1,325 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    net::Ipv4Addr,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    config::{Output, OutputType},
+    events::{Event, EventSink, FlowRecord},
+    hosts::csv_field,
+};
+
+/// A disconnected socket is retried at most this often
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+const CSV_HEADER: &str = "event,ts,protocol,client,client_port,server,server_port,app,host,packets,detail";
+
+/// Opens the `[[outputs]]` of the config, the error names the output that failed
+pub fn open(outputs: &[Output]) -> Result<Vec<Box<dyn EventSink>>, (String, io::Error)> {
+    let mut sinks: Vec<Box<dyn EventSink>> = Vec::new();
+    for output in outputs {
+        let failed = |e: io::Error| (format!("{} {}", output.output_type, output.path.display()), e);
+        let sink: Box<dyn EventSink> = match output.output_type {
+            OutputType::STDOUT => Box::new(Stdout),
+            OutputType::JSONL => Box::new(JsonLines { out: append(&output.path).map_err(failed)? }),
+            OutputType::CSV => {
+                let exists = output.path.metadata().map(|m| m.len() > 0).unwrap_or(false);
+                let mut out = append(&output.path).map_err(failed)?;
+                // Appending to an existing export keeps a single header
+                if !exists {
+                    writeln!(out, "{}", CSV_HEADER).map_err(failed)?;
+                }
+                Box::new(Csv { out })
+            }
+            OutputType::UNIX => Box::new(Socket { path: output.path.clone(), stream: None, last_attempt: None }),
+        };
+        if output.events.is_empty() {
+            sinks.push(sink);
+        } else {
+            sinks.push(Box::new(Filter { events: output.events.clone(), sink }));
+        }
+    }
+    Ok(sinks)
+}
+
+fn append(path: &Path) -> io::Result<BufWriter<std::fs::File>> {
+    Ok(BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?))
+}
+
+/// Only passes the events named in `events`
+struct Filter {
+    events: Vec<String>,
+    sink: Box<dyn EventSink>,
+}
+
+impl EventSink for Filter {
+    fn event(&mut self, event: &Event) {
+        if self.events.iter().any(|name| name == event.name()) {
+            self.sink.event(event);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.sink.flush();
+    }
+}
+
+/// One human readable line per event
+struct Stdout;
+
+impl EventSink for Stdout {
+    fn event(&mut self, event: &Event) {
+        match event {
+            Event::FlowStarted(flow) | Event::FlowUpdated(flow) | Event::FlowEnded(flow) => println!(
+                "[FLOW] {} {} {}:{} -> {}:{} {:?} sni: {} packets: {} if{:?} {:?} icmp: {}",
+                event.name(),
+                protocol(flow),
+                flow.client.0,
+                flow.client.1,
+                flow.server.0,
+                flow.server.1,
+                flow.app_type,
+                flow.sni.as_deref().unwrap_or("-"),
+                flow.packets,
+                flow.interfaces,
+                flow.tunnels,
+                flow.icmp_error.as_ref().map_or("-", |error| error.description)
+            ),
+            Event::Dns(dns) => println!(
+                "[DNS] {} {} -> {} {} {}: {}",
+                dns.ts,
+                dns.server,
+                dns.client,
+                dns.response_code,
+                dns.questions.join(","),
+                dns.answers.join(",")
+            ),
+            Event::App(app) => println!(
+                "[{}] {}:{} -> {}:{} {} {} {} {}",
+                app.protocol.to_uppercase(),
+                app.client.0,
+                app.client.1,
+                app.server.0,
+                app.server.1,
+                app.host.as_deref().unwrap_or("-"),
+                app.method.as_deref().unwrap_or("-"),
+                app.path.as_deref().unwrap_or("-"),
+                app.status.map(|status| status.to_string()).unwrap_or_else(|| "-".to_string())
+            ),
+            Event::Alert(alert) => println!(
+                "[ALERT] {} {:?} client: {} subject: {} score: {:.2} evidence: {}",
+                alert.ts,
+                alert.alert_type,
+                alert.client,
+                alert.subject,
+                alert.score,
+                alert.evidence.join("; ")
+            ),
+        }
+    }
+}
+
+/// One JSON object per line, appended to a file
+struct JsonLines {
+    out: BufWriter<std::fs::File>,
+}
+
+impl EventSink for JsonLines {
+    fn event(&mut self, event: &Event) {
+        if let Err(e) = writeln!(self.out, "{}", to_json(event)) {
+            log::warn!("Couldn't write JSON event: {}", e);
+        }
+    }
+
+    fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// One row per event, `CSV_HEADER` columns
+struct Csv {
+    out: BufWriter<std::fs::File>,
+}
+
+impl EventSink for Csv {
+    fn event(&mut self, event: &Event) {
+        let row: Vec<String> = to_row(event).iter().map(|field| csv_field(field)).collect();
+        if let Err(e) = writeln!(self.out, "{}", row.join(",")) {
+            log::warn!("Couldn't write CSV event: {}", e);
+        }
+    }
+
+    fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// JSON Lines to a Unix stream socket, events are dropped while nobody listens
+struct Socket {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+    last_attempt: Option<Instant>,
+}
+
+impl EventSink for Socket {
+    fn event(&mut self, event: &Event) {
+        if self.stream.is_none() {
+            if self.last_attempt.is_some_and(|ts| ts.elapsed() < RECONNECT_INTERVAL) {
+                return;
+            }
+            self.last_attempt = Some(Instant::now());
+            match UnixStream::connect(&self.path) {
+                Ok(stream) => self.stream = Some(stream),
+                Err(e) => {
+                    log::warn!("Couldn't connect to {}: {}", self.path.display(), e);
+                    return;
+                }
+            }
+        }
+        if let Some(stream) = &mut self.stream {
+            if let Err(e) = writeln!(stream, "{}", to_json(event)) {
+                log::warn!("Lost the output socket {}: {}", self.path.display(), e);
+                self.stream = None;
+            }
+        }
+    }
+}
+
+fn protocol(flow: &FlowRecord) -> &'static str {
+    if flow.protocol == 6 {
+        "tcp"
+    } else {
+        "udp"
+    }
+}
+
+fn endpoint(endpoint: &(Ipv4Addr, u16)) -> String {
+    format!("{}:{}", endpoint.0, endpoint.1)
+}
+
+fn to_json(event: &Event) -> Value {
+    match event {
+        Event::FlowStarted(flow) | Event::FlowUpdated(flow) | Event::FlowEnded(flow) => json!({
+            "event": event.name(),
+            "protocol": protocol(flow),
+            "client": endpoint(&flow.client),
+            "server": endpoint(&flow.server),
+            "vlan": flow.vlan,
+            "first_ts": flow.first_ts as u64,
+            "last_ts": flow.last_ts as u64,
+            "packets": flow.packets,
+            "app": format!("{:?}", flow.app_type),
+            "sni": flow.sni,
+            "client_name": flow.client_name,
+            "server_name": flow.server_name,
+            "icmp_error": flow.icmp_error.as_ref().map(|error| json!({
+                "ts": error.ts as u64,
+                "reporter": error.reporter.to_string(),
+                "type": error.icmp_type,
+                "code": error.code,
+                "description": error.description,
+            })),
+            "interfaces": flow.interfaces,
+            "tunnels": flow.tunnels.iter().map(|tunnel| format!("{:?}", tunnel)).collect::<Vec<String>>(),
+        }),
+        Event::Dns(dns) => json!({
+            "event": event.name(),
+            "ts": dns.ts as u64,
+            "client": dns.client.to_string(),
+            "server": dns.server.to_string(),
+            "id": dns.id,
+            "questions": dns.questions,
+            "answers": dns.answers,
+            "response_code": dns.response_code,
+        }),
+        Event::App(app) => json!({
+            "event": event.name(),
+            "ts": app.ts as u64,
+            "protocol": app.protocol,
+            "client": endpoint(&app.client),
+            "server": endpoint(&app.server),
+            "host": app.host,
+            "method": app.method,
+            "path": app.path,
+            "status": app.status,
+        }),
+        Event::Alert(alert) => json!({
+            "event": event.name(),
+            "ts": alert.ts as u64,
+            "type": format!("{:?}", alert.alert_type),
+            "client": alert.client.to_string(),
+            "subject": alert.subject,
+            "score": alert.score,
+            "evidence": alert.evidence,
+        }),
+    }
+}
+
+fn to_row(event: &Event) -> [String; 11] {
+    let none = String::new;
+    match event {
+        Event::FlowStarted(flow) | Event::FlowUpdated(flow) | Event::FlowEnded(flow) => [
+            event.name().to_string(),
+            flow.last_ts.to_string(),
+            protocol(flow).to_string(),
+            flow.client.0.to_string(),
+            flow.client.1.to_string(),
+            flow.server.0.to_string(),
+            flow.server.1.to_string(),
+            format!("{:?}", flow.app_type),
+            flow.sni.clone().unwrap_or_default(),
+            flow.packets.to_string(),
+            format!("vlan={}", flow.vlan),
+        ],
+        Event::Dns(dns) => [
+            event.name().to_string(),
+            dns.ts.to_string(),
+            "dns".to_string(),
+            dns.client.to_string(),
+            none(),
+            dns.server.to_string(),
+            none(),
+            none(),
+            dns.questions.join(";"),
+            none(),
+            format!("{} {}", dns.response_code, dns.answers.join(";")),
+        ],
+        Event::App(app) => [
+            event.name().to_string(),
+            app.ts.to_string(),
+            app.protocol.to_string(),
+            app.client.0.to_string(),
+            app.client.1.to_string(),
+            app.server.0.to_string(),
+            app.server.1.to_string(),
+            none(),
+            app.host.clone().unwrap_or_default(),
+            none(),
+            format!(
+                "{} {} {}",
+                app.method.as_deref().unwrap_or("-"),
+                app.path.as_deref().unwrap_or("-"),
+                app.status.map(|status| status.to_string()).unwrap_or_else(|| "-".to_string())
+            ),
+        ],
+        Event::Alert(alert) => [
+            event.name().to_string(),
+            alert.ts.to_string(),
+            none(),
+            alert.client.to_string(),
+            none(),
+            none(),
+            none(),
+            format!("{:?}", alert.alert_type),
+            alert.subject.clone(),
+            none(),
+            format!("score={:.2} {}", alert.score, alert.evidence.join("; ")),
+        ],
+    }
+}
@@ -1,55 +1,168 @@
-use std::{collections::HashMap, fs::File, sync::{Arc, Mutex, mpsc::{Receiver,}}, thread::{self, JoinHandle}};
+use std::{collections::HashMap, net::Ipv4Addr, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}, thread, time::{Duration, Instant}};
 
-use core_affinity::CoreId;
 use num_traits::FromPrimitive;
 
-use crate::{config::Config, handlers::{
+use crate::{dissector::Registry, events::Events, hosts::{HostEntry, NameSource}, handlers::{
         tcp::{self, Quad, TcpContext},
-        udp,
-    }, stats::Stats, utils::{DnsRecord, Files, ProtocolType, QueuePacket}};
+        dns::DnsDissector,
+        dns_analytics::DnsAnalytics,
+        http::HttpDissector,
+        icmp::{self, IcmpState},
+        mdns::MdnsDissector,
+        nbns::NbnsDissector,
+        quic::QuicDissector,
+        tls::TlsDissector,
+        udp::{self, UdpContext},
+        whatsapp::WhatsappDissector,
+    }, queue::PacketQueue, reload::LiveConfig, shutdown, stats::Stats, utils::{DnsRecord, ProtocolType}};
 
-pub fn run(
-    config: &Config,
-    core_id: CoreId,
-    queue: Arc<Mutex<Receiver<QueuePacket>>>,
+/// The dissectors we ship, probed before the ones added with `AnalyzerBuilder::dissector`.
+/// The workers share them, and so the DNS analytics
+pub fn builtin(
+    live: &Arc<LiveConfig>,
     dns_records: &Arc<Mutex<HashMap<DnsRecord, String>>>,
+    hosts: &Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
     stats: &Arc<Stats>,
-) -> JoinHandle<()> {
-    core_affinity::set_for_current(core_id);
+) -> Registry {
+    let config = live.get();
+    let analytics: Arc<Mutex<DnsAnalytics>> = Arc::new(Mutex::new(DnsAnalytics::new()));
+    let mut registry = Registry::default();
+    registry.register(ProtocolType::TCP as u8, Arc::new(TlsDissector));
+    registry.register(ProtocolType::TCP as u8, Arc::new(HttpDissector { config: live.clone(), stats: stats.clone() }));
+    registry.register(ProtocolType::TCP as u8, Arc::new(WhatsappDissector));
+    registry.register(ProtocolType::UDP as u8, Arc::new(QuicDissector { config: live.clone(), stats: stats.clone() }));
+    registry.register(
+        ProtocolType::UDP as u8,
+        Arc::new(DnsDissector {
+            config: live.clone(),
+            dns_records: dns_records.clone(),
+            analytics: analytics.clone(),
+            stats: stats.clone(),
+        }),
+    );
+    // Local name announcements
+    if config.names.enabled {
+        for (port, name_source) in [(5353, NameSource::MDNS), (5355, NameSource::LLMNR)] {
+            registry.register(
+                ProtocolType::UDP as u8,
+                Arc::new(MdnsDissector { port, name_source, hosts: hosts.clone(), stats: stats.clone() }),
+            );
+        }
+        registry.register(ProtocolType::UDP as u8, Arc::new(NbnsDissector { hosts: hosts.clone(), stats: stats.clone() }));
+    }
+    registry
+}
 
-    let stats = stats.clone();
-    let dns_records = dns_records.clone();
+/// Capture time of a worker in milliseconds: the timestamp of the last packet it handled, moved
+/// on by the wall clock while it waits for the next one. Flows expire on the timeline of the
+/// capture, a replayed file included
+pub(crate) struct CaptureClock {
+    started: Instant,
+    /// Capture time of the last packet, 0 before the first one
+    last_ts: AtomicU64,
+    /// When it was handled, in milliseconds since `started`
+    handled_at: AtomicU64,
+}
 
-    let connections: Arc<Mutex<HashMap<Quad, TcpContext>>> = Arc::new(Mutex::new(HashMap::new()));
-    
-    let mut files: Files = Files { whatsapp: None };
+impl CaptureClock {
+    pub fn new() -> CaptureClock {
+        CaptureClock { started: Instant::now(), last_ts: AtomicU64::new(0), handled_at: AtomicU64::new(0) }
+    }
 
-    let config = config.clone();
+    pub fn tick(&self, ts: u128) {
+        self.handled_at.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.last_ts.fetch_max(ts as u64, Ordering::Relaxed);
+    }
 
-    let cfg = config.clone();
-    // create the files
-    if config.whatsapp.debug {
-        match File::open(config.whatsapp.file) {
-            Ok(file) => files.whatsapp = Some(file),
-            Err(e) => println!("Couldn't open whatsapp debug"),
+    /// None until a packet was handled
+    pub fn now(&self) -> Option<u128> {
+        let last_ts = self.last_ts.load(Ordering::Relaxed);
+        if last_ts == 0 {
+            return None;
         }
+        let idle = (self.started.elapsed().as_millis() as u64).saturating_sub(self.handled_at.load(Ordering::Relaxed));
+        Some(u128::from(last_ts + idle))
     }
-    
-
-    thread::spawn(move || {
-        loop {
-            let queue_packet = queue.lock().unwrap().recv().unwrap();
-            match FromPrimitive::from_u8(queue_packet.protocol) {
-                Some(ProtocolType::TCP) => {
-                    let connections = connections.clone();
-                    tcp::handle(&mut files, &cfg, connections, queue_packet, &dns_records, &stats);
-                },
-                Some(ProtocolType::UDP) => {
-                    udp::handle(queue_packet, &dns_records, &stats);
-                },
-                Some(ProtocolType::IGMP) => (),
-                None => (),
+}
+
+/// Runs a worker on the calling thread until its queue closes. The worker handles the packets
+/// of its queue with its own flow tables. A reload is picked up between two packets, and applies
+/// to the flows that start after it
+pub fn run(
+    live: &Arc<LiveConfig>,
+    queue: PacketQueue,
+    dns_records: &Arc<Mutex<HashMap<DnsRecord, String>>>,
+    hosts: &Arc<Mutex<HashMap<Ipv4Addr, HostEntry>>>,
+    dissectors: Registry,
+    events: &Arc<Events>,
+    stats: &Arc<Stats>,
+) {
+    let connections: Arc<Mutex<HashMap<Quad, TcpContext>>> = Arc::new(Mutex::new(HashMap::new()));
+    let udp_connections: Arc<Mutex<HashMap<Quad, UdpContext>>> = Arc::new(Mutex::new(HashMap::new()));
+    // Cleared once the queue is drained, which stops the threads below
+    let running = Arc::new(AtomicBool::new(true));
+
+    // Start the kill thread
+    let clock = Arc::new(CaptureClock::new());
+    let expired_clock = clock.clone();
+    let expired_tcp = connections.clone();
+    let expired_udp = udp_connections.clone();
+    let expired_events = events.clone();
+    let expired_stats = stats.clone();
+    let expired_running = running.clone();
+    let kill_thread = thread::spawn(move || {
+        while shutdown::sleep(&expired_running, Duration::from_secs(1)) {
+            if let Some(ts) = expired_clock.now() {
+                tcp::expire(&expired_tcp, ts, &expired_events, &expired_stats);
+                udp::expire(&expired_udp, ts, &expired_events);
             }
+            expired_events.flush();
+        }
+    });
+
+    // Start the ICMP report thread
+    let icmp_state: Arc<Mutex<IcmpState>> = Arc::new(Mutex::new(IcmpState::default()));
+    let reported_state = icmp_state.clone();
+    let reported_running = running.clone();
+    let report_thread = thread::spawn(move || {
+        while shutdown::sleep(&reported_running, Duration::from_secs(60)) {
+            icmp::report(&reported_state);
         }
-    })
+    });
+
+    let mut generation = live.generation();
+    let mut cfg = live.get();
+    // Runs until every capture thread is done, the input ended or a shutdown was requested
+    while let Some(queue_packet) = queue.pop() {
+        if live.generation() != generation {
+            generation = live.generation();
+            cfg = live.get();
+        }
+        clock.tick(queue_packet.millis());
+        match FromPrimitive::from_u8(queue_packet.protocol) {
+            Some(ProtocolType::TCP) => {
+                for event in tcp::handle(&cfg, &connections, queue_packet, dns_records, hosts, &dissectors, stats) {
+                    events.emit(event);
+                }
+            },
+            Some(ProtocolType::UDP) => {
+                for event in udp::handle(&cfg, &udp_connections, queue_packet, hosts, &dissectors, stats) {
+                    events.emit(event);
+                }
+            },
+            Some(ProtocolType::ICMP) | Some(ProtocolType::ICMPV6) => {
+                icmp::handle(&icmp_state, queue_packet, &connections, &udp_connections, stats);
+            },
+            Some(ProtocolType::IGMP) => (),
+            None => (),
+        }
+    }
+
+    running.store(false, Ordering::Relaxed);
+    let _ = kill_thread.join();
+    let _ = report_thread.join();
+    let tcp_flows = tcp::flush(&connections, events, stats);
+    let udp_flows = udp::flush(&udp_connections, events);
+    icmp::report(&icmp_state);
+    log::info!("Flushed {} TCP and {} UDP flows", tcp_flows, udp_flows);
 }
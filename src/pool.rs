@@ -0,0 +1,86 @@
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+/// Preallocated packet buffers, recycled once the handlers drop the packet
+/// so the capture path doesn't allocate per packet
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    /// Buffers kept around, the pool allocates past it when empty but doesn't keep the extra ones
+    count: usize,
+    buffer_size: usize,
+}
+
+/// Captured bytes in a pooled buffer, handed back to the pool on drop
+pub struct PacketBuffer {
+    data: Vec<u8>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl BufferPool {
+    pub fn new(count: usize, buffer_size: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            free: Mutex::new((0..count).map(|_| Vec::with_capacity(buffer_size)).collect()),
+            count,
+            buffer_size,
+        })
+    }
+
+    /// Copies `data` into a free buffer
+    pub fn take(self: &Arc<Self>, data: &[u8]) -> PacketBuffer {
+        let mut buffer = match self.free.lock().unwrap().pop() {
+            Some(buffer) => buffer,
+            None => Vec::with_capacity(self.buffer_size.max(data.len())),
+        };
+        buffer.extend_from_slice(data);
+        PacketBuffer { data: buffer, pool: Some(self.clone()) }
+    }
+
+    fn recycle(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.count {
+            free.push(buffer);
+        }
+    }
+}
+
+impl PacketBuffer {
+    /// Buffer outside of any pool, for packets built by hand
+    pub fn from_vec(data: Vec<u8>) -> PacketBuffer {
+        PacketBuffer { data, pool: None }
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Clone for PacketBuffer {
+    fn clone(&self) -> PacketBuffer {
+        match &self.pool {
+            Some(pool) => pool.take(&self.data),
+            None => PacketBuffer::from_vec(self.data.clone()),
+        }
+    }
+}
+
+impl fmt::Debug for PacketBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PacketBuffer({} bytes)", self.data.len())
+    }
+}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.recycle(std::mem::take(&mut self.data));
+        }
+    }
+}
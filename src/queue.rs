@@ -0,0 +1,235 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex, atomic::Ordering},
+};
+
+use crate::{config::QueuePolicy, stats::Stats, utils::QueuePacket};
+
+#[derive(Debug)]
+struct Inner {
+    packets: VecDeque<QueuePacket>,
+    producers: usize,
+    /// Set when the consumer is dropped, e.g. by a panicking worker
+    consumer_gone: bool,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: QueuePolicy,
+    stats: Arc<Stats>,
+}
+
+/// Bounded ring buffer between the capture threads and the packet handler, this is the
+/// popping end. Dropping it closes the queue, the packets sent afterwards are dropped
+pub struct PacketQueue {
+    shared: Arc<Shared>,
+}
+
+/// Pushing end of the queue, the queue closes once every producer is dropped
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+impl PacketQueue {
+    pub fn new(capacity: usize, policy: QueuePolicy, stats: &Arc<Stats>) -> (Producer, PacketQueue) {
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner { packets: VecDeque::with_capacity(capacity), producers: 1, consumer_gone: false }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: capacity.max(1),
+            policy,
+            stats: stats.clone(),
+        });
+        (Producer { shared: shared.clone() }, PacketQueue { shared })
+    }
+
+    /// Waits for the next packet, None once the queue is closed and drained
+    pub fn pop(&self) -> Option<QueuePacket> {
+        let shared = &self.shared;
+        let mut inner = shared.inner.lock().unwrap();
+        loop {
+            if let Some(packet) = inner.packets.pop_front() {
+                shared.stats.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                shared.not_full.notify_one();
+                return Some(packet);
+            }
+            if inner.producers == 0 {
+                return None;
+            }
+            inner = shared.not_empty.wait(inner).unwrap();
+        }
+    }
+}
+
+impl Drop for PacketQueue {
+    fn drop(&mut self) {
+        let shared = &self.shared;
+        // Runs while a panicking worker unwinds, a poisoned lock is still usable
+        let mut inner = shared.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.consumer_gone = true;
+        let dropped = inner.packets.len();
+        inner.packets.clear();
+        shared.stats.queue_depth.fetch_sub(dropped, Ordering::Relaxed);
+        shared.stats.queue_dropped.fetch_add(dropped, Ordering::Relaxed);
+        shared.not_full.notify_all();
+    }
+}
+
+impl Producer {
+    pub fn send(&self, packet: QueuePacket) {
+        let shared = &self.shared;
+        let mut inner = shared.inner.lock().unwrap();
+        if inner.packets.len() >= shared.capacity && !inner.consumer_gone {
+            match shared.policy {
+                QueuePolicy::BLOCK => {
+                    while inner.packets.len() >= shared.capacity && !inner.consumer_gone {
+                        inner = shared.not_full.wait(inner).unwrap();
+                    }
+                }
+                QueuePolicy::NEWEST => {
+                    shared.stats.queue_dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                QueuePolicy::OLDEST => {
+                    inner.packets.pop_front();
+                    shared.stats.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                    shared.stats.queue_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        // Nobody would ever pop it
+        if inner.consumer_gone {
+            shared.stats.queue_dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        inner.packets.push_back(packet);
+        shared.stats.queue_depth.fetch_add(1, Ordering::Relaxed);
+        shared.not_empty.notify_one();
+    }
+}
+
+impl Clone for Producer {
+    fn clone(&self) -> Producer {
+        self.shared.inner.lock().unwrap().producers += 1;
+        Producer { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.producers -= 1;
+        if inner.producers == 0 {
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread, time::Duration};
+
+    use super::*;
+    use crate::pool::PacketBuffer;
+
+    /// The timestamp tells the packets apart
+    fn packet(ts: u128) -> QueuePacket {
+        QueuePacket {
+            ts,
+            interface: 0,
+            protocol: 17,
+            source: Ipv4Addr::UNSPECIFIED,
+            destination: Ipv4Addr::UNSPECIFIED,
+            source6: None,
+            destination6: None,
+            vlans: Vec::new(),
+            flow_vlan: 0,
+            flow_tunnel: 0,
+            tunnels: Vec::new(),
+            payload_len: 0,
+            buffer: PacketBuffer::from_vec(Vec::new()),
+            payload_offset: 0,
+        }
+    }
+
+    fn counters(stats: &Stats) -> (usize, usize) {
+        (stats.queue_depth.load(Ordering::Relaxed), stats.queue_dropped.load(Ordering::Relaxed))
+    }
+
+    /// Pops what is left once the producers are gone
+    fn drain(queue: &PacketQueue) -> Vec<u128> {
+        std::iter::from_fn(|| queue.pop()).map(|packet| packet.ts).collect()
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_first_packets() {
+        let stats = Stats::new(&[]);
+        let (producer, queue) = PacketQueue::new(2, QueuePolicy::NEWEST, &stats);
+        (0..5).for_each(|ts| producer.send(packet(ts)));
+        assert_eq!(counters(&stats), (2, 3));
+        drop(producer);
+        assert_eq!(drain(&queue), [0, 1]);
+        assert_eq!(counters(&stats), (0, 3));
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_last_packets() {
+        let stats = Stats::new(&[]);
+        let (producer, queue) = PacketQueue::new(2, QueuePolicy::OLDEST, &stats);
+        (0..5).for_each(|ts| producer.send(packet(ts)));
+        assert_eq!(counters(&stats), (2, 3));
+        drop(producer);
+        assert_eq!(drain(&queue), [3, 4]);
+        assert_eq!(counters(&stats), (0, 3));
+    }
+
+    #[test]
+    fn block_waits_for_the_consumer() {
+        let stats = Stats::new(&[]);
+        let (producer, queue) = PacketQueue::new(2, QueuePolicy::BLOCK, &stats);
+        let sender = thread::spawn(move || (0..5).for_each(|ts| producer.send(packet(ts))));
+        while counters(&stats).0 < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sender.is_finished());
+        assert_eq!(counters(&stats), (2, 0));
+
+        assert_eq!(drain(&queue), [0, 1, 2, 3, 4]);
+        sender.join().unwrap();
+        assert_eq!(counters(&stats), (0, 0));
+    }
+
+    #[test]
+    fn pop_ends_once_every_producer_is_dropped() {
+        let stats = Stats::new(&[]);
+        let (producer, queue) = PacketQueue::new(4, QueuePolicy::BLOCK, &stats);
+        let other = producer.clone();
+        producer.send(packet(0));
+        drop(producer);
+        let popper = thread::spawn(move || drain(&queue));
+        thread::sleep(Duration::from_millis(50));
+        other.send(packet(1));
+        drop(other);
+        assert_eq!(popper.join().unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn a_panicked_consumer_unblocks_the_producers() {
+        let stats = Stats::new(&[]);
+        let (producer, queue) = PacketQueue::new(2, QueuePolicy::BLOCK, &stats);
+        let worker = thread::spawn(move || {
+            queue.pop();
+            panic!("worker failed");
+        });
+        let sender = thread::spawn(move || (0..5).for_each(|ts| producer.send(packet(ts))));
+        assert!(worker.join().is_err());
+        sender.join().unwrap();
+        let (depth, dropped) = counters(&stats);
+        assert_eq!(depth, 0);
+        assert_eq!(dropped, 4);
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    config::{Config, ConfigError},
+    events::Events,
+    outputs,
+    shutdown,
+};
+
+/// Reads the config again, e.g. the file it came from with the command line applied
+pub type Reloader = Box<dyn Fn() -> Result<Config, ConfigError> + Send>;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Turns SIGHUP into a reload request instead of killing the process
+pub fn install() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGHUP, handler);
+    }
+}
+
+/// Reloads as a signal would, for programs embedding the analyzer
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// The config the handlers classify with, replaced as a whole by a reload.
+/// The flows keep the snapshot they started with, the new flows get the new one
+pub(crate) struct LiveConfig {
+    generation: AtomicUsize,
+    config: Mutex<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Arc<LiveConfig> {
+        Arc::new(LiveConfig { generation: AtomicUsize::new(0), config: Mutex::new(Arc::new(config)) })
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Bumped by every swap, cheaper to poll per packet than `get`
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn swap(&self, config: Config) {
+        *self.config.lock().unwrap() = Arc::new(config);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Waits for reload requests until `running` is cleared, the sections that need a restart
+/// are reported and left as they are
+pub(crate) fn run(reloader: Reloader, live: &Arc<LiveConfig>, events: &Arc<Events>, running: &Arc<AtomicBool>) -> JoinHandle<()> {
+    let live = live.clone();
+    let events = events.clone();
+    let running = running.clone();
+
+    thread::spawn(move || {
+        while shutdown::sleep(&running, Duration::from_secs(1)) {
+            if REQUESTED.swap(false, Ordering::SeqCst) {
+                reload(&reloader, &live, &events);
+            }
+        }
+    })
+}
+
+fn reload(reloader: &Reloader, live: &LiveConfig, events: &Events) {
+    let loaded = match reloader() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            log::warn!("[RELOAD] Kept the current config: {}", e);
+            return;
+        }
+    };
+    let current = live.get();
+    let mut config = (*current).clone();
+    config.apps = loaded.apps.clone();
+    config.encrypted_dns = loaded.encrypted_dns.clone();
+    config.dns_analytics = loaded.dns_analytics.clone();
+    config.extract = loaded.extract.clone();
+    config.outputs = loaded.outputs.clone();
+    if let Err(e) = config.validate() {
+        log::warn!("[RELOAD] Kept the current config: {}", e);
+        return;
+    }
+
+    // The new outputs must all open before the old ones are closed
+    if config.outputs != current.outputs {
+        match outputs::open(&config.outputs) {
+            Ok(sinks) => events.replace_outputs(sinks),
+            Err((output, e)) => {
+                log::warn!("[RELOAD] Kept the current config: couldn't open output {}: {}", output, e);
+                return;
+            }
+        }
+    }
+
+    let changes = changes(&current, &config);
+    let restart = restart(&current, &loaded);
+    if changes.is_empty() {
+        log::info!("[RELOAD] No rule or output changed");
+    } else {
+        log::info!("[RELOAD] {}", changes.join(", "));
+        live.swap(config);
+    }
+    if !restart.is_empty() {
+        log::info!("[RELOAD] Restart to apply the changes of {}", restart.join(", "));
+    }
+}
+
+/// What a reload replaces, lists are reported as added and removed entries
+fn changes(old: &Config, new: &Config) -> Vec<String> {
+    let mut changes = Vec::new();
+    let domains = (&old.apps.domains, &new.apps.domains);
+    if domains.0 != domains.1 {
+        let added = domains.1.iter().filter(|(name, app)| domains.0.get(*name) != Some(app)).count();
+        let removed = domains.0.keys().filter(|name| !domains.1.contains_key(*name)).count();
+        changes.push(format!("apps.domains +{} -{}", added, removed));
+    }
+    let servers = (&old.encrypted_dns.doh_servers, &new.encrypted_dns.doh_servers);
+    if servers.0 != servers.1 {
+        changes.push(format!("encrypted_dns.doh_servers {}", added_removed(servers.0, servers.1)));
+    }
+    let ips = (&old.encrypted_dns.doh_ips, &new.encrypted_dns.doh_ips);
+    if ips.0 != ips.1 {
+        changes.push(format!("encrypted_dns.doh_ips {}", added_removed(ips.0, ips.1)));
+    }
+    if old.dns_analytics != new.dns_analytics {
+        changes.push("dns_analytics".to_string());
+    }
+    if old.extract != new.extract {
+        changes.push("extract".to_string());
+    }
+    if old.outputs != new.outputs {
+        let names: Vec<String> = new.outputs.iter().map(|output| output.output_type.to_string()).collect();
+        changes.push(format!("outputs [{}]", names.join(", ")));
+    }
+    changes
+}
+
+/// The sections the capture and the workers were started with
+fn restart(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut sections = Vec::new();
+    if old.general != new.general {
+        sections.push("general");
+    }
+    if old.names != new.names {
+        sections.push("names");
+    }
+    if old.arp != new.arp {
+        sections.push("arp");
+    }
+    if old.capture != new.capture {
+        sections.push("capture");
+    }
+    if old.fragments != new.fragments {
+        sections.push("fragments");
+    }
+    if old.queue != new.queue {
+        sections.push("queue");
+    }
+    sections
+}
+
+fn added_removed<T: PartialEq>(old: &[T], new: &[T]) -> String {
+    let added = new.iter().filter(|entry| !old.contains(entry)).count();
+    let removed = old.iter().filter(|entry| !new.contains(entry)).count();
+    format!("+{} -{}", added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Validates without a capture device
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.general.interface = "eth0".to_string();
+        config
+    }
+
+    #[test]
+    fn changes_list_the_replaced_sections() {
+        let old = config();
+        let mut new = config();
+        new.encrypted_dns.doh_servers.remove(0);
+        new.encrypted_dns.doh_servers.push("doh.example.net".to_string());
+        new.encrypted_dns.doh_servers.push("dns.example.org".to_string());
+        new.apps.domains.insert("example.net".to_string(), crate::AppType::WHATSAPP);
+        new.extract.enabled = true;
+        assert_eq!(changes(&old, &new), ["apps.domains +1 -0", "encrypted_dns.doh_servers +2 -1", "extract"]);
+        assert!(changes(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn restart_lists_the_sections_a_reload_keeps() {
+        let old = config();
+        let mut new = config();
+        new.general.workers = 4;
+        new.queue.capacity = 1024;
+        new.dns_analytics.enabled = false;
+        assert_eq!(restart(&old, &new), ["general", "queue"]);
+        assert!(restart(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn reload_swaps_the_rules_and_keeps_the_rest() {
+        let live = LiveConfig::new(config());
+        let events = Events::new(Vec::new(), Vec::new());
+        let reloader: Reloader = Box::new(|| {
+            let mut config = config();
+            config.encrypted_dns.doh_servers = vec!["doh.example.net".to_string()];
+            config.general.workers = 4;
+            Ok(config)
+        });
+        // What a flow started before the reload holds
+        let snapshot = live.get();
+
+        reload(&reloader, &live, &events);
+        assert_eq!(live.generation(), 1);
+        assert_eq!(live.get().encrypted_dns.doh_servers, ["doh.example.net"]);
+        assert_eq!(live.get().general.workers, 1);
+        assert_eq!(snapshot.encrypted_dns, config().encrypted_dns);
+
+        // Nothing to swap the second time
+        reload(&reloader, &live, &events);
+        assert_eq!(live.generation(), 1);
+    }
+
+    #[test]
+    fn reload_keeps_an_invalid_config() {
+        let live = LiveConfig::new(config());
+        let events = Events::new(Vec::new(), Vec::new());
+        let reloader: Reloader = Box::new(|| {
+            let mut config = config();
+            config.dns_analytics.dga_threshold = 2.0;
+            Ok(config)
+        });
+        reload(&reloader, &live, &events);
+        assert_eq!(live.generation(), 0);
+        assert_eq!(*live.get(), config());
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    sync::{Arc, atomic::{AtomicBool, AtomicI32, Ordering}},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The first signal caught, 0 until then
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // A second signal means the user doesn't want to wait for the flush
+    if SIGNAL.swap(signal, Ordering::SeqCst) != 0 {
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+/// Turns SIGINT and SIGTERM into a shutdown request of every analyzer instead of killing the process
+pub fn install() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Stops the capture of one analyzer, see `Analyzer::shutdown`. The clones share the request
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// The capture threads stop reading, the pipeline drains and `Analyzer::run` returns
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Checked by the capture threads between two reads
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst) || signal().is_some()
+    }
+}
+
+/// The signal that requested the shutdown, the process should exit with 128 plus it
+pub fn signal() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Sleeps for `duration` or until `running` is cleared, the periodic threads loop while it returns true
+pub(crate) fn sleep(running: &AtomicBool, duration: Duration) -> bool {
+    let start = Instant::now();
+    while running.load(Ordering::Relaxed) {
+        let elapsed = start.elapsed();
+        if elapsed >= duration {
+            return true;
+        }
+        thread::sleep((duration - elapsed).min(Duration::from_millis(100)));
+    }
+    false
+}
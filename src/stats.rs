@@ -1,6 +1,59 @@
-use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, thread::{self, JoinHandle}, time::Duration};
+use std::{sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread::{self, JoinHandle}, time::Duration};
 
-use crate::utils::StatType;
+use crate::{shutdown, utils::StatType};
+
+/// Counters of one capture source
+pub struct InterfaceStats {
+    pub name: String,
+    pub packets: AtomicUsize,
+    pub bytes: AtomicUsize,
+    /// Kernel drops as reported by pcap
+    pub dropped: AtomicUsize,
+}
+
+/// The counters reported per second. The counters only ever grow, the report prints
+/// the difference between two snapshots and the summary a snapshot of the totals
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub ipv4: usize,
+    pub ipv6: usize,
+    pub tcp: usize,
+    pub udp: usize,
+    /// Packets and bytes of each capture source
+    pub interfaces: Vec<(usize, usize)>,
+}
+
+impl Snapshot {
+    pub fn take(stats: &Stats) -> Snapshot {
+        Snapshot {
+            ipv4: stats.get_stat(StatType::IPV4),
+            ipv6: stats.get_stat(StatType::IPV6),
+            tcp: stats.get_stat(StatType::TCP),
+            udp: stats.get_stat(StatType::UDP),
+            interfaces: stats
+                .interfaces
+                .iter()
+                .map(|interface| (interface.packets.load(Ordering::Relaxed), interface.bytes.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+
+    /// What was counted since `earlier`
+    pub fn since(&self, earlier: &Snapshot) -> Snapshot {
+        Snapshot {
+            ipv4: self.ipv4 - earlier.ipv4,
+            ipv6: self.ipv6 - earlier.ipv6,
+            tcp: self.tcp - earlier.tcp,
+            udp: self.udp - earlier.udp,
+            interfaces: self
+                .interfaces
+                .iter()
+                .zip(earlier.interfaces.iter())
+                .map(|(now, then)| (now.0 - then.0, now.1 - then.1))
+                .collect(),
+        }
+    }
+}
 
 pub struct Stats {
     pub ipv4: AtomicUsize,
@@ -8,46 +61,124 @@ pub struct Stats {
     pub tcp: AtomicUsize,
     pub udp: AtomicUsize,
     pub dns: AtomicUsize,
-    pub ctx: AtomicUsize
+    pub names: AtomicUsize,
+    pub dot: AtomicUsize,
+    pub doq: AtomicUsize,
+    pub doh: AtomicUsize,
+    pub alerts: AtomicUsize,
+    pub quic: AtomicUsize,
+    pub extracted: AtomicUsize,
+    pub icmp: AtomicUsize,
+    pub arp: AtomicUsize,
+    pub tunneled: AtomicUsize,
+    /// TCP and UDP packets whose header was cut or invalid
+    pub malformed: AtomicUsize,
+    pub fragments: AtomicUsize,
+    pub reassembled: AtomicUsize,
+    pub fragments_expired: AtomicUsize,
+    pub fragments_overlapping: AtomicUsize,
+    /// Packets waiting in the queues, a gauge
+    pub queue_depth: AtomicUsize,
+    /// Packets the queue policy threw away
+    pub queue_dropped: AtomicUsize,
+    pub ctx: AtomicUsize,
+    pub interfaces: Vec<InterfaceStats>,
 }
 
-pub fn run(stats: &Arc<Stats>) -> JoinHandle<()> {
+/// Logs what was counted every second until `running` is cleared
+pub fn run(stats: &Arc<Stats>, running: &Arc<AtomicBool>) -> JoinHandle<()> {
     let stats = stats.clone();
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_secs(1));
-        println!(
-            "ipv4: {}  ipv6: {}  tcp: {}  udp: {}  dns: {}  ctx: {}",
-            stats.get_stat(StatType::IPV4),
-            stats.get_stat(StatType::IPV6),
-            stats.get_stat(StatType::TCP),
-            stats.get_stat(StatType::UDP),
-            stats.get_stat(StatType::DNS),
-            stats.get_stat(StatType::CTX)
-        );
-        stats.reset();
+    let running = running.clone();
+    thread::spawn(move || {
+        let mut last = Snapshot::take(&stats);
+        while shutdown::sleep(&running, Duration::from_secs(1)) {
+            let now = Snapshot::take(&stats);
+            print(&stats, &now.since(&last));
+            last = now;
+        }
     })
 }
 
+/// Logs the counters, the packets per protocol and source are taken from `counts`
+pub fn print(stats: &Stats, counts: &Snapshot) {
+    log::info!(
+        "ipv4: {}  ipv6: {}  tcp: {}  udp: {}  dns: {}  names: {}  dot: {}  doq: {}  doh: {}  alerts: {}  quic: {}  extracted: {}  icmp: {}  arp: {}  tunneled: {}  malformed: {}  fragments: {}  reassembled: {}  expired: {}  overlapping: {}  queue: {}  queue dropped: {}  pcap dropped: {}  ctx: {}",
+        counts.ipv4,
+        counts.ipv6,
+        counts.tcp,
+        counts.udp,
+        stats.get_stat(StatType::DNS),
+        stats.get_stat(StatType::NAMES),
+        stats.get_stat(StatType::DOT),
+        stats.get_stat(StatType::DOQ),
+        stats.get_stat(StatType::DOH),
+        stats.get_stat(StatType::ALERTS),
+        stats.get_stat(StatType::QUIC),
+        stats.get_stat(StatType::EXTRACTED),
+        stats.get_stat(StatType::ICMP),
+        stats.get_stat(StatType::ARP),
+        stats.get_stat(StatType::TUNNELED),
+        stats.get_stat(StatType::MALFORMED),
+        stats.get_stat(StatType::FRAGMENTS),
+        stats.get_stat(StatType::REASSEMBLED),
+        stats.get_stat(StatType::EXPIRED),
+        stats.get_stat(StatType::OVERLAPPING),
+        stats.get_stat(StatType::DEPTH),
+        stats.get_stat(StatType::DROPPED),
+        stats.interfaces.iter().map(|interface| interface.dropped.load(Ordering::Relaxed)).sum::<usize>(),
+        stats.get_stat(StatType::CTX)
+    );
+    for (interface, (packets, bytes)) in stats.interfaces.iter().zip(counts.interfaces.iter()) {
+        log::info!(
+            "  {}: packets: {}  bytes: {}  dropped: {}",
+            interface.name,
+            packets,
+            bytes,
+            interface.dropped.load(Ordering::Relaxed)
+        );
+    }
+}
+
 impl Stats {
-    pub fn new() -> Arc<Stats> {
+    /// `interfaces` are the capture sources, in the order their packets are tagged with
+    pub fn new(interfaces: &[String]) -> Arc<Stats> {
         Arc::new(Stats {
             ipv4: AtomicUsize::new(0),
             ipv6: AtomicUsize::new(0),
             tcp: AtomicUsize::new(0),
             udp: AtomicUsize::new(0),
             dns: AtomicUsize::new(0),
+            names: AtomicUsize::new(0),
+            dot: AtomicUsize::new(0),
+            doq: AtomicUsize::new(0),
+            doh: AtomicUsize::new(0),
+            alerts: AtomicUsize::new(0),
+            quic: AtomicUsize::new(0),
+            extracted: AtomicUsize::new(0),
+            icmp: AtomicUsize::new(0),
+            arp: AtomicUsize::new(0),
+            tunneled: AtomicUsize::new(0),
+            malformed: AtomicUsize::new(0),
+            fragments: AtomicUsize::new(0),
+            reassembled: AtomicUsize::new(0),
+            fragments_expired: AtomicUsize::new(0),
+            fragments_overlapping: AtomicUsize::new(0),
+            queue_depth: AtomicUsize::new(0),
+            queue_dropped: AtomicUsize::new(0),
             ctx: AtomicUsize::new(0),
+            interfaces: interfaces
+                .iter()
+                .map(|name| InterfaceStats {
+                    name: name.clone(),
+                    packets: AtomicUsize::new(0),
+                    bytes: AtomicUsize::new(0),
+                    dropped: AtomicUsize::new(0),
+                })
+                .collect(),
         })
     }
 
-    pub fn reset(&self) {
-        self.ipv4.swap(0, Ordering::Relaxed);
-        self.ipv6.swap(0, Ordering::Relaxed);
-        self.tcp.swap(0, Ordering::Relaxed);
-        self.udp.swap(0, Ordering::Relaxed);
-    }
-
-    pub fn get_stat(&self, stat: StatType) -> usize {
+    pub(crate) fn get_stat(&self, stat: StatType) -> usize {
         match stat {
             StatType::TCP => {
                 self.tcp.load(Ordering::Relaxed)
@@ -58,6 +189,57 @@ impl Stats {
             StatType::DNS => {
                 self.dns.load(Ordering::Relaxed)
             },
+            StatType::NAMES => {
+                self.names.load(Ordering::Relaxed)
+            },
+            StatType::DOT => {
+                self.dot.load(Ordering::Relaxed)
+            },
+            StatType::DOQ => {
+                self.doq.load(Ordering::Relaxed)
+            },
+            StatType::DOH => {
+                self.doh.load(Ordering::Relaxed)
+            },
+            StatType::ALERTS => {
+                self.alerts.load(Ordering::Relaxed)
+            },
+            StatType::QUIC => {
+                self.quic.load(Ordering::Relaxed)
+            },
+            StatType::EXTRACTED => {
+                self.extracted.load(Ordering::Relaxed)
+            },
+            StatType::ICMP => {
+                self.icmp.load(Ordering::Relaxed)
+            },
+            StatType::ARP => {
+                self.arp.load(Ordering::Relaxed)
+            },
+            StatType::TUNNELED => {
+                self.tunneled.load(Ordering::Relaxed)
+            },
+            StatType::MALFORMED => {
+                self.malformed.load(Ordering::Relaxed)
+            },
+            StatType::FRAGMENTS => {
+                self.fragments.load(Ordering::Relaxed)
+            },
+            StatType::REASSEMBLED => {
+                self.reassembled.load(Ordering::Relaxed)
+            },
+            StatType::EXPIRED => {
+                self.fragments_expired.load(Ordering::Relaxed)
+            },
+            StatType::OVERLAPPING => {
+                self.fragments_overlapping.load(Ordering::Relaxed)
+            },
+            StatType::DEPTH => {
+                self.queue_depth.load(Ordering::Relaxed)
+            },
+            StatType::DROPPED => {
+                self.queue_dropped.load(Ordering::Relaxed)
+            },
             StatType::IPV4 => {
                 self.ipv4.load(Ordering::Relaxed)
             },
@@ -1,12 +1,17 @@
-use std::{fs::File, net::Ipv4Addr};
+use std::net::{Ipv4Addr, Ipv6Addr};
 
-use num_derive::FromPrimitive; 
+use num_derive::FromPrimitive;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::pool::PacketBuffer; 
 
 #[derive(FromPrimitive)]
 pub enum ProtocolType {
+    ICMP = 1,
     IGMP = 2,
     TCP = 6,
     UDP = 17,
+    ICMPV6 = 58,
 }
 
 pub enum StatType {
@@ -15,16 +20,86 @@ pub enum StatType {
     TCP,
     UDP,
     DNS,
+    NAMES,
+    DOT,
+    DOQ,
+    DOH,
+    ALERTS,
+    QUIC,
+    EXTRACTED,
+    ICMP,
+    ARP,
+    TUNNELED,
+    MALFORMED,
+    FRAGMENTS,
+    REASSEMBLED,
+    EXPIRED,
+    OVERLAPPING,
+    DEPTH,
+    DROPPED,
     CTX
 }
 
 #[derive(Debug, Clone)]
 pub struct QueuePacket {
+    /// Capture timestamp in microseconds
+    pub ts: u128,
+    /// Index of the capture source in `interface::sources`
+    pub interface: u16,
     pub protocol: u8,
     pub source: Ipv4Addr,
     pub destination: Ipv4Addr,
+    /// Only set for IPv6 packets, `source` and `destination` are then unspecified
+    pub source6: Option<Ipv6Addr>,
+    pub destination6: Option<Ipv6Addr>,
+    /// 802.1Q/802.1ad VLAN IDs, outermost first
+    pub vlans: Vec<u16>,
+    /// VLAN ID that is part of the flow key, 0 unless `capture.vlan_flow_key` is set
+    pub flow_vlan: u16,
+    /// Tunnels the packet was decapsulated from, outermost first
+    pub tunnels: Vec<Tunnel>,
+    /// Tunnel ID that is part of the flow key, 0 unless `capture.tunnel_flow_key` is set
+    pub flow_tunnel: u32,
     pub payload_len: u16,
-    pub payload: Vec<u8>
+    /// Frame from the link layer header to the end of the payload, or only the payload when it was reassembled
+    pub buffer: PacketBuffer,
+    /// Where the IP payload starts in `buffer`
+    pub payload_offset: usize,
+}
+
+impl QueuePacket {
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer[self.payload_offset..self.payload_offset + self.payload_len as usize]
+    }
+
+    /// Capture time in milliseconds, the unit and clock of the flow and event timestamps
+    pub fn millis(&self) -> u128 {
+        self.ts / 1000
+    }
+}
+
+/// Outer tunnel of a decapsulated packet, with its identifier
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tunnel {
+    IPIP,
+    /// GRE key, if present
+    GRE(Option<u32>),
+    /// VXLAN network identifier
+    VXLAN(u32),
+    /// GENEVE virtual network identifier
+    GENEVE(u32),
+    /// GTP-U tunnel endpoint identifier
+    GTPU(u32),
+}
+
+impl Tunnel {
+    /// The identifier of the tunnel, 0 for IP-in-IP and for GRE without a key
+    pub fn id(&self) -> u32 {
+        match *self {
+            Tunnel::IPIP | Tunnel::GRE(None) => 0,
+            Tunnel::GRE(Some(id)) | Tunnel::VXLAN(id) | Tunnel::GENEVE(id) | Tunnel::GTPU(id) => id,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Hash, Clone, Copy)]
@@ -33,12 +108,20 @@ pub enum DnsRecordType {
     CNAME = 2,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AppType {
     NONE,
     WHATSAPP
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptedDnsType {
+    DOT,
+    DOQ,
+    DOH
+}
+
 #[derive(Debug, Hash)]
 pub struct DnsRecord {
     pub data: String,
@@ -51,11 +134,6 @@ impl PartialEq for DnsRecord {
 }
 impl Eq for DnsRecord {}
 
-#[derive(Debug, Default)]
-pub struct Files {
-    pub whatsapp: Option<File>,
-}
-
 pub fn _u8_to_ipv4(arr: [u8; 4]) -> String {
     let mut i = 0;
     let mut ip = String::from("");
@@ -67,4 +145,7 @@ pub fn _u8_to_ipv4(arr: [u8; 4]) -> String {
         i += 1;
     }
     ip
-}
\ No newline at end of file
+}
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(":")
+}